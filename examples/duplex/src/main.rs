@@ -0,0 +1,34 @@
+use std::error::Error;
+
+use futures_lite::StreamExt;
+use futures_util::sink::SinkExt;
+use nectar::{event::TelnetEvent, option::TelnetOption, test_util::framed_pair};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Two endpoints connected in memory by `tokio::io::duplex`, so no real
+    // sockets are needed.
+    let (mut server, mut client) = framed_pair(1024);
+
+    // The server asks the client to report its window size...
+    server.send(TelnetEvent::Do(TelnetOption::NAWS)).await?;
+
+    // ...which the client receives as a decoded event, and agrees to.
+    let event = client.next().await.ok_or("connection closed")??;
+    println!("client received: {:?}", event);
+
+    if event == TelnetEvent::Do(TelnetOption::NAWS) {
+        client.send(TelnetEvent::Will(TelnetOption::NAWS)).await?;
+    }
+
+    let event = server.next().await.ok_or("connection closed")??;
+    println!("server received: {:?}", event);
+
+    // Once negotiation is done, regular messages flow as usual.
+    client.send(TelnetEvent::Message("hello from the client".to_string())).await?;
+
+    let event = server.next().await.ok_or("connection closed")??;
+    println!("server received: {:?}", event);
+
+    Ok(())
+}
@@ -4,7 +4,6 @@ use futures_lite::StreamExt;
 use futures_util::sink::SinkExt;
 use nectar::{event::TelnetEvent, TelnetCodec};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_util::codec::Framed;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -26,8 +25,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
 async fn handler(stream: TcpStream) -> Result<(), Box<dyn Error>> {
     // We construct a 'Frame', which is just a wrapper around the underlying
-    // stream that is decoded by the `nectar::TelnetCodec`.
-    let mut frame = Framed::new(stream, TelnetCodec::new(1024));
+    // stream that is decoded by the `nectar::TelnetCodec`. The read buffer is
+    // sized from the codec, which avoids reallocating it for long lines.
+    let mut frame = TelnetCodec::new(1024).framed(stream);
 
     // Let's send a friendly welcome message to anyone who connects!
     frame
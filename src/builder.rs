@@ -0,0 +1,71 @@
+use crate::{event::TelnetEvent, option::TelnetOption, TelnetCodec};
+
+/// Configures a `TelnetCodec` before it is used, for settings that are not
+/// plain fields, such as the initial size of the line buffer.
+///
+/// Start from `TelnetCodec::builder`.
+#[derive(Debug)]
+pub struct TelnetCodecBuilder {
+    codec: TelnetCodec,
+}
+
+impl TelnetCodecBuilder {
+    /// Returns a builder for a codec with the defaults of `TelnetCodec::new`.
+    #[must_use]
+    pub fn new(max_buffer_length: usize) -> Self {
+        TelnetCodecBuilder { codec: TelnetCodec::new(max_buffer_length) }
+    }
+
+    /// Preallocates the line buffer with room for `capacity` bytes, such as
+    /// the length of a typical line. The buffer keeps its capacity as lines
+    /// are taken from it, so it does not grow from empty for every line.
+    #[must_use]
+    pub fn initial_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.codec.buffer = Vec::with_capacity(capacity);
+        self
+    }
+
+    /// Sets `message_mode`.
+    #[must_use]
+    pub fn message_mode(mut self, message_mode: bool) -> Self {
+        self.codec.message_mode = message_mode;
+        self
+    }
+
+    /// Sets `sga`.
+    #[must_use]
+    pub fn sga(mut self, sga: bool) -> Self {
+        self.codec.sga = sga;
+        self
+    }
+
+    /// Sets `track_options`.
+    #[must_use]
+    pub fn track_options(mut self, track_options: bool) -> Self {
+        self.codec.track_options = track_options;
+        self
+    }
+
+    /// Queues the negotiations that offer each of `options`, as returned by
+    /// `TelnetCodec::offer`, for `take_outgoing`.
+    #[must_use]
+    pub fn offer(mut self, options: &[TelnetOption]) -> Self {
+        let offers = self.codec.offer(options);
+        self.codec.outgoing.extend(offers);
+        self
+    }
+
+    /// Queues an event for `take_outgoing`, to be sent once the peer
+    /// connects.
+    #[must_use]
+    pub fn queue(mut self, event: TelnetEvent) -> Self {
+        self.codec.outgoing.push(event);
+        self
+    }
+
+    /// Returns the configured codec.
+    #[must_use]
+    pub fn build(self) -> TelnetCodec {
+        self.codec
+    }
+}
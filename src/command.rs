@@ -0,0 +1,117 @@
+use crate::constants::{AO, AYT, BRK, DM, DO, DONT, EC, EL, GA, IAC, IP, NOP, SB, SE, WILL, WONT};
+
+/// Represents the commands that may follow an IAC byte.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Command {
+    /// End of subnegotiation parameters.
+    SE,
+    /// No operation.
+    NOP,
+    /// The data stream portion of a Synch.
+    DM,
+    /// The NVT character BRK.
+    BRK,
+    /// Interrupt Process.
+    IP,
+    /// Abort Output.
+    AO,
+    /// Are You There.
+    AYT,
+    /// Erase Character.
+    EC,
+    /// Erase Line.
+    EL,
+    /// Go Ahead.
+    GA,
+    /// Subnegotiation Begin.
+    SB,
+    /// An intent to perform an option.
+    WILL,
+    /// A refusal to perform an option.
+    WONT,
+    /// A request for the other side to perform an option.
+    DO,
+    /// A demand for the other side to stop performing an option.
+    DONT,
+    /// An escaped 0xFF data byte.
+    IAC,
+    /// A generic marker indicating an unknown command.
+    Unknown(u8),
+}
+
+impl From<u8> for Command {
+    fn from(byte: u8) -> Self {
+        match byte {
+            SE => Command::SE,
+            NOP => Command::NOP,
+            DM => Command::DM,
+            BRK => Command::BRK,
+            IP => Command::IP,
+            AO => Command::AO,
+            AYT => Command::AYT,
+            EC => Command::EC,
+            EL => Command::EL,
+            GA => Command::GA,
+            SB => Command::SB,
+            WILL => Command::WILL,
+            WONT => Command::WONT,
+            DO => Command::DO,
+            DONT => Command::DONT,
+            IAC => Command::IAC,
+            _ => Command::Unknown(byte),
+        }
+    }
+}
+
+impl From<Command> for u8 {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::SE => SE,
+            Command::NOP => NOP,
+            Command::DM => DM,
+            Command::BRK => BRK,
+            Command::IP => IP,
+            Command::AO => AO,
+            Command::AYT => AYT,
+            Command::EC => EC,
+            Command::EL => EL,
+            Command::GA => GA,
+            Command::SB => SB,
+            Command::WILL => WILL,
+            Command::WONT => WONT,
+            Command::DO => DO,
+            Command::DONT => DONT,
+            Command::IAC => IAC,
+            Command::Unknown(byte) => byte,
+        }
+    }
+}
+
+/// Returns the command at the start of `bytes`, or `None` if `bytes` does not
+/// start with IAC or the command byte has not arrived yet.
+pub fn decode_command(bytes: &[u8]) -> Option<Command> {
+    match bytes {
+        [IAC, command, ..] => Some(Command::from(*command)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_command() {
+        assert_eq!(decode_command(&[IAC, WILL, 1]), Some(Command::WILL));
+        assert_eq!(decode_command(&[IAC, 200]), Some(Command::Unknown(200)));
+        assert_eq!(decode_command(&[IAC]), None);
+        assert_eq!(decode_command(&[b'a', IAC]), None);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for byte in 0..=u8::MAX {
+            assert_eq!(u8::from(Command::from(byte)), byte);
+        }
+    }
+}
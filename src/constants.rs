@@ -19,6 +19,24 @@ pub const NAWS: u8 = 31;
 // Subnegotiation End
 pub const SE: u8 = 240;
 
+// Data Mark
+pub const DM: u8 = 242;
+
+// Break
+pub const BRK: u8 = 243;
+
+// Interrupt Process
+pub const IP: u8 = 244;
+
+// Abort Output
+pub const AO: u8 = 245;
+
+// Are You There
+pub const AYT: u8 = 246;
+
+// Erase Character
+pub const EC: u8 = 247;
+
 // Erase Line
 pub const EL: u8 = 248;
 
@@ -77,6 +95,18 @@ pub const LINEMODE_EDIT: u8 = 1;
 // values.
 pub const LINEMODE_TRAPSIG: u8 = 2;
 
+// When set, this bit acknowledges a MODE sent by the other side. It is never
+// set when proposing a new mode.
+pub const LINEMODE_MODE_ACK: u8 = 4;
+
+// When set, the client side should expand horizontal tabs to the appropriate
+// number of spaces before displaying them.
+pub const LINEMODE_SOFT_TAB: u8 = 8;
+
+// When set, the client side should echo non-printable characters literally
+// rather than as printable representations (such as ^C).
+pub const LINEMODE_LIT_ECHO: u8 = 16;
+
 // Indicates the desire to begin performing, or confirmation that you are now
 // performing, the indicated option.
 pub const WILL: u8 = 251;
@@ -105,12 +135,46 @@ pub const STATUS: u8 = 5;
 /// <https://datatracker.ietf.org/doc/rfc860/>
 pub const TIMING_MARK: u8 = 6;
 
+// Terminal Type - <https://datatracker.ietf.org/doc/html/rfc1091>
+pub const TTYPE: u8 = 24;
+
+// Terminal Speed - <https://datatracker.ietf.org/doc/html/rfc1079>
+pub const TSPEED: u8 = 32;
+
+// X Display Location - <https://datatracker.ietf.org/doc/html/rfc1096>
+pub const XDISPLOC: u8 = 35;
+
+// Old Environment - <https://datatracker.ietf.org/doc/html/rfc1408>
+pub const OLD_ENVIRON: u8 = 36;
+
+// Authentication - <https://datatracker.ietf.org/doc/html/rfc2941>
+pub const AUTHENTICATION: u8 = 37;
+
+// Data Encryption - <https://datatracker.ietf.org/doc/html/rfc2946>
+pub const ENCRYPT: u8 = 38;
+
+// IS and SEND subnegotiation commands, shared by options such as TSPEED that
+// follow the same request/response pattern.
+pub const IS: u8 = 0;
+pub const SEND: u8 = 1;
+
 /// Remote flow control - <https://datatracker.ietf.org/doc/rfc1372/>
 pub const REMOTE_FLOW_CONTROL: u8 = 33;
 
 // End of Record - <https://tintin.mudhalla.net/protocols/eor/>
 pub const EOR: u8 = 239;
 
+// Mud Server Data Protocol - <https://tintin.mudhalla.net/protocols/msdp/>
+pub const MSDP: u8 = 69;
+
+// MSDP subnegotiation commands
+pub const MSDP_VAR: u8 = 1;
+pub const MSDP_VAL: u8 = 2;
+pub const MSDP_TABLE_OPEN: u8 = 3;
+pub const MSDP_TABLE_CLOSE: u8 = 4;
+pub const MSDP_ARRAY_OPEN: u8 = 5;
+pub const MSDP_ARRAY_CLOSE: u8 = 6;
+
 // Mud Server Status Protocol - <https://mudhalla.net/tintin/protocols/mssp/>
 pub const MSSP: u8 = 70;
 
@@ -118,6 +182,10 @@ pub const MSSP: u8 = 70;
 // <https://www.gammon.com.au/mccp/protocol.html>
 pub const MCCP2: u8 = 86;
 
+// Mud Client Compression Protocol (v3) -
+// <https://tintin.mudhalla.net/protocols/mccp/>
+pub const MCCP3: u8 = 87;
+
 // Mud Sound Protocol - <https://www.zuggsoft.com/zmud/msp.htm>
 pub const MSP: u8 = 90;
 
@@ -139,8 +207,12 @@ pub const CHARSET_TTABLE_REJECTED: u8 = 5;
 pub const CHARSET_TTABLE_ACK: u8 = 6;
 pub const CHARSET_TTABLE_NAK: u8 = 7;
 
-/// Constants representing different levels and functionalities associated with
-/// Telnet's Special Linemode Characters (SLC).
+// The token before the version byte of a CHARSET REQUEST that offers a
+// translation table.
+pub const CHARSET_TTABLE: &[u8] = b"[TTABLE]";
+
+// Constants representing different levels and functionalities associated with
+// Telnet's Special Linemode Characters (SLC).
 
 /// `SLC_DEFAULT`: Represents the default state of a linemode option. This level
 /// indicates that the default action should be taken for a particular SLC
@@ -188,7 +260,7 @@ pub const SLC_FLUSHIN: u8 = 64;
 /// commands.
 pub const SLC_FLUSHOUT: u8 = 32;
 
-/// Telnet Special Linemode Characters (SLC) Functions as Constants
+// Telnet Special Linemode Characters (SLC) Functions as Constants
 
 // SLC Function Names
 /// SLC_SYNCH: Synchronize
@@ -34,7 +34,7 @@ pub enum EnvironmentKind {
 
 /// `WellKnownVariable` is an enumeration of all the well known
 /// variables that can be utilized in an environment.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum WellKnownVariable {
     /// `User` variant represents the username the client wishes to use for logging in.
     User,
@@ -102,6 +102,30 @@ impl From<u8> for EnvironmentOperation {
     }
 }
 
+impl EnvironmentOperation {
+    /// Builds an `Is` operation from well-known and user-defined variables,
+    /// tagging each with the correct `EnvironmentKind`. This is the usual
+    /// response to a `Send` request.
+    ///
+    /// Both arguments accept anything that yields name/value pairs, such as a
+    /// `HashMap`. Well-known variables are encoded first, each group in
+    /// iteration order.
+    pub fn from_vars<W, U>(well_known: W, user_defined: U) -> Self
+    where
+        W: IntoIterator<Item = (WellKnownVariable, String)>,
+        U: IntoIterator<Item = (String, String)>,
+    {
+        let well_known = well_known.into_iter().map(|(name, value)| {
+            (EnvironmentKind::WellKnown(Some(name)), Some(value.into_bytes()))
+        });
+        let user_defined = user_defined.into_iter().map(|(name, value)| {
+            (EnvironmentKind::UserDefined(Some(name)), Some(value.into_bytes()))
+        });
+
+        EnvironmentOperation::Is(well_known.chain(user_defined).collect())
+    }
+}
+
 impl WellKnownVariable {
     pub fn encoded_size(&self) -> usize {
         self.as_str().len()
@@ -143,8 +167,8 @@ impl EnvironmentKind {
         match self {
             EnvironmentKind::WellKnown(None) => 1,
             EnvironmentKind::UserDefined(None) => 1,
-            EnvironmentKind::WellKnown(Some(v)) => 1 + v.encoded_size(),
-            EnvironmentKind::UserDefined(Some(v)) => 1 + v.len(),
+            EnvironmentKind::WellKnown(Some(v)) => v.encoded_size().saturating_add(1),
+            EnvironmentKind::UserDefined(Some(v)) => v.len().saturating_add(1),
         }
     }
 }
@@ -184,10 +208,13 @@ pub fn encode_env_op(op: EnvironmentOperation, buffer: &mut BytesMut) {
         }
         EnvironmentOperation::Send(vars) => {
             buffer.extend([ENV_SEND]);
-            for (kind, name) in vars.iter().filter_map(|k| k.name().map(|name| (k.as_u8(), name))) {
-                buffer.extend([kind]);
-                // TODO: Maybe we should strip UTF-8 here but we could also just leave that to the user...
-                buffer.extend(name.as_bytes());
+            for kind in &vars {
+                buffer.extend([kind.as_u8()]);
+                // A wildcard kind has no name and requests every variable of
+                // that kind, so only the type byte is sent.
+                if let Some(name) = kind.name() {
+                    buffer.extend(encode_bytes(name.as_bytes()));
+                }
             }
         }
         EnvironmentOperation::Info(vars) => {
@@ -463,11 +490,11 @@ pub fn decode_env_send_var(kind: u8, name: &[u8]) -> Option<EnvironmentKind> {
     };
 
     match kind {
-        ENV_USERVAR => {
+        ENV_VAR => {
             let name = inner.map(WellKnownVariable::from);
             Some(EnvironmentKind::WellKnown(name))
         }
-        ENV_VAR => {
+        ENV_USERVAR => {
             let name = inner.map(|n| n.to_string());
             Some(EnvironmentKind::UserDefined(name))
         }
@@ -478,42 +505,23 @@ pub fn decode_env_send_var(kind: u8, name: &[u8]) -> Option<EnvironmentKind> {
 pub fn decode_env_send(subvec: &[u8]) -> Option<Vec<EnvironmentKind>> {
     // Create empty buffer to store decoded EnvironmentKinds
     let mut buf = Vec::new();
+    let mut index = 0;
 
-    // If subvec is empty, return the empty buffer
-    if subvec.is_empty() {
-        return Some(buf);
-    }
+    // Each declaration is a type byte followed by a name, which is escaped
+    // the same way as in IS. A type byte without a name, such as one at the
+    // end, is a wildcard requesting every variable of that type not already
+    // listed.
+    while index < subvec.len() {
+        let kind = subvec[index];
+        index += 1;
 
-    // Create a mutable reference to hold the name of the current EnvironmentKind
-    let mut current_name = Vec::new();
-
-    // Assign the kind of the current EnvironmentKind
-    let mut current_kind = subvec[0];
-
-    // Iterate through each byte in subvec (skipping the first)
-    // We basically parse until we hit the next variable or the end of subvec
-    for b in &subvec[1..] {
-        match *b {
-            // If the byte matches ENV_USERVAR or ENV_VAR...
-            ENV_USERVAR | ENV_VAR => {
-                // Decode current_name into EnvironmentKind and push it to the buffer
-                buf.push(decode_env_send_var(current_kind, current_name.as_slice())?);
-                // Update the current_kind
-                current_kind = *b;
-                // Clear the current_name vector for the next EnvironmentKind
-                current_name.clear();
-            }
-            // For any other byte...
-            _ => {
-                // Push the byte to current_name
-                current_name.push(*b);
-            }
-        }
-    }
+        let (name, size) = match subvec.get(index..) {
+            Some(rest) if !rest.is_empty() => decode_env_name(rest)?,
+            _ => (Vec::new(), 0),
+        };
 
-    // Also decode any trailing declarations
-    if !current_name.is_empty() {
-        buf.push(decode_env_send_var(current_kind, current_name.as_slice())?);
+        buf.push(decode_env_send_var(kind, name.as_slice())?);
+        index += size;
     }
 
     // Return the filled buffer
@@ -521,29 +529,88 @@ pub fn decode_env_send(subvec: &[u8]) -> Option<Vec<EnvironmentKind>> {
 }
 
 pub fn decode_env(subvec: &[u8]) -> Option<TelnetEvent> {
+    let op = decode_env_op(subvec)?;
+
+    // Return a Telnet event with the processed operation data encapsulated within a SubnegotiationType enum.
+    Some(TelnetEvent::Subnegotiate(SubnegotiationType::Environment(op)))
+}
+
+/// Decodes a legacy ENVIRON (RFC 1408) subnegotiation by translating it to
+/// the NEW-ENVIRON byte values and reusing the same parser.
+pub fn decode_old_env(subvec: &[u8]) -> Option<TelnetEvent> {
+    let mut subvec = subvec.to_vec();
+    swap_legacy_bytes(&mut subvec);
+
+    let op = decode_env_op(&subvec)?;
+
+    Some(TelnetEvent::Subnegotiate(SubnegotiationType::OldEnvironment(op)))
+}
+
+/// Encodes an operation for the legacy ENVIRON (RFC 1408) option.
+pub fn encode_old_env_op(op: EnvironmentOperation, buffer: &mut BytesMut) {
+    let mut encoded = BytesMut::new();
+    encode_env_op(op, &mut encoded);
+    swap_legacy_bytes(&mut encoded);
+
+    buffer.extend(encoded);
+}
+
+/// Swaps the VAR and VALUE bytes in an encoded environment operation. Most
+/// servers implementing the original ENVIRON option use VAR = 1 and VALUE = 0,
+/// the reverse of NEW-ENVIRON (see RFC 1571). Escaped bytes are data, not
+/// markers, so they are left alone, and the leading operation byte has the
+/// same value in both.
+fn swap_legacy_bytes(subvec: &mut [u8]) {
+    let mut escaped = false;
+
+    for byte in subvec.iter_mut().skip(1) {
+        match (*byte, escaped) {
+            (_, true) => escaped = false,
+            (ENV_ESC, false) => escaped = true,
+            (ENV_VAR, false) => *byte = ENV_VALUE,
+            (ENV_VALUE, false) => *byte = ENV_VAR,
+            _ => {}
+        }
+    }
+}
+
+/// Doubles each IAC byte, restoring the escaping used on the wire.
+fn double_iac(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|byte| if *byte == IAC { &[IAC, IAC][..] } else { std::slice::from_ref(byte) })
+        .copied()
+        .collect()
+}
+
+/// Decodes the operation carried by an environment subnegotiation.
+fn decode_env_op(subvec: &[u8]) -> Option<EnvironmentOperation> {
     // Return None if incoming byte slice is empty.
     if subvec.is_empty() {
         return None;
     }
 
+    // The codec has already undone the IAC doubling of the subnegotiation,
+    // but the variable decoders expect IAC as it is sent on the wire.
+    let vars = double_iac(&subvec[1..]);
+
     // Extract the operation from the first element of the slice,
     // and match it to the corresponding EnvironmentOperation.
     let op = match EnvironmentOperation::from(subvec[0]) {
         // For 'Is' operations, decode the environment variables.
-        EnvironmentOperation::Is(_) => EnvironmentOperation::Is(decode_env_is(&subvec[1..])?),
+        EnvironmentOperation::Is(_) => EnvironmentOperation::Is(decode_env_is(&vars)?),
         // For 'Send' operations, decode the environment variables to be sent.
-        EnvironmentOperation::Send(_) => EnvironmentOperation::Send(decode_env_send(&subvec[1..])?),
+        EnvironmentOperation::Send(_) => EnvironmentOperation::Send(decode_env_send(&vars)?),
         // For 'Info' operations, decode the environment variables in the information.
         // This is the same as with `EnvironmentOperation::Is`.
-        EnvironmentOperation::Info(_) => EnvironmentOperation::Info(decode_env_is(&subvec[1..])?),
+        EnvironmentOperation::Info(_) => EnvironmentOperation::Info(decode_env_is(&vars)?),
         // For 'Unknown' operations, store the unknown data.
         EnvironmentOperation::Unknown(id, _) => {
             EnvironmentOperation::Unknown(id, Bytes::from(subvec[1..].to_vec()))
         }
     };
 
-    // Return a Telnet event with the processed operation data encapsulated within a SubnegotiationType enum.
-    Some(TelnetEvent::Subnegotiate(SubnegotiationType::Environment(op)))
+    Some(op)
 }
 
 #[cfg(test)]
@@ -725,6 +792,56 @@ mod tests {
         assert_eq!(buffer[2..12], *b"VarExample");
     }
 
+    #[test]
+    fn test_encode_env_op_send_wildcard() {
+        let mut buffer = BytesMut::new();
+        let op = EnvironmentOperation::Send(vec![
+            EnvironmentKind::WellKnown(None),
+            EnvironmentKind::UserDefined(None),
+        ]);
+        encode_env_op(op, &mut buffer);
+        assert_eq!(buffer.as_ref(), &[ENV_SEND, ENV_VAR, ENV_USERVAR]);
+    }
+
+    #[test]
+    fn test_env_send_mixed_roundtrip() {
+        let op = EnvironmentOperation::Send(vec![
+            EnvironmentKind::WellKnown(Some(WellKnownVariable::User)),
+            EnvironmentKind::UserDefined(Some("HOME".to_string())),
+            EnvironmentKind::WellKnown(None),
+            EnvironmentKind::UserDefined(None),
+        ]);
+
+        let mut buffer = BytesMut::new();
+        encode_env_op(op.clone(), &mut buffer);
+        assert_eq!(buffer.as_ref(), b"\x01\x00USER\x03HOME\x00\x03");
+
+        assert_eq!(
+            decode_env(&buffer),
+            Some(TelnetEvent::Subnegotiate(SubnegotiationType::Environment(op)))
+        );
+    }
+
+    #[test]
+    fn test_env_send_escaped_name() {
+        let op = EnvironmentOperation::Send(vec![
+            EnvironmentKind::UserDefined(Some("A\x00B\x02".to_string())),
+            EnvironmentKind::WellKnown(Some(WellKnownVariable::User)),
+        ]);
+
+        let mut buffer = BytesMut::new();
+        encode_env_op(op.clone(), &mut buffer);
+        assert_eq!(buffer.as_ref(), b"\x01\x03A\x02\x00B\x02\x02\x00USER");
+
+        assert_eq!(
+            decode_env(&buffer),
+            Some(TelnetEvent::Subnegotiate(SubnegotiationType::Environment(op)))
+        );
+
+        // A VALUE has no place in a SEND
+        assert_eq!(decode_env(b"\x01\x03A\x01b"), None);
+    }
+
     #[test]
     fn test_encode_env_op_info() {
         let mut buffer = BytesMut::new();
@@ -739,6 +856,43 @@ mod tests {
         assert_eq!(buffer[7..12], [ENV_ESC, 2, ENV_ESC, 3, 4]);
     }
 
+    #[test]
+    fn test_from_vars() {
+        let user_defined = std::collections::HashMap::from([("HOME".to_string(), "/".to_string())]);
+        let op = EnvironmentOperation::from_vars(
+            [(WellKnownVariable::User, "rob".to_string())],
+            user_defined,
+        );
+
+        assert_eq!(
+            op,
+            EnvironmentOperation::Is(vec![
+                (EnvironmentKind::WellKnown(Some(WellKnownVariable::User)), Some(b"rob".to_vec())),
+                (EnvironmentKind::UserDefined(Some("HOME".to_string())), Some(b"/".to_vec())),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_old_env() {
+        // VAR is 1 and VALUE is 0 in the legacy encoding, and an escaped 0 is
+        // still data.
+        let legacy = [ENV_IS, 1, b'U', b'S', b'E', b'R', 0, b'r', ENV_ESC, 0, b'b'];
+        let op = EnvironmentOperation::Is(vec![(
+            EnvironmentKind::WellKnown(Some(WellKnownVariable::User)),
+            Some(vec![b'r', 0, b'b']),
+        )]);
+
+        assert_eq!(
+            decode_old_env(&legacy),
+            Some(TelnetEvent::Subnegotiate(SubnegotiationType::OldEnvironment(op.clone())))
+        );
+
+        let mut buffer = BytesMut::new();
+        encode_old_env_op(op, &mut buffer);
+        assert_eq!(buffer.as_ref(), &legacy);
+    }
+
     #[test]
     fn test_encode_env_op_unknown() {
         let mut buffer = BytesMut::new();
@@ -1,11 +1,13 @@
+use bytes::{Bytes, BytesMut};
+
 use crate::{
-    constants::{DO, DONT, GA, NOP, SB, WILL, WONT},
+    constants::{DM, DO, DONT, EC, EL, GA, NOP, SB, WILL, WONT},
     option::TelnetOption,
     subnegotiation::SubnegotiationType,
 };
 
 /// Represents message types supported by Nectar.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum TelnetEvent {
     /// A single byte character.
     Character(u8),
@@ -18,6 +20,10 @@ pub enum TelnetEvent {
     /// sending messages without enforced newlines. Used for outgoing messages
     /// only.
     RawMessage(String),
+    /// A prompt: a line of text that is not terminated with `\r\n`, such as
+    /// `"login: "`. It is produced by `TelnetCodec::flush_prompt`, and is
+    /// encoded as the text followed by `IAC GA`.
+    Prompt(String),
     /// A message requesting the other side to perform an option.
     Do(TelnetOption),
     /// A message indicating an intent to perform an option.
@@ -33,27 +39,95 @@ pub enum TelnetEvent {
     GoAhead,
     /// No operation.
     Nop,
+    /// The Data Mark that ends a SYNCH, marking where urgent data was sent.
+    DataMark,
+    /// Erase Character: the other side should delete the last character
+    /// typed.
+    EraseCharacter,
+    /// Erase Line: the other side should delete the line being typed.
+    EraseLine,
+    /// A command that nectar does not handle, given as the byte that followed
+    /// IAC.
+    UnknownCommand(u8),
+    /// A piece of a message longer than `TelnetCodec::chunk_size`, returned
+    /// before the rest of the line has arrived. `last` is set on the final
+    /// piece, which is returned when the line ends. Used for incoming events
+    /// only.
+    MessageChunk { data: String, last: bool },
+    /// Bytes that are already encoded for the wire, such as a frame a proxy
+    /// is forwarding. They are written exactly as given: IAC is not doubled,
+    /// no line ending is added, and no charset is applied. Used for outgoing
+    /// events only.
+    ///
+    /// The caller is responsible for the bytes being valid Telnet. A stray
+    /// IAC will be read by the peer as the start of a command, and
+    /// negotiations sent this way are not recorded in the codec's option
+    /// state.
+    PreEncoded(Bytes),
+    /// Input that could not be decoded, reported in place of an error when
+    /// `TelnetCodec::errors_as_events` is enabled. The bad data has already
+    /// been skipped. Used for incoming events only.
+    ProtocolError(String),
 }
 
 impl TelnetEvent {
+    /// Returns an event suitable for keeping an idle connection alive. This
+    /// encodes as `IAC NOP`, which the other side is required to ignore.
+    ///
+    /// Sending this periodically prevents NAT devices and proxies from
+    /// dropping quiet connections; scheduling it is left to the application.
+    pub fn keepalive() -> Self {
+        TelnetEvent::Nop
+    }
+
+    /// Returns a message containing ANSI escape sequences, such as SGR colors.
+    ///
+    /// Escapes are not stripped or interpreted. When encoded, ESC and CSI
+    /// bytes pass through unchanged, and only 0xFF, which can appear when a
+    /// single-byte charset is active, is doubled as IAC.
+    pub fn ansi_message(text: impl Into<String>) -> Self {
+        TelnetEvent::Message(text.into())
+    }
+
+    /// Returns the complete wire representation of the event, as the codec
+    /// would encode it with its default settings.
+    ///
+    /// Events that the codec does not send, such as `Character`, produce an
+    /// empty vector.
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        crate::encode_event(self, &mut buffer);
+
+        buffer.to_vec()
+    }
+
     /// Returns the length (in bytes) of the event.
     pub fn len(&self) -> usize {
         match self {
             TelnetEvent::Message(message) => message.len(),
             TelnetEvent::RawMessage(message) => message.len(),
+            TelnetEvent::Prompt(prompt) => prompt.len(),
             TelnetEvent::Subnegotiate(subnegotiation) => {
                 // the 5 is made up of the IAC SB, IAC SE, and the single byte
                 // option
-                5 + subnegotiation.len()
+                subnegotiation.len().saturating_add(5)
             }
             TelnetEvent::Character(_) => 1,
             TelnetEvent::Do(_)
             | TelnetEvent::Will(_)
             | TelnetEvent::Dont(_)
-            | TelnetEvent::Wont(_) => 6,
+            | TelnetEvent::Wont(_) => 3,
             #[cfg(feature = "unicode")]
             TelnetEvent::Unicode(c) => c.len_utf8(),
-            _ => 5,
+            TelnetEvent::GoAhead
+            | TelnetEvent::Nop
+            | TelnetEvent::DataMark
+            | TelnetEvent::EraseCharacter
+            | TelnetEvent::EraseLine
+            | TelnetEvent::UnknownCommand(_) => 2,
+            TelnetEvent::MessageChunk { data, .. } => data.len(),
+            TelnetEvent::PreEncoded(bytes) => bytes.len(),
+            TelnetEvent::ProtocolError(_) => 0,
         }
     }
 
@@ -61,12 +135,58 @@ impl TelnetEvent {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Returns true if the event is a `Do`, `Dont`, `Will`, or `Wont`.
+    pub fn is_negotiation(&self) -> bool {
+        matches!(
+            self,
+            TelnetEvent::Do(_) | TelnetEvent::Dont(_) | TelnetEvent::Will(_) | TelnetEvent::Wont(_)
+        )
+    }
+
+    /// Returns true if the event carries text or character data.
+    pub fn is_data(&self) -> bool {
+        match self {
+            TelnetEvent::Message(_)
+            | TelnetEvent::RawMessage(_)
+            | TelnetEvent::Prompt(_)
+            | TelnetEvent::MessageChunk { .. }
+            | TelnetEvent::Character(_) => true,
+            #[cfg(feature = "unicode")]
+            TelnetEvent::Unicode(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the event is a subnegotiation.
+    pub fn is_subnegotiation(&self) -> bool {
+        matches!(self, TelnetEvent::Subnegotiate(_))
+    }
+
+    /// Returns true if the event is a two-byte command, such as `GoAhead` or
+    /// `Nop`.
+    pub fn is_command(&self) -> bool {
+        matches!(
+            self,
+            TelnetEvent::GoAhead
+                | TelnetEvent::Nop
+                | TelnetEvent::DataMark
+                | TelnetEvent::EraseCharacter
+                | TelnetEvent::EraseLine
+                | TelnetEvent::UnknownCommand(_)
+        )
+    }
 }
 
 impl From<TelnetEvent> for u8 {
     fn from(event: TelnetEvent) -> Self {
         match event {
-            TelnetEvent::Message(_) | TelnetEvent::RawMessage(_) => 0x00,
+            TelnetEvent::Message(_)
+            | TelnetEvent::RawMessage(_)
+            | TelnetEvent::Prompt(_)
+            | TelnetEvent::MessageChunk { .. }
+            | TelnetEvent::PreEncoded(_)
+            | TelnetEvent::ProtocolError(_) => 0x00,
             TelnetEvent::Do(_) => DO,
             TelnetEvent::Will(_) => WILL,
             TelnetEvent::Dont(_) => DONT,
@@ -75,8 +195,37 @@ impl From<TelnetEvent> for u8 {
             TelnetEvent::Character(byte) => byte,
             TelnetEvent::GoAhead => GA,
             TelnetEvent::Nop => NOP,
+            TelnetEvent::DataMark => DM,
+            TelnetEvent::EraseCharacter => EC,
+            TelnetEvent::EraseLine => EL,
+            TelnetEvent::UnknownCommand(byte) => byte,
             #[cfg(feature = "unicode")]
             TelnetEvent::Unicode(_) => 0x00,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classifiers() {
+        let negotiation = TelnetEvent::Will(TelnetOption::GMCP);
+        assert!(negotiation.is_negotiation());
+        assert!(!negotiation.is_data());
+
+        let data = TelnetEvent::Message("hello".to_string());
+        assert!(data.is_data());
+        assert!(!data.is_command());
+        assert!(TelnetEvent::Character(b'a').is_data());
+
+        let subnegotiation = TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(80, 24));
+        assert!(subnegotiation.is_subnegotiation());
+        assert!(!subnegotiation.is_negotiation());
+
+        assert!(TelnetEvent::GoAhead.is_command());
+        assert!(TelnetEvent::UnknownCommand(200).is_command());
+        assert!(!TelnetEvent::Nop.is_subnegotiation());
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{error::TelnetError, option::TelnetOption, subnegotiation::SubnegotiationType};
+
+type Result<T> = std::result::Result<T, TelnetError>;
+
+/// A GMCP message, with the standard packages decoded into typed values. A
+/// message is a package name, optionally followed by a space and a JSON
+/// value, such as `Core.Hello {"client":"Mudlet","version":"4.17"}`.
+///
+/// Package names are matched case-insensitively, as GMCP requires.
+///
+/// See <https://www.gammon.com.au/gmcp> for more information.
+#[derive(Debug, PartialEq, Clone)]
+pub enum GmcpPackage {
+    /// `Core.Hello`, sent by the client to identify itself.
+    CoreHello(CoreHello),
+    /// `Core.Supports.Set`, listing the packages the client supports, such as
+    /// `"Char 1"`.
+    CoreSupportsSet(Vec<String>),
+    /// `Char.Vitals`, reporting the character's health and other pools.
+    CharVitals(CharVitals),
+    /// `Room.Info`, describing the room the character is in.
+    RoomInfo(RoomInfo),
+    /// Any other package, by name, with its value. A package without a value
+    /// has a value of `Value::Null`.
+    Raw(String, Value),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct CoreHello {
+    pub client: String,
+    pub version: String,
+}
+
+/// The vitals of a character. Servers commonly send the numbers as strings,
+/// so either form is accepted. Any other fields are kept in `other`.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct CharVitals {
+    #[serde(
+        default,
+        deserialize_with = "number_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub hp: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "number_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub maxhp: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "number_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mp: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "number_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub maxmp: Option<i64>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct RoomInfo {
+    #[serde(default)]
+    pub num: i64,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub area: String,
+    #[serde(default)]
+    pub environment: String,
+    /// The room number each exit leads to, by direction.
+    #[serde(default)]
+    pub exits: HashMap<String, i64>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+fn number_or_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<i64>, D::Error> {
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Number(number)) => {
+            number.as_i64().map(Some).ok_or_else(|| serde::de::Error::custom("expected an integer"))
+        }
+        Some(Value::String(string)) => {
+            string.trim().parse().map(Some).map_err(serde::de::Error::custom)
+        }
+        Some(_) => Err(serde::de::Error::custom("expected a number or a string")),
+    }
+}
+
+impl GmcpPackage {
+    /// Parses the payload of a GMCP subnegotiation.
+    ///
+    /// Returns an error if the value is not valid JSON, or does not have the
+    /// shape of a known package.
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let payload = std::str::from_utf8(payload)
+            .map_err(|_| "received a GMCP message that is not valid UTF-8".to_string())?;
+
+        let (name, value) = match payload.split_once(' ') {
+            Some((name, value)) if !value.trim().is_empty() => (name, Some(value)),
+            Some((name, _)) => (name, None),
+            None => (payload, None),
+        };
+
+        match name.to_ascii_lowercase().as_str() {
+            "core.hello" => Ok(GmcpPackage::CoreHello(typed(name, value)?)),
+            "core.supports.set" => Ok(GmcpPackage::CoreSupportsSet(typed(name, value)?)),
+            "char.vitals" => Ok(GmcpPackage::CharVitals(typed(name, value)?)),
+            "room.info" => Ok(GmcpPackage::RoomInfo(typed(name, value)?)),
+            _ => {
+                let value = match value {
+                    Some(value) => serde_json::from_str(value)
+                        .map_err(|error| format!("invalid GMCP value for {name}: {error}"))?,
+                    None => Value::Null,
+                };
+
+                Ok(GmcpPackage::Raw(name.to_string(), value))
+            }
+        }
+    }
+
+    /// Returns the package name, as written on the wire.
+    pub fn name(&self) -> &str {
+        match self {
+            GmcpPackage::CoreHello(_) => "Core.Hello",
+            GmcpPackage::CoreSupportsSet(_) => "Core.Supports.Set",
+            GmcpPackage::CharVitals(_) => "Char.Vitals",
+            GmcpPackage::RoomInfo(_) => "Room.Info",
+            GmcpPackage::Raw(name, _) => name,
+        }
+    }
+
+    /// Encodes the message as the payload of a GMCP subnegotiation.
+    pub fn to_payload(&self) -> String {
+        let value = match self {
+            GmcpPackage::CoreHello(hello) => serde_json::to_value(hello),
+            GmcpPackage::CoreSupportsSet(packages) => serde_json::to_value(packages),
+            GmcpPackage::CharVitals(vitals) => serde_json::to_value(vitals),
+            GmcpPackage::RoomInfo(room) => serde_json::to_value(room),
+            GmcpPackage::Raw(_, value) => Ok(value.clone()),
+        };
+
+        match value {
+            Ok(Value::Null) | Err(_) => self.name().to_string(),
+            Ok(value) => format!("{} {value}", self.name()),
+        }
+    }
+}
+
+/// Deserializes the value of a known package, which is required.
+fn typed<T: DeserializeOwned>(name: &str, value: Option<&str>) -> Result<T> {
+    let value = value.ok_or_else(|| format!("GMCP package {name} requires a value"))?;
+
+    serde_json::from_str(value)
+        .map_err(|error| format!("invalid GMCP value for {name}: {error}").into())
+}
+
+impl From<GmcpPackage> for SubnegotiationType {
+    fn from(package: GmcpPackage) -> Self {
+        SubnegotiationType::Unknown(TelnetOption::GMCP, Bytes::from(package.to_payload()))
+    }
+}
+
+impl SubnegotiationType {
+    /// Parses a GMCP subnegotiation into a `GmcpPackage`, or returns `None`
+    /// for a subnegotiation of any other option.
+    pub fn gmcp(&self) -> Option<Result<GmcpPackage>> {
+        match self {
+            SubnegotiationType::Unknown(TelnetOption::GMCP, payload) => {
+                Some(GmcpPackage::parse(payload))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_core_packages() {
+        assert_eq!(
+            GmcpPackage::parse(br#"Core.Hello {"client":"Mudlet","version":"4.17"}"#).unwrap(),
+            GmcpPackage::CoreHello(CoreHello {
+                client: "Mudlet".to_string(),
+                version: "4.17".to_string()
+            })
+        );
+        assert_eq!(
+            GmcpPackage::parse(br#"core.supports.set ["Char 1", "Room 1"]"#).unwrap(),
+            GmcpPackage::CoreSupportsSet(vec!["Char 1".to_string(), "Room 1".to_string()])
+        );
+
+        // Numbers sent as strings are accepted, and unknown fields are kept
+        let GmcpPackage::CharVitals(vitals) =
+            GmcpPackage::parse(br#"Char.Vitals {"hp":"450","maxhp":500,"string":"H:450"}"#)
+                .unwrap()
+        else {
+            panic!("expected Char.Vitals");
+        };
+        assert_eq!((vitals.hp, vitals.maxhp, vitals.mp), (Some(450), Some(500), None));
+        assert_eq!(vitals.other.get("string"), Some(&json!("H:450")));
+
+        let GmcpPackage::RoomInfo(room) =
+            GmcpPackage::parse(br#"Room.Info {"num":12,"name":"Town Square","exits":{"n":13}}"#)
+                .unwrap()
+        else {
+            panic!("expected Room.Info");
+        };
+        assert_eq!(room.num, 12);
+        assert_eq!(room.name, "Town Square");
+        assert_eq!(room.exits.get("n"), Some(&13));
+    }
+
+    #[test]
+    fn test_raw_package() {
+        assert_eq!(
+            GmcpPackage::parse(br#"Comm.Channel.Text {"text":"hi"}"#).unwrap(),
+            GmcpPackage::Raw("Comm.Channel.Text".to_string(), json!({ "text": "hi" }))
+        );
+        assert_eq!(
+            GmcpPackage::parse(b"Core.Ping").unwrap(),
+            GmcpPackage::Raw("Core.Ping".to_string(), Value::Null)
+        );
+
+        assert!(GmcpPackage::parse(b"Core.Hello").is_err());
+        assert!(GmcpPackage::parse(b"Comm.Channel.Text {").is_err());
+    }
+
+    #[test]
+    fn test_subnegotiation_roundtrip() {
+        let package = GmcpPackage::CoreSupportsSet(vec!["Char 1".to_string()]);
+        let sb = SubnegotiationType::from(package.clone());
+
+        assert_eq!(
+            sb,
+            SubnegotiationType::Unknown(
+                TelnetOption::GMCP,
+                Bytes::from_static(br#"Core.Supports.Set ["Char 1"]"#)
+            )
+        );
+        assert_eq!(sb.gmcp().unwrap().unwrap(), package);
+        assert!(SubnegotiationType::WindowSize(80, 24).gmcp().is_none());
+    }
+}
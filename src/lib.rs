@@ -6,25 +6,49 @@
 // Originally based off of https://github.com/jtenner/telnet_codec, which has
 // been archived.
 
-use std::mem;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    time::Instant,
+};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use tokio_util::codec::{Decoder, Encoder};
-
+#[cfg(feature = "charset")]
+use encoding_rs::Encoding;
+#[cfg(feature = "mccp")]
+use flate2::FlushDecompress;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::CodecMetrics;
+#[cfg(feature = "charset")]
+use crate::subnegotiation::Charset;
 use crate::{
+    builder::TelnetCodecBuilder,
+    command::{decode_command, Command},
     constants::{
-        CHARSET, CHARSET_ACCEPTED, CHARSET_REJECTED, CHARSET_REQUEST, CHARSET_TTABLE_REJECTED, DO,
-        DONT, ENVIRON, IAC, LINEMODE, LINEMODE_FORWARD_MASK, LINEMODE_SLC, MODE, NAWS, NOP, SB, SE,
-        WILL, WONT,
+        AUTHENTICATION, CHARSET, CHARSET_ACCEPTED, CHARSET_REJECTED, CHARSET_REQUEST,
+        CHARSET_TTABLE, CHARSET_TTABLE_ACK, CHARSET_TTABLE_IS, CHARSET_TTABLE_NAK,
+        CHARSET_TTABLE_REJECTED, DM, DO, DONT, EC, EL, ENCRYPT, ENVIRON, GA, GMCP, IAC, IS,
+        LINEMODE, LINEMODE_FORWARD_MASK, LINEMODE_SLC, MODE, MSDP, NAWS, NOP, OLD_ENVIRON, SB, SE,
+        SEND, TSPEED, TTYPE, WILL, WONT, XDISPLOC,
     },
-    env::{decode_env, encode_env_op},
-    error::TelnetError,
+    env::{decode_env, decode_old_env, encode_env_op, encode_old_env_op},
+    error::{TelnetError, TelnetErrorType},
     event::TelnetEvent,
-    linemode::ForwardMaskOption,
-    option::TelnetOption,
-    subnegotiation::{LineModeOption, SubnegotiationType},
+    linemode::{ForwardMaskOption, LineMode, Modifiers},
+    msdp::{decode_msdp, encode_msdp},
+    option::{OptionState, PendingNegotiation, TelnetOption, DEFAULT_MUD_OPTIONS},
+    subnegotiation::{
+        LineModeOption, SubnegotiationType, TerminalSpeedOption, TerminalTypeOption,
+        XDisplayLocationOption,
+    },
 };
 
+/// A builder for configuring a `TelnetCodec`.
+pub mod builder;
+/// Telnet commands, such as `WILL` and `SB`, that follow an IAC byte.
+pub mod command;
 /// Various byte or byte sequences used in the Telnet protocol.
 pub mod constants;
 /// Telnet environment options
@@ -33,15 +57,142 @@ pub mod env;
 pub mod error;
 /// Top-level Telnet events, such as Message, Do, Will, and Subnegotiation.
 pub mod event;
+/// Typed values for the standard GMCP packages.
+#[cfg(feature = "gmcp-core")]
+pub mod gmcp;
 /// Telnet linemode options
 pub mod linemode;
+#[cfg(feature = "mccp")]
+mod mccp;
+/// Counters for observing the events processed by the codec.
+#[cfg(feature = "metrics")]
+pub mod metrics;
+/// Mud Server Data Protocol variables, including nested arrays and tables.
+pub mod msdp;
 /// Telnet options such as `Echo`, `GoAhead`, and `SuppressGoAhead`.
 pub mod option;
+/// A Telnet session that answers negotiations on top of the codec.
+pub mod session;
+/// Adaptors over a stream of decoded Telnet events.
+pub mod stream;
 /// Telnet subnegotiation options.
 pub mod subnegotiation;
+/// Assertions for testing code built on nectar.
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
 type Result<T> = std::result::Result<T, TelnetError>;
 
+/// The options returned by `TelnetCodec::supported_options`, which depend on
+/// the enabled features.
+const SUPPORTED_OPTIONS: &[TelnetOption] = {
+    const BUILT_IN: &[TelnetOption] = &[
+        TelnetOption::Binary,
+        TelnetOption::Echo,
+        TelnetOption::SuppressGoAhead,
+        TelnetOption::GMCP,
+        TelnetOption::MSDP,
+    ];
+    const COMPRESSION: &[TelnetOption] =
+        if cfg!(feature = "mccp") { &[TelnetOption::MCCP2, TelnetOption::MCCP3] } else { &[] };
+    const LEN: usize = BUILT_IN.len() + COMPRESSION.len() + DEFAULT_MUD_OPTIONS.len()
+        - if cfg!(feature = "charset") { 0 } else { 1 };
+    const OPTIONS: [TelnetOption; LEN] = {
+        let mut options = [TelnetOption::Binary; LEN];
+        let mut len = 0;
+        let sources = [BUILT_IN, COMPRESSION, DEFAULT_MUD_OPTIONS];
+
+        let mut source = 0;
+        while source < sources.len() {
+            let mut index = 0;
+            while index < sources[source].len() {
+                let option = sources[source][index];
+                if cfg!(feature = "charset") || !matches!(option, TelnetOption::Charset) {
+                    options[len] = option;
+                    len += 1;
+                }
+                index += 1;
+            }
+            source += 1;
+        }
+
+        assert!(len == LEN);
+        options
+    };
+
+    &OPTIONS
+};
+
+/// A stream framed with `TelnetCodec`, which yields and accepts
+/// `TelnetEvent`s.
+pub type TelnetFrame<S> = Framed<S, TelnetCodec>;
+
+/// A decoder for the payload of a subnegotiation, registered with
+/// `TelnetCodec::register_subnegotiation`. It receives the unescaped data
+/// between the option byte and `IAC SE`.
+pub type SubnegotiationDecoder = Box<dyn Fn(&[u8]) -> Option<SubnegotiationType> + Send + Sync>;
+
+/// A registered `SubnegotiationDecoder`, which cannot derive `Debug` itself.
+struct CustomDecoder(SubnegotiationDecoder);
+
+impl std::fmt::Debug for CustomDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CustomDecoder")
+    }
+}
+
+/// A command that was cut off by the end of the input, kept so that decoding
+/// resumes where it stopped when more input arrives.
+#[derive(Debug)]
+enum PartialCommand {
+    /// A negotiation command, such as `DO`, waiting for its option byte.
+    Negotiation(u8),
+    /// A subnegotiation waiting for its IAC SE.
+    Subnegotiation(PartialSubnegotiation),
+}
+
+/// The part of a subnegotiation decoded so far.
+#[derive(Debug)]
+struct PartialSubnegotiation {
+    option: u8,
+    /// The unescaped data received so far.
+    data: Vec<u8>,
+    /// Whether the frame contained a lone IAC, and is discarded at its end.
+    invalid: bool,
+    /// Whether the frame grew past `max_subnegotiation_length`, and is being
+    /// skipped up to its IAC SE.
+    overflowed: bool,
+}
+
+/// The line terminators that end a received message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// Lines end with `\r\n`, as required by the Telnet specification.
+    #[default]
+    Crlf,
+    /// Lines end with either `\r\n` or the reversed `\n\r` that some
+    /// misbehaving peers send.
+    LenientCrlf,
+    /// Lines end with a bare `\r`, as sent by classic Mac-style servers. A
+    /// `\n` directly after the `\r` is dropped rather than starting a new
+    /// line, so `\r\n` is accepted as well.
+    Cr,
+}
+
+/// How a received message that is not valid UTF-8 is decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidUtf8Policy {
+    /// Each invalid sequence is replaced with U+FFFD.
+    #[default]
+    Lossy,
+    /// Decoding fails with a `Codec` error.
+    Error,
+    /// Invalid sequences are removed from the message.
+    Drop,
+    /// The whole message is decoded as Latin-1 (ISO-8859-1) instead.
+    Latin1,
+}
+
 /// Implements a Tokio codec for the Telnet protocol, along with MUD-specific
 /// extension protocols such as GMCP.
 ///
@@ -49,15 +200,158 @@ type Result<T> = std::result::Result<T, TelnetError>;
 #[derive(Debug)]
 pub struct TelnetCodec {
     /// Whether or not the client has enabled the Suppress Go Ahead option.
+    ///
+    /// While this is set, data is decoded a byte at a time as `Character`
+    /// events, alongside Telnet commands, so `message_mode` has no effect. It
+    /// is updated automatically when both `track_options` and
+    /// `sga_follows_negotiation` are enabled.
     pub sga: bool,
+    /// If this field is set to true (and `track_options` is enabled), `sga`
+    /// is set once Suppress Go Ahead has been agreed in either direction,
+    /// and cleared when it is turned off, so that the codec switches to
+    /// character mode with the negotiation.
+    ///
+    /// It is off by default, as most MUD clients agree to SGA while still
+    /// sending whole lines, which should keep being decoded as `Message`s.
+    pub sga_follows_negotiation: bool,
     pub max_buffer_length: usize,
     pub buffer: Vec<u8>,
     /// If this field is set to false, nectar will generate an event for each
     /// character instead of each message
     pub message_mode: bool,
+    /// The line terminators accepted at the end of a message.
+    pub line_ending: LineEnding,
+    /// Whether BINARY is in effect for data we send. Outside of binary mode,
+    /// a CR that is not followed by LF is encoded as CR NUL, as required by
+    /// the NVT. It is updated automatically when `track_options` is enabled.
+    pub binary_local: bool,
+    /// Whether BINARY is in effect for data we receive. Outside of binary
+    /// mode, the NUL after a bare CR is dropped when decoding. It is updated
+    /// automatically when `track_options` is enabled.
+    pub binary_remote: bool,
+    /// How messages that are not valid UTF-8 are decoded. This has no effect
+    /// while a charset is set.
+    pub invalid_utf8: InvalidUtf8Policy,
     /// Attempt to parse unicode when received
     #[cfg(feature = "unicode")]
     pub unicode: bool,
+    /// The character set used to decode incoming messages and encode outgoing
+    /// messages. When this is `None`, messages are treated as UTF-8.
+    #[cfg(feature = "charset")]
+    pub charset: Option<&'static Encoding>,
+    /// If this field is set to true, a WILL, WONT, DO, or DONT is not encoded
+    /// when it repeats the last command sent for the same option. This
+    /// prevents negotiation loops with peers that echo negotiations back.
+    pub dedupe_negotiation: bool,
+    /// The negotiation state of each option in this session.
+    pub options: OptionState,
+    /// If this field is set to true, received negotiations are recorded in
+    /// `options` as well. `binary_local` and `binary_remote` follow BINARY in
+    /// each direction, and `sga` follows Suppress Go Ahead when
+    /// `sga_follows_negotiation` is enabled.
+    pub track_options: bool,
+    /// If this field is set to false, IAC bytes are treated as ordinary data
+    /// rather than the start of a Telnet command, and are not escaped when
+    /// encoding messages. This allows nectar to be used as a plain line codec
+    /// for protocols that do not speak Telnet.
+    pub process_iac: bool,
+    /// The most events `decode_all` will return from a single call. Any
+    /// remaining input is left in the buffer, so that a server multiplexing
+    /// many connections can yield to other tasks before decoding the rest.
+    /// When this is `None`, there is no limit.
+    pub max_events_per_poll: Option<usize>,
+    /// If this field is set to true, a single unescaped IAC inside a GMCP
+    /// subnegotiation is treated as data when the byte after it does not form
+    /// a valid command, rather than discarding the whole message. This
+    /// tolerates clients that forget to double 0xFF in their payloads. An IAC
+    /// followed by a command byte, such as `IAC WILL`, is still a command:
+    /// the unfinished message is discarded and the command is decoded.
+    pub lenient: bool,
+    /// The largest width or height accepted from a NAWS subnegotiation. Larger
+    /// dimensions, which buggy clients sometimes send, are clamped to this
+    /// value. When this is `None`, sizes are passed through unchanged.
+    pub max_window_size: Option<u16>,
+    /// The most data bytes accepted in a single subnegotiation. A longer one,
+    /// such as when the peer never sends IAC SE, is a `Codec` error, and the
+    /// rest of the frame is skipped up to its IAC SE. When this is `None`,
+    /// there is no limit.
+    pub max_subnegotiation_length: Option<usize>,
+    /// The SLC modifiers agreed for the SYNCH function in a linemode session,
+    /// or `None` if SYNCH is not handled. When its `flush_in` flag is set, an
+    /// `IAC DM` discards the unterminated line received before it.
+    pub synch: Option<Modifiers>,
+    /// If this field is set to true, every subnegotiation is decoded as
+    /// `SubnegotiationType::Unknown` with its unescaped payload, bypassing
+    /// the decoders for options such as NAWS and CHARSET. Encoding the event
+    /// reproduces the original frame, so a proxy can forward it verbatim.
+    pub decode_raw_subnegotiations: bool,
+    /// If this field is set to true, input that cannot be decoded, such as an
+    /// overlong subnegotiation, is returned as a `TelnetEvent::ProtocolError`
+    /// instead of an error, so that a `Framed` stream stays open. The bad data
+    /// is skipped either way. IO errors are still returned as errors.
+    pub errors_as_events: bool,
+    /// Whether the client has been asked to stop echoing its own input, such
+    /// as while it types a password.
+    pub client_echo_suppressed: bool,
+    /// If this field is set to true, consecutive messages that are decoded
+    /// from the same read are merged into a single `Message`, with each line
+    /// separated by `\n`. This reduces the number of events a chatty peer
+    /// produces.
+    pub coalesce_messages: bool,
+    /// If this field is set to true, an empty `Message` that directly follows
+    /// another is not returned, so that a run of blank lines is collapsed
+    /// into one.
+    pub collapse_blank_lines: bool,
+    /// If this field is set to true, control characters (bytes below 0x20,
+    /// other than CR and LF) are returned as `Character` events as soon as
+    /// they arrive, while `message_mode` still buffers printable text into
+    /// a `Message`. This suits line editors that react to keys like Ctrl-C.
+    pub control_chars_as_events: bool,
+    /// The longest message returned as a single event. Once a line grows
+    /// past this many bytes, it is returned as it arrives in
+    /// `MessageChunk`s, the last of which is returned when the line ends.
+    /// Shorter lines are still returned as a `Message`. When this is `None`,
+    /// lines are never chunked.
+    pub chunk_size: Option<usize>,
+    /// How deeply MSDP tables and arrays may be nested inside one another.
+    /// A subnegotiation that nests deeper is a `Codec` error, so that a peer
+    /// cannot make the codec build arbitrarily deep values.
+    pub max_msdp_depth: usize,
+    /// Options that are always refused when the peer asks to enable them.
+    denied: HashSet<TelnetOption>,
+    /// A command cut off by the end of the previous input.
+    partial_command: Option<PartialCommand>,
+    /// Decoders registered for the subnegotiations of specific options.
+    subnegotiation_decoders: HashMap<u8, CustomDecoder>,
+    /// Events generated by the codec itself, waiting to be sent.
+    outgoing: Vec<TelnetEvent>,
+    /// Negotiations sent while `track_options` is enabled that the peer has
+    /// not answered yet.
+    pending_negotiations: HashMap<TelnetOption, PendingNegotiation>,
+    /// An event decoded after a run of coalesced messages, returned by the
+    /// next call to `decode`.
+    held: Option<TelnetEvent>,
+    /// Input bytes consumed by `decode_with_consumed` since it last returned
+    /// an event.
+    unreported_consumed: usize,
+    /// The window size last returned by `resize`.
+    last_window_size: Option<(u16, u16)>,
+    /// The terminal type the peer last reported, recorded while
+    /// `track_options` is enabled.
+    terminal_type: Option<String>,
+    /// Whether the last event decoded was an empty `Message`.
+    last_blank: bool,
+    /// Whether the start of the pending line was returned as a
+    /// `MessageChunk`.
+    chunked: bool,
+    /// Whether the last byte decoded was a `\r` that ended a line, with
+    /// `line_ending` set to `LineEnding::Cr`.
+    after_cr: bool,
+    #[cfg(feature = "metrics")]
+    metrics: CodecMetrics,
+    /// The state of MCCP compression, in each direction.
+    #[cfg(feature = "mccp")]
+    compression: mccp::Compression,
 }
 
 impl TelnetCodec {
@@ -65,747 +359,3590 @@ impl TelnetCodec {
     pub fn new(max_buffer_length: usize) -> Self {
         TelnetCodec {
             sga: false,
+            sga_follows_negotiation: false,
             max_buffer_length,
             buffer: Vec::new(),
             message_mode: true,
+            line_ending: LineEnding::Crlf,
+            binary_local: false,
+            binary_remote: false,
+            invalid_utf8: InvalidUtf8Policy::Lossy,
             #[cfg(feature = "unicode")]
             unicode: false,
+            #[cfg(feature = "charset")]
+            charset: None,
+            dedupe_negotiation: false,
+            options: OptionState::default(),
+            track_options: false,
+            process_iac: true,
+            max_events_per_poll: None,
+            lenient: false,
+            max_window_size: None,
+            max_subnegotiation_length: None,
+            synch: None,
+            decode_raw_subnegotiations: false,
+            errors_as_events: false,
+            client_echo_suppressed: false,
+            coalesce_messages: false,
+            collapse_blank_lines: false,
+            control_chars_as_events: false,
+            chunk_size: None,
+            max_msdp_depth: 16,
+            denied: HashSet::new(),
+            partial_command: None,
+            subnegotiation_decoders: HashMap::new(),
+            outgoing: Vec::new(),
+            pending_negotiations: HashMap::new(),
+            held: None,
+            unreported_consumed: 0,
+            last_window_size: None,
+            terminal_type: None,
+            last_blank: false,
+            chunked: false,
+            after_cr: false,
+            #[cfg(feature = "metrics")]
+            metrics: CodecMetrics::default(),
+            #[cfg(feature = "mccp")]
+            compression: mccp::Compression::default(),
         }
     }
-}
 
-impl Decoder for TelnetCodec {
-    type Item = TelnetEvent;
-    type Error = TelnetError;
+    /// Returns a builder for a codec, for settings such as
+    /// `initial_buffer_capacity` that are not plain fields.
+    #[must_use]
+    pub fn builder(max_buffer_length: usize) -> TelnetCodecBuilder {
+        TelnetCodecBuilder::new(max_buffer_length)
+    }
 
-    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>> {
-        let mut byte_index = 0;
+    /// Returns a codec whose line buffer is preallocated with room for
+    /// `capacity` bytes. This is shorthand for
+    /// `TelnetCodec::builder(max_buffer_length).initial_buffer_capacity(capacity)`.
+    #[must_use]
+    pub fn with_buffer_capacity(max_buffer_length: usize, capacity: usize) -> Self {
+        TelnetCodec::builder(max_buffer_length).initial_buffer_capacity(capacity).build()
+    }
 
-        if self.sga && !self.buffer.is_empty() {
-            let buf = mem::take(&mut self.buffer);
-            let result = String::from_utf8_lossy(&buf[..]);
+    /// Returns a codec configured for a typical MUD server: message mode with
+    /// Go Ahead, a 4096 byte line limit, and option tracking. The offers for
+    /// GMCP, MSSP, and NAWS (`Will(GMCP)`, `Will(MSSP)`, and `Do(NAWS)`) are
+    /// queued for `take_outgoing`, to be sent once the client connects.
+    #[must_use]
+    pub fn mud_server() -> Self {
+        TelnetCodec::builder(4096)
+            .track_options(true)
+            .offer(&[TelnetOption::GMCP, TelnetOption::MSSP, TelnetOption::NAWS])
+            .build()
+    }
 
-            return Ok(Some(TelnetEvent::Message(result.to_string())));
-        }
+    /// Returns a codec configured for a typical MUD client, with the same
+    /// settings as `mud_server`. Only `Will(NAWS)` is queued for
+    /// `take_outgoing`, as GMCP and MSSP are offered by the server.
+    #[must_use]
+    pub fn mud_client() -> Self {
+        TelnetCodec::builder(4096)
+            .track_options(true)
+            .queue(TelnetEvent::Will(TelnetOption::NAWS))
+            .build()
+    }
 
-        if buffer.is_empty() {
-            return Ok(None);
-        }
+    /// Sets the character set used for incoming and outgoing messages, typically to
+    /// the name agreed upon during CHARSET negotiation (e.g. `"ISO-8859-1"`).
+    ///
+    /// Returns an error if the name is not a known encoding label.
+    #[cfg(feature = "charset")]
+    pub fn set_charset(&mut self, name: &str) -> Result<()> {
+        // Telnet charset names are IANA names, which are not always valid
+        // WHATWG labels (e.g. "LATIN-1"), so retry without the dashes.
+        let encoding = Encoding::for_label(name.as_bytes())
+            .or_else(|| Encoding::for_label(name.replace('-', "").as_bytes()))
+            .ok_or_else(|| format!("unknown charset: {name}"))?;
 
-        if self.sga {
-            return Ok(decode_suppress_go_ahead(&mut byte_index, buffer));
+        self.charset = Some(encoding);
+
+        Ok(())
+    }
+
+    /// Answers a `CharsetRequest` from the other side with the first charset
+    /// in `preferred` that it offered, and makes that charset active for
+    /// subsequent messages. The reply is `CharsetAccepted` with the name as
+    /// the other side spelled it, or `CharsetRejected` when none of the
+    /// offered charsets are preferred (or `request` is not a request).
+    #[cfg(feature = "charset")]
+    pub fn accept_charset(
+        &mut self,
+        request: &SubnegotiationType,
+        preferred: &[Charset],
+    ) -> TelnetEvent {
+        if let SubnegotiationType::CharsetRequest(_, offered) = request {
+            for charset in preferred {
+                let name = offered.iter().find(|name| Charset::from(*name) == *charset);
+
+                if let Some(name) = name {
+                    if self.set_charset(charset.as_str()).is_ok() {
+                        return TelnetEvent::Subnegotiate(SubnegotiationType::CharsetAccepted(
+                            name.clone(),
+                        ));
+                    }
+                }
+            }
         }
 
-        Ok(decode_bytes(self, &mut byte_index, buffer))
+        TelnetEvent::Subnegotiate(SubnegotiationType::CharsetRejected)
     }
-}
 
-impl Encoder<TelnetEvent> for TelnetCodec {
-    type Error = TelnetError;
+    /// Wraps `stream` in a `Framed` using this codec, with a read buffer
+    /// sized by `recommended_read_buffer`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use futures_lite::StreamExt;
+    /// use futures_util::sink::SinkExt;
+    /// use nectar::{event::TelnetEvent, TelnetCodec};
+    /// use tokio::net::TcpStream;
+    ///
+    /// async fn handler(stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+    ///     let mut frame = TelnetCodec::new(1024).framed(stream);
+    ///
+    ///     while let Some(Ok(event)) = frame.next().await {
+    ///         if let TelnetEvent::Message(message) = event {
+    ///             frame.send(TelnetEvent::Message(message)).await?;
+    ///         }
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn framed<S>(self, stream: S) -> TelnetFrame<S> {
+        let capacity = self.recommended_read_buffer();
+
+        Framed::with_capacity(stream, self, capacity)
+    }
 
-    fn encode(&mut self, event: TelnetEvent, buffer: &mut BytesMut) -> Result<()> {
-        match event {
-            TelnetEvent::Do(option) => encode_negotiate(DO, option, buffer),
-            TelnetEvent::Dont(option) => encode_negotiate(DONT, option, buffer),
-            TelnetEvent::Will(option) => encode_negotiate(WILL, option, buffer),
-            TelnetEvent::Wont(option) => encode_negotiate(WONT, option, buffer),
-            TelnetEvent::Subnegotiate(sb_type) => encode_sb(sb_type, buffer),
-            TelnetEvent::Message(msg) => encode_message(msg, buffer),
-            TelnetEvent::RawMessage(msg) => encode_raw_message(msg, buffer),
-            _ => {}
-        }
+    /// Returns a suggested initial capacity for the read buffer of a `Framed`
+    /// using this codec, for use with `Framed::with_capacity`.
+    ///
+    /// This leaves room for a full line where every byte arrived as an
+    /// escaped `IAC IAC` pair, plus the line ending, so that a maximum length
+    /// line can be read without reallocating. It is never smaller than the 8
+    /// KiB that `Framed` uses by default.
+    pub fn recommended_read_buffer(&self) -> usize {
+        self.max_buffer_length.saturating_mul(2).saturating_add(2).max(8 * 1024)
+    }
 
-        Ok(())
+    /// Returns the options this build of nectar has built-in handling for,
+    /// which an application can advertise to the peer and refuse the rest.
+    /// These are the options with codec state, such as `sga` for Suppress Go
+    /// Ahead, GMCP and MSDP, and those in `DEFAULT_MUD_OPTIONS`. CHARSET is
+    /// only included when the `charset` feature is enabled, as the codec
+    /// cannot switch encodings without it, and MCCP2 and MCCP3 only with the
+    /// `mccp` feature.
+    pub fn supported_options(&self) -> &'static [TelnetOption] {
+        SUPPORTED_OPTIONS
     }
-}
 
-#[cfg(feature = "unicode")]
-fn decode_utf8(byte_index: usize, buffer: &mut BytesMut, start: u8) -> Option<TelnetEvent> {
-    let length = match start {
-        0xC2..=0xDF => 2,
-        0xE0..=0xEF => 3,
-        // In theory this should never happen...
-        0xF0..=0xF4 => 4,
-        _ => 1,
-    };
+    /// Returns the negotiations a server sends to offer each of `options`, in
+    /// order. Options the server performs, such as GMCP and MSSP, are offered
+    /// with `Will`, and options the client performs, such as NAWS and TTYPE,
+    /// are requested with `Do`. Unknown options are offered with `Will`.
+    pub fn offer(&self, options: &[TelnetOption]) -> Vec<TelnetEvent> {
+        options
+            .iter()
+            .map(|&option| match option {
+                TelnetOption::NAWS
+                | TelnetOption::TerminalType
+                | TelnetOption::TerminalSpeed
+                | TelnetOption::XDisplayLocation
+                | TelnetOption::Environ
+                | TelnetOption::OldEnviron
+                | TelnetOption::LineMode
+                | TelnetOption::RemoteFlowControl
+                | TelnetOption::Authentication => TelnetEvent::Do(option),
+                _ => TelnetEvent::Will(option),
+            })
+            .collect()
+    }
 
-    if length == 1 {
-        buffer.advance(byte_index + 1);
-        Some(TelnetEvent::Unicode(start as char))
-    } else {
-        if let Ok(s) = std::str::from_utf8(&buffer[byte_index..byte_index + length]) {
-            if s.chars().count() != 1 {
-                // Something weird happened here...
-                // Maybe we should disconnect / fail here instead
+    /// Refuses an option whenever the peer asks to enable it. A received
+    /// `Will` is answered with `Dont`, and a received `Do` with `Wont`; the
+    /// answers are queued for `take_outgoing`. The original event is still
+    /// returned from `decode`.
+    pub fn deny(&mut self, option: TelnetOption) {
+        self.denied.insert(option);
+    }
 
-                buffer.advance(byte_index + length);
-                return Some(TelnetEvent::Nop);
+    /// Registers a decoder for the subnegotiations of an option, such as an
+    /// experimental MUD protocol. The decoder is given the unescaped payload,
+    /// and is consulted before the built-in decoders; when it returns `None`,
+    /// the subnegotiation is decoded as usual, which is
+    /// `SubnegotiationType::Unknown` for options nectar does not know.
+    ///
+    /// Registering a decoder for the same option again replaces it. Decoders
+    /// are not consulted while `decode_raw_subnegotiations` is enabled.
+    pub fn register_subnegotiation(&mut self, option: u8, decoder: SubnegotiationDecoder) {
+        self.subnegotiation_decoders.insert(option, CustomDecoder(decoder));
+    }
+
+    /// Takes the events the codec has queued in response to decoded input,
+    /// such as refusals of denied options. These should be sent to the peer.
+    pub fn take_outgoing(&mut self) -> Vec<TelnetEvent> {
+        mem::take(&mut self.outgoing)
+    }
+
+    /// Returns the negotiations sent while `track_options` is enabled that
+    /// the peer has not answered yet, along with when each was sent. An
+    /// application can use this to time out or resend them.
+    ///
+    /// A `Do` or `Dont` is answered by a `Will` or `Wont` for the same
+    /// option, and a `Will` or `Wont` by a `Do` or `Dont`.
+    pub fn pending_negotiations(&self) -> &HashMap<TelnetOption, PendingNegotiation> {
+        &self.pending_negotiations
+    }
+
+    /// Returns the exact number of bytes `encode` would write for the event
+    /// with the current settings, including doubled IAC bytes, the line
+    /// ending added to a `Message`, and the active charset. A negotiation
+    /// that deduplication would suppress has a length of 0.
+    pub fn wire_len(&self, event: &TelnetEvent) -> usize {
+        let (command, option) = match event {
+            TelnetEvent::Do(option) => (DO, *option),
+            TelnetEvent::Dont(option) => (DONT, *option),
+            TelnetEvent::Will(option) => (WILL, *option),
+            TelnetEvent::Wont(option) => (WONT, *option),
+            TelnetEvent::Message(message)
+            | TelnetEvent::RawMessage(message)
+            | TelnetEvent::Prompt(message) => {
+                let bytes = self.encode_message(message.clone());
+                let mut length = escaped_len(&bytes, self.process_iac, self.binary_local);
+
+                // The line ending of a message, or the IAC GA after a prompt
+                match event {
+                    TelnetEvent::Message(_) if !bytes.ends_with(b"\r\n") => length += 2,
+                    TelnetEvent::Prompt(_) => length += 2,
+                    _ => {}
+                }
+
+                return length;
+            }
+            // Sized like `encode`, by BINARY in the peer's direction.
+            TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
+                LineModeOption::ForwardMask(ForwardMaskOption::Do(mask)),
+            )) => {
+                let mut buffer = BytesMut::new();
+                encode_forward_mask(mask, forward_mask_length(self.binary_remote), &mut buffer);
+
+                return buffer.len();
             }
+            event => {
+                let mut buffer = BytesMut::new();
+                encode_event(event, &mut buffer);
 
-            // We can unwrap here since we checked it above.
-            let c = s.chars().next().unwrap();
+                return buffer.len();
+            }
+        };
 
-            buffer.advance(byte_index + length);
-            return Some(TelnetEvent::Unicode(c));
+        if self.dedupe_negotiation && self.options.repeats_sent(command, option) {
+            return 0;
         }
 
-        // We were unable to parse the unicode...
-        // Discard the input and act like nothing happened!
+        // An option of 255 is doubled.
+        if u8::from(option) == IAC {
+            4
+        } else {
+            3
+        }
+    }
 
-        buffer.advance(byte_index + length);
-        Some(TelnetEvent::Nop)
+    /// Returns the NAWS subnegotiation to send after the terminal is resized,
+    /// or `None` if the size has not changed since it was last sent, or if
+    /// NAWS has not been agreed. Agreement is only known when
+    /// `track_options` is enabled.
+    pub fn resize(&mut self, width: u16, height: u16) -> Option<TelnetEvent> {
+        if !self.options.local_enabled(TelnetOption::NAWS)
+            || self.last_window_size == Some((width, height))
+        {
+            return None;
+        }
+
+        self.last_window_size = Some((width, height));
+
+        Some(TelnetEvent::Subnegotiate(SubnegotiationType::window_size(width, height)))
     }
-}
 
-fn decode_negotiate(byte_index: usize, buffer: &mut BytesMut, option: u8) -> Option<TelnetEvent> {
-    if byte_index + 2 >= buffer.len() {
-        return None;
+    /// Returns the terminal type the peer last reported with a TTYPE IS
+    /// subnegotiation, such as `"xterm-256color"`, or `None` if it has not
+    /// reported one. This is only recorded when `track_options` is enabled.
+    pub fn terminal_type(&self) -> Option<&str> {
+        self.terminal_type.as_deref()
     }
 
-    let byte = buffer[byte_index + 2];
-    buffer.advance(byte_index + 3);
-    match option {
-        WILL => Some(TelnetEvent::Will(byte.into())),
-        WONT => Some(TelnetEvent::Wont(byte.into())),
-        DO => Some(TelnetEvent::Do(byte.into())),
-        DONT => Some(TelnetEvent::Dont(byte.into())),
-        _ => None,
+    /// Returns the bytes of the unterminated line received so far.
+    pub fn pending(&self) -> &[u8] {
+        &self.buffer
     }
-}
 
-fn decode_suppress_go_ahead(byte_index: &mut usize, buffer: &mut BytesMut) -> Option<TelnetEvent> {
-    match buffer[0] {
-        IAC => {
-            if 1 >= buffer.len() {
-                return None;
-            }
+    /// Returns what `decode` would return next for `buffer`, as the byte
+    /// `u8::from` gives for that event, without consuming anything: the
+    /// command byte for a command or negotiation (such as `DO` or `SB`), the
+    /// character for a `Character`, and `0x00` for a line of text. Returns
+    /// `None` if more input is needed first.
+    ///
+    /// This is a best-effort classification of the first bytes, and can
+    /// disagree with `decode` in modes that split or flush lines on their
+    /// own, such as `chunk_size`, `control_chars_as_events`, or Suppress Go
+    /// Ahead.
+    pub fn peek(&self, buffer: &BytesMut) -> Option<u8> {
+        match &self.partial_command {
+            Some(PartialCommand::Negotiation(command)) => return Some(*command),
+            Some(PartialCommand::Subnegotiation(_)) => return Some(SB),
+            None => {}
+        }
 
-            match buffer[*byte_index + 1] {
-                IAC => {
-                    buffer.advance(2);
-                    Some(TelnetEvent::Character(IAC))
-                }
-                _ => None,
+        let mut index = 0;
+
+        while index < buffer.len() {
+            match buffer[index] {
+                IAC => match *buffer.get(index + 1)? {
+                    IAC => index += 1,
+                    // A GA ends the text before it as a message first.
+                    GA if index > 0 || !self.buffer.is_empty() => return Some(0x00),
+                    command => return Some(command),
+                },
+                byte if !self.message_mode => return Some(byte),
+                b'\n' => return Some(0x00),
+                b'\r' if self.line_ending == LineEnding::Cr => return Some(0x00),
+                _ => {}
             }
+
+            index += 1;
         }
-        _ => None,
+
+        None
     }
-}
 
-fn decode_negotiate_about_window_size(subvec: &[u8]) -> Option<TelnetEvent> {
-    match subvec.len() {
-        4 => {
-            let result = SubnegotiationType::WindowSize(
-                (u16::from(subvec[0]) << 8) | u16::from(subvec[1]),
-                (u16::from(subvec[2]) << 8) | u16::from(subvec[3]),
-            );
-            Some(TelnetEvent::Subnegotiate(result))
+    /// Returns true when an unterminated line is waiting in the buffer. When
+    /// the server then goes quiet, this is usually a prompt, such as
+    /// `"login: "`, which can be read with `pending`.
+    pub fn has_pending_prompt(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Takes the unterminated line waiting in the buffer as a `Prompt`, or
+    /// returns `None` if there is none. An application can call this once the
+    /// server has been quiet for a while, rather than the decoder guessing
+    /// when a partial line is a prompt.
+    ///
+    /// Returns an error if the line is not valid UTF-8 and `invalid_utf8` is
+    /// `InvalidUtf8Policy::Error`.
+    pub fn flush_prompt(&mut self) -> Result<Option<TelnetEvent>> {
+        if self.buffer.is_empty() {
+            return Ok(None);
         }
-        _ => None,
+
+        let buffer = self.take_buffer();
+
+        Ok(Some(TelnetEvent::Prompt(self.decode_message(&buffer)?)))
     }
-}
 
-fn decode_linemode(subvec: &[u8]) -> Option<TelnetEvent> {
-    if subvec.is_empty() {
-        return None;
+    /// Returns the event that asks the client to stop echoing its input
+    /// locally, typically sent before a password prompt. The server becomes
+    /// responsible for echoing, and may choose not to.
+    pub fn suppress_client_echo(&mut self) -> TelnetEvent {
+        self.client_echo_suppressed = true;
+
+        TelnetEvent::Will(TelnetOption::Echo)
     }
 
-    let suboption = match subvec[0] {
-        WILL | WONT | DO | DONT => LineModeOption::ForwardMask(ForwardMaskOption::from(subvec[0])),
-        _ => LineModeOption::from(subvec[0]),
-    };
+    /// Returns the event that hands echoing back to the client, undoing
+    /// `suppress_client_echo`.
+    pub fn restore_client_echo(&mut self) -> TelnetEvent {
+        self.client_echo_suppressed = false;
 
-    match suboption {
-        LineModeOption::SLC(_) => {
-            let slc_data = &subvec[1..];
+        TelnetEvent::Wont(TelnetOption::Echo)
+    }
 
-            // (function, flag, value)
-            let slc_triples = slc_data
-                .chunks_exact(3)
-                .map(|chunk| ((chunk[0], chunk[1]).into(), chunk[2] as char))
-                .collect();
-            Some(TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(LineModeOption::SLC(
-                slc_triples,
-            ))))
-        }
-        LineModeOption::ForwardMask(_) => {
-            let data = &subvec[2..];
-            let option = match subvec[0] {
-                DO => ForwardMaskOption::Do(data.to_vec()),
-                byte => ForwardMaskOption::from(byte),
+    /// Decodes every complete event in `buffer`, such as an entire captured
+    /// session. A trailing partial event is kept for the next call.
+    ///
+    /// If `max_events_per_poll` is set, at most that many events are decoded
+    /// and the rest of the input is left for the next call.
+    pub fn decode_all(&mut self, buffer: &mut BytesMut) -> Result<Vec<TelnetEvent>> {
+        let mut events = Vec::new();
+
+        while self.max_events_per_poll.is_none_or(|max| events.len() < max) {
+            let Some(event) = self.decode(buffer)? else {
+                break;
             };
 
-            Some(TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
-                LineModeOption::ForwardMask(option),
-            )))
+            events.push(event);
         }
-        LineModeOption::Mode(_) => {
-            let mode = subvec[1];
 
-            Some(TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(LineModeOption::Mode(
-                mode,
-            ))))
-        }
-        LineModeOption::Unknown(_, _) => {
-            let data = &subvec[1..];
-            Some(TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(LineModeOption::Unknown(
-                subvec[0],
-                Bytes::from(data.to_vec()),
-            ))))
-        }
+        Ok(events)
     }
-}
 
-fn decode_charset(subvec: &[u8]) -> Option<TelnetEvent> {
-    if subvec.is_empty() {
-        return None;
+    /// Decodes the next event from a borrowed slice, for callers that do not
+    /// hold their input in a `BytesMut`.
+    ///
+    /// Returns the decoded event (if any) along with the number of bytes
+    /// consumed from the front of `input`. The caller is responsible for
+    /// discarding those bytes before the next call.
+    pub fn decode_slice(&mut self, input: &[u8]) -> Result<(Option<TelnetEvent>, usize)> {
+        let mut buffer = BytesMut::from(input);
+        let event = self.decode(&mut buffer)?;
+
+        Ok((event, input.len() - buffer.len()))
     }
 
-    match subvec[0] {
-        CHARSET_REQUEST => {
-            if subvec.len() == 1 {
-                return None;
-            }
+    /// Decodes the next event, along with the number of input bytes it was
+    /// decoded from.
+    ///
+    /// Bytes consumed by calls that return no event, such as the start of an
+    /// unterminated line, are counted towards the next event, so the counts
+    /// add up to every byte removed from the input. When `coalesce_messages`
+    /// is enabled, the event after a merged message was already consumed
+    /// with it and reports 0.
+    pub fn decode_with_consumed(
+        &mut self,
+        buffer: &mut BytesMut,
+    ) -> Result<Option<(TelnetEvent, usize)>> {
+        let length = buffer.len();
+        let event = self.decode(buffer)?;
+        self.unreported_consumed += length - buffer.len();
+
+        Ok(event.map(|event| (event, mem::take(&mut self.unreported_consumed))))
+    }
 
-            let separator = subvec[1];
-            let charsets: Vec<_> =
-                subvec[2..].split(|&x| x == separator).map(|x| Bytes::from(x.to_vec())).collect();
+    /// Returns the counters for the events decoded so far.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &CodecMetrics {
+        &self.metrics
+    }
 
-            if charsets.is_empty() {
-                return None;
+    /// Decodes a single event and applies the codec's bookkeeping to it, such
+    /// as option tracking and denials.
+    fn decode_tracked(&mut self, buffer: &mut BytesMut) -> Result<Option<TelnetEvent>> {
+        #[cfg(feature = "metrics")]
+        let length = buffer.len();
+
+        let event = match self.decode_input(buffer) {
+            Err(error) if self.errors_as_events && matches!(error.kind, TelnetErrorType::Codec) => {
+                Some(TelnetEvent::ProtocolError(error.message))
             }
+            result => result?,
+        };
 
-            let result = SubnegotiationType::CharsetRequest(charsets);
-            Some(TelnetEvent::Subnegotiate(result))
-        }
-        CHARSET_ACCEPTED => {
-            let result = SubnegotiationType::CharsetAccepted(Bytes::from(subvec[1..].to_vec()));
-            Some(TelnetEvent::Subnegotiate(result))
-        }
-        CHARSET_REJECTED => {
-            let result = SubnegotiationType::CharsetRejected;
-            Some(TelnetEvent::Subnegotiate(result))
+        if self.track_options {
+            self.track_received(event.as_ref());
         }
-        CHARSET_TTABLE_REJECTED => {
-            let result = SubnegotiationType::CharsetTTableRejected;
-            Some(TelnetEvent::Subnegotiate(result))
+
+        match &event {
+            Some(TelnetEvent::Will(option)) if self.denied.contains(option) => {
+                self.outgoing.push(TelnetEvent::Dont(*option));
+            }
+            Some(TelnetEvent::Do(option)) if self.denied.contains(option) => {
+                self.outgoing.push(TelnetEvent::Wont(*option));
+            }
+            _ => {}
         }
-        _ => None,
-    }
-}
 
-fn decode_unknown(option: u8, subvec: Vec<u8>) -> TelnetEvent {
-    TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(option.into(), Bytes::from(subvec)))
-}
+        #[cfg(feature = "metrics")]
+        self.metrics.record(length - buffer.len(), event.as_ref());
 
-fn decode_next_byte(codec: &mut TelnetCodec, buffer_size: &mut usize, byte: u8) {
-    if buffer_size < &mut codec.max_buffer_length {
-        codec.buffer.push(byte);
-        *buffer_size += 1;
+        Ok(event)
     }
-}
 
-fn decode_subnegotiation_end(
-    invalid: bool,
-    buffer: &mut BytesMut,
-    subvec: Vec<u8>,
-    option: u8,
-) -> Option<TelnetEvent> {
-    if invalid {
-        None
-    } else {
-        let opt = match option {
-            NAWS => decode_negotiate_about_window_size(&subvec),
-            CHARSET => decode_charset(&subvec),
-            LINEMODE => decode_linemode(&subvec),
-            ENVIRON => decode_env(&subvec),
-            _ => Some(decode_unknown(option, subvec)),
-        };
+    /// Decodes a single event from the input as received, or from the
+    /// inflated input once the peer has started MCCP compression.
+    #[cfg(feature = "mccp")]
+    fn decode_input(&mut self, buffer: &mut BytesMut) -> Result<Option<TelnetEvent>> {
+        self.compression.inflate(buffer, FlushDecompress::None)?;
 
-        if let Some(event) = &opt {
-            buffer.advance(event.len());
+        if self.compression.is_active() {
+            let mut inflated = mem::take(&mut self.compression.inflated);
+            // The rest of the inflated input is kept, even on an error.
+            let event = self.decode_event(&mut inflated);
+            self.compression.inflated = inflated;
+
+            return event;
+        }
+
+        let event = self.decode_event(buffer)?;
+
+        // The peer compresses everything it sends after IAC SB MCCP2 (as a
+        // server) or IAC SB MCCP3 (as a client) IAC SE.
+        if let Some(TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+            TelnetOption::MCCP2 | TelnetOption::MCCP3,
+            _,
+        ))) = &event
+        {
+            self.compression.start_inflating();
         }
 
-        opt
+        Ok(event)
     }
-}
 
-fn decode_bytes(
-    codec: &mut TelnetCodec,
-    byte_index: &mut usize,
-    buffer: &mut BytesMut,
-) -> Option<TelnetEvent> {
-    let mut codec_buffer_size = codec.buffer.len();
+    #[cfg(not(feature = "mccp"))]
+    fn decode_input(&mut self, buffer: &mut BytesMut) -> Result<Option<TelnetEvent>> {
+        self.decode_event(buffer)
+    }
 
-    loop {
-        if *byte_index >= buffer.len() {
-            return None;
-        }
+    /// Decodes a single event, skipping any empty message that directly
+    /// follows another when `collapse_blank_lines` is enabled.
+    fn decode_collapsed(&mut self, buffer: &mut BytesMut) -> Result<Option<TelnetEvent>> {
+        loop {
+            let event = self.decode_tracked(buffer)?;
+            let blank = matches!(&event, Some(TelnetEvent::Message(message)) if message.is_empty());
 
-        // Handle matches against the first byte in the buffer.
-        match buffer[*byte_index] {
-            IAC => {
-                if *byte_index + 1 >= buffer.len() {
-                    return None;
+            if !(self.collapse_blank_lines && blank && self.last_blank) {
+                if event.is_some() {
+                    self.last_blank = blank;
                 }
 
-                // Handle matches against the second byte in the buffer.
-                match buffer[*byte_index + 1] {
-                    IAC => {
-                        if codec.buffer.len() < codec.max_buffer_length {
-                            codec.buffer.push(IAC);
-                            codec_buffer_size += 1;
-                        }
+                return Ok(event);
+            }
+        }
+    }
 
-                        *byte_index += 1;
-                    }
-                    DO => return decode_negotiate(*byte_index, buffer, DO),
-                    DONT => return decode_negotiate(*byte_index, buffer, DONT),
-                    WILL => return decode_negotiate(*byte_index, buffer, WILL),
-                    WONT => return decode_negotiate(*byte_index, buffer, WONT),
-                    SB => {
-                        if *byte_index + 2 >= buffer.len() {
-                            buffer.advance(*byte_index + 2);
-                            return None;
-                        }
+    /// Returns the event for a line that has ended: a `Message`, or the last
+    /// `MessageChunk` if the start of the line was already returned in
+    /// chunks.
+    fn line_event(&mut self, bytes: &[u8]) -> Result<TelnetEvent> {
+        let data = self.decode_message(bytes)?;
 
-                        let start = *byte_index;
-                        let opt = buffer[*byte_index + 2];
+        if mem::take(&mut self.chunked) {
+            return Ok(TelnetEvent::MessageChunk { data, last: true });
+        }
 
-                        *byte_index += 3;
+        Ok(TelnetEvent::Message(data))
+    }
 
-                        let mut subvec: Vec<u8> = Vec::new();
-                        let mut invalid = false;
+    /// Takes the first `chunk_size` bytes of the pending line as a
+    /// `MessageChunk`. A UTF-8 character split by the chunk boundary is left
+    /// in the buffer, to start the next chunk.
+    fn take_chunk(&mut self) -> Result<TelnetEvent> {
+        let mut chunk = self.take_buffer();
+
+        #[cfg(feature = "charset")]
+        let utf8 = self.charset.is_none();
+        #[cfg(not(feature = "charset"))]
+        let utf8 = true;
+
+        if utf8 {
+            if let Err(error) = std::str::from_utf8(&chunk) {
+                if error.error_len().is_none() && error.valid_up_to() > 0 {
+                    self.buffer.extend(chunk.drain(error.valid_up_to()..));
+                }
+            }
+        }
 
-                        loop {
-                            if *byte_index > buffer.len() {
-                                buffer.advance(start);
-                                return None;
-                            }
+        self.chunked = true;
 
-                            // Handle matches against the third byte in the
-                            // buffer. This is for subnegotiation.
-                            match buffer[*byte_index] {
-                                IAC => {
-                                    if *byte_index + 1 > buffer.len() {
-                                        return None;
-                                    }
-
-                                    // Handle matches against the fourth byte in
-                                    // the buffer. This is the final byte in the
-                                    // buffer.
-                                    match buffer[*byte_index + 1] {
-                                        SE => {
-                                            return decode_subnegotiation_end(
-                                                invalid, buffer, subvec, opt,
-                                            )
-                                        }
-                                        IAC => subvec.push(IAC),
-                                        _ => invalid = true,
-                                    }
-
-                                    *byte_index += 1;
-                                }
-                                _ => subvec.push(buffer[*byte_index]),
-                            }
+        Ok(TelnetEvent::MessageChunk { data: self.decode_message(&chunk)?, last: false })
+    }
 
-                            *byte_index += 1;
-                        }
-                    }
-                    NOP => *byte_index += 1,
-                    _ => {}
-                }
-            }
-            b'\n' => {
-                let mut codec_buffer = mem::take(&mut codec.buffer);
-                if codec_buffer.ends_with(&[b'\r']) {
-                    codec_buffer.pop();
-                    buffer.advance(*byte_index + 1);
+    /// Takes the pending line. The line is copied out, so the buffer keeps
+    /// its capacity for the next one rather than being reallocated.
+    fn take_buffer(&mut self) -> Vec<u8> {
+        let line = self.buffer.clone();
+        self.buffer.clear();
+
+        line
+    }
+
+    /// Decodes the next event from the input buffer, or the pending line
+    /// buffer when Suppress Go Ahead is enabled.
+    fn decode_event(&mut self, buffer: &mut BytesMut) -> Result<Option<TelnetEvent>> {
+        let mut byte_index = 0;
+
+        if self.sga && !self.buffer.is_empty() {
+            let buf = self.take_buffer();
+
+            return Ok(Some(self.line_event(&buf)?));
+        }
+
+        if buffer.is_empty() {
+            return Ok(None);
+        }
 
-                    let result = String::from_utf8_lossy(&codec_buffer[..]);
-                    return Some(TelnetEvent::Message(result.to_string()));
+        // Resume a command cut off by the end of the previous input. If it
+        // was a subnegotiation that turned out to be invalid, carry on with
+        // the input after it.
+        if let Some(partial) = self.partial_command.take() {
+            let event = match partial {
+                PartialCommand::Negotiation(command) => decode_negotiate(self, 0, buffer, command),
+                PartialCommand::Subnegotiation(partial) => {
+                    decode_subnegotiation(self, &mut 0, buffer, partial)?
                 }
+            };
 
-                decode_next_byte(codec, &mut codec_buffer_size, buffer[*byte_index]);
-            }
-            #[cfg(not(feature = "unicode"))]
-            c if !codec.message_mode => {
-                let mut codec_buffer = mem::take(&mut codec.buffer);
-                codec_buffer.pop();
-                buffer.advance(*byte_index + 1);
-                return Some(TelnetEvent::Character(c));
+            if event.is_some() || self.partial_command.is_some() || buffer.is_empty() {
+                return Ok(event);
             }
+        }
 
-            #[cfg(feature = "unicode")]
-            c if !codec.message_mode => {
-                // Unicode support is compiled in but not enabled,
-                // so just pass characters on as they are
+        if self.sga {
+            return decode_suppress_go_ahead(self, buffer);
+        }
 
-                if !codec.unicode {
-                    let mut codec_buffer = mem::take(&mut codec.buffer);
-                    codec_buffer.pop();
-                    buffer.advance(*byte_index + 1);
-                    return Some(TelnetEvent::Character(c));
-                }
+        decode_bytes(self, &mut byte_index, buffer)
+    }
+
+    /// Converts the bytes of a received message into a `String`, using the
+    /// active charset if one has been set. Otherwise, the message is UTF-8
+    /// and any invalid bytes are handled according to `invalid_utf8`.
+    fn decode_message(&self, bytes: &[u8]) -> Result<String> {
+        #[cfg(feature = "charset")]
+        if let Some(encoding) = self.charset {
+            return Ok(encoding.decode_without_bom_handling(bytes).0.into_owned());
+        }
+
+        if let Ok(message) = std::str::from_utf8(bytes) {
+            return Ok(message.to_string());
+        }
 
-                return decode_utf8(*byte_index, buffer, c);
+        let message = match self.invalid_utf8 {
+            InvalidUtf8Policy::Lossy => String::from_utf8_lossy(bytes).to_string(),
+            InvalidUtf8Policy::Error => {
+                return Err("received a message that is not valid UTF-8".to_string().into());
             }
-            _ => decode_next_byte(codec, &mut codec_buffer_size, buffer[*byte_index]),
+            InvalidUtf8Policy::Drop => bytes.utf8_chunks().map(|chunk| chunk.valid()).collect(),
+            InvalidUtf8Policy::Latin1 => bytes.iter().map(|&byte| byte as char).collect(),
         };
 
-        *byte_index += 1;
+        Ok(message)
     }
-}
 
-fn encode_negotiate(opt: u8, subopt: TelnetOption, buf: &mut BytesMut) {
-    buf.reserve(3);
-    buf.put_u8(IAC);
+    /// Records an outgoing negotiation and encodes it, unless deduplication is
+    /// enabled and it repeats the last command sent for the option.
+    fn encode_negotiate(&mut self, command: u8, option: TelnetOption, buffer: &mut BytesMut) {
+        if self.options.record_sent(command, option) || !self.dedupe_negotiation {
+            encode_negotiate(command, option, buffer);
+
+            if self.track_options {
+                let event = match command {
+                    DO => TelnetEvent::Do(option),
+                    DONT => TelnetEvent::Dont(option),
+                    WILL => TelnetEvent::Will(option),
+                    WONT => TelnetEvent::Wont(option),
+                    _ => unreachable!(),
+                };
+
+                self.pending_negotiations
+                    .insert(option, PendingNegotiation { event, sent_at: Instant::now() });
+            }
+        }
 
-    match opt {
-        DO => buf.put_u8(DO),
-        DONT => buf.put_u8(DONT),
-        WILL => buf.put_u8(WILL),
-        WONT => buf.put_u8(WONT),
-        _ => unreachable!(),
+        if self.track_options {
+            self.update_negotiated(option);
+        }
     }
 
-    buf.put_u8(subopt.into());
-}
+    /// Records a received negotiation.
+    fn track_received(&mut self, event: Option<&TelnetEvent>) {
+        let (command, option) = match event {
+            Some(TelnetEvent::Subnegotiate(SubnegotiationType::TerminalType(
+                TerminalTypeOption::Is(name),
+            ))) => {
+                self.terminal_type = Some(name.clone());
+                return;
+            }
+            Some(TelnetEvent::Do(option)) => (DO, *option),
+            Some(TelnetEvent::Dont(option)) => (DONT, *option),
+            Some(TelnetEvent::Will(option)) => (WILL, *option),
+            Some(TelnetEvent::Wont(option)) => (WONT, *option),
+            _ => return,
+        };
 
-fn encode_sb(sb: SubnegotiationType, buffer: &mut BytesMut) {
-    match sb {
-        SubnegotiationType::WindowSize(width, height) => {
-            buffer.reserve(9);
-            buffer.extend([IAC, SB, NAWS]);
-            buffer.put_u16(width);
-            buffer.put_u16(height);
-            buffer.extend([IAC, SE]);
+        self.options.record_received(command, option);
+
+        let answered = match self.pending_negotiations.get(&option).map(|pending| &pending.event) {
+            Some(TelnetEvent::Do(_) | TelnetEvent::Dont(_)) => matches!(command, WILL | WONT),
+            Some(TelnetEvent::Will(_) | TelnetEvent::Wont(_)) => matches!(command, DO | DONT),
+            _ => false,
+        };
+
+        if answered {
+            self.pending_negotiations.remove(&option);
         }
-        SubnegotiationType::CharsetRequest(charsets) => {
-            let charset_lens = charsets.iter().map(|c| c.len()).sum::<usize>();
-            let spaces = charsets.len().saturating_sub(1);
 
-            buffer.reserve(7 + charset_lens + spaces);
-            let sep = b' ';
-            buffer.extend([IAC, SB, CHARSET, CHARSET_REQUEST, sep]);
+        self.update_negotiated(option);
+    }
 
-            for (i, charset) in charsets.iter().enumerate() {
-                buffer.extend(charset);
-                if i < charsets.len() - 1 {
-                    buffer.put_u8(sep);
-                }
+    /// Updates the settings that follow the negotiated state of an option:
+    /// `sga` for Suppress Go Ahead (when `sga_follows_negotiation` is
+    /// enabled), and `binary_local` and `binary_remote` for BINARY.
+    fn update_negotiated(&mut self, option: TelnetOption) {
+        match option {
+            TelnetOption::SuppressGoAhead if self.sga_follows_negotiation => {
+                self.sga =
+                    self.options.local_enabled(option) || self.options.remote_enabled(option);
             }
-
-            buffer.extend([IAC, SE]);
-        }
-        SubnegotiationType::CharsetAccepted(charset) => {
-            buffer.reserve(6 + charset.len());
-            buffer.extend([IAC, SB, CHARSET, CHARSET_ACCEPTED]);
-            buffer.extend(charset);
-            buffer.extend([IAC, SE]);
-        }
-        SubnegotiationType::CharsetRejected => {
-            buffer.reserve(6);
-            buffer.extend([IAC, SB, CHARSET, CHARSET_REJECTED, IAC, SE]);
+            TelnetOption::Binary => {
+                self.binary_local = self.options.local_enabled(option);
+                self.binary_remote = self.options.remote_enabled(option);
+            }
+            _ => {}
         }
-        SubnegotiationType::CharsetTTableRejected => {
-            buffer.reserve(6);
-            buffer.extend([IAC, SB, CHARSET, CHARSET_TTABLE_REJECTED, IAC, SE]);
+    }
+
+    /// Converts an outgoing message into bytes, using the active charset if
+    /// one has been set.
+    fn encode_message(&self, message: String) -> Bytes {
+        #[cfg(feature = "charset")]
+        if let Some(encoding) = self.charset {
+            return Bytes::from(encoding.encode(&message).0.into_owned());
         }
-        SubnegotiationType::Environment(op) => {
-            buffer.extend([IAC, SB, ENVIRON]);
-            encode_env_op(op, buffer);
-            buffer.extend([IAC, SE]);
+
+        Bytes::from(message)
+    }
+}
+
+impl Decoder for TelnetCodec {
+    type Item = TelnetEvent;
+    type Error = TelnetError;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if let Some(event) = self.held.take() {
+            return Ok(Some(event));
         }
-        SubnegotiationType::Unknown(option, bytes) => {
-            let mut bytes_buffer_size = bytes.len() + 5;
 
-            for byte in &bytes {
-                if *byte == IAC {
-                    bytes_buffer_size += 1;
+        let mut event = self.decode_collapsed(buffer)?;
+
+        if self.coalesce_messages {
+            if let Some(TelnetEvent::Message(message)) = &mut event {
+                while let Some(next) = self.decode_collapsed(buffer)? {
+                    match next {
+                        TelnetEvent::Message(next) => {
+                            message.push('\n');
+                            message.push_str(&next);
+                        }
+                        next => {
+                            self.held = Some(next);
+                            break;
+                        }
+                    }
                 }
             }
+        }
+
+        Ok(event)
+    }
 
-            buffer.reserve(bytes_buffer_size);
+    /// Decodes the events left when the stream ends. A command cut off by the
+    /// end of the stream, such as a trailing lone IAC, can never complete, so
+    /// it is discarded instead of being reported as an error.
+    fn decode_eof(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if let Some(event) = self.decode(buffer)? {
+            return Ok(Some(event));
+        }
 
-            // IAC SUB OPTION
-            buffer.extend([IAC, SB, option.into()]);
+        // Output the decompressor is still holding is decoded before the
+        // stream is given up.
+        #[cfg(feature = "mccp")]
+        if self.compression.is_active() {
+            self.compression.inflate(buffer, FlushDecompress::Finish)?;
 
-            // Write to the buffer
-            for byte in &bytes {
-                if *byte == IAC {
-                    buffer.extend([IAC, IAC]);
-                } else {
-                    buffer.put_u8(*byte);
-                }
+            if let Some(event) = self.decode(buffer)? {
+                return Ok(Some(event));
             }
 
-            // IAC SUBNEGOTIATION END
-            buffer.extend([IAC, SE]);
+            self.compression.clear();
         }
-        SubnegotiationType::LineMode(mode) => match mode {
-            LineModeOption::Mode(value) => {
-                buffer.reserve(7);
-                buffer.extend([IAC, SB, LINEMODE, MODE, value, IAC, SE]);
-            }
-            LineModeOption::SLC(values) => {
-                // 4: Subnegotiation begin values.len() * 3: each entry
-                // symbolizes a triple of bytes:
-                // - Function
-                // - Modifiers (acknowledgement, urgency etc.)
-                // - Character 2: Subnegotiation end
 
-                buffer.reserve(6 + values.len() * 3);
-                buffer.extend([IAC, SB, LINEMODE, LINEMODE_SLC]);
+        // A command cut off by the end of the stream can never complete, but
+        // the unterminated line before it is still returned as a message.
+        buffer.clear();
+        self.partial_command = None;
 
-                for &(dispatch, char) in &values {
-                    let (first, second) = dispatch.into();
-                    buffer.extend([first, second, char as u8]);
-                }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
 
-                buffer.extend([IAC, SE]);
-            }
-            LineModeOption::ForwardMask(ForwardMaskOption::Do(data)) => {
-                // Note: this needs to be 32 bytes in binary mode
-                buffer.reserve(7 + 16);
+        let line = self.take_buffer();
 
-                buffer.extend([IAC, SB, LINEMODE, DO, LINEMODE_FORWARD_MASK]);
+        Ok(Some(self.line_event(&line)?))
+    }
+}
+
+impl Encoder<TelnetEvent> for TelnetCodec {
+    type Error = TelnetError;
 
-                let iter = data.into_iter().take(16);
-                let zeros = std::iter::repeat(0).take(16 - iter.len());
+    fn encode(&mut self, event: TelnetEvent, buffer: &mut BytesMut) -> Result<()> {
+        #[cfg(feature = "mccp")]
+        let start = buffer.len();
+        // Everything we send after IAC SB MCCP2 (as a server) or IAC SB MCCP3
+        // (as a client) IAC SE is compressed.
+        #[cfg(feature = "mccp")]
+        let starts_compression = matches!(
+            &event,
+            TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                TelnetOption::MCCP2 | TelnetOption::MCCP3,
+                _
+            ))
+        );
 
-                buffer.extend(iter.chain(zeros));
-                buffer.extend([IAC, SE]);
+        match event {
+            TelnetEvent::Do(option) => self.encode_negotiate(DO, option, buffer),
+            TelnetEvent::Dont(option) => self.encode_negotiate(DONT, option, buffer),
+            TelnetEvent::Will(option) => self.encode_negotiate(WILL, option, buffer),
+            TelnetEvent::Wont(option) => self.encode_negotiate(WONT, option, buffer),
+            TelnetEvent::Message(msg) => encode_message(
+                self.encode_message(msg),
+                self.process_iac,
+                self.binary_local,
+                buffer,
+            ),
+            TelnetEvent::RawMessage(msg) => {
+                let bytes = self.encode_message(msg);
+                encode_raw_message(bytes, self.process_iac, self.binary_local, buffer)
             }
-            LineModeOption::ForwardMask(option) => {
-                buffer.reserve(7);
-                buffer.extend([IAC, SB, LINEMODE, option.into(), LINEMODE_FORWARD_MASK, IAC, SE]);
+            TelnetEvent::Prompt(prompt) => {
+                let bytes = self.encode_message(prompt);
+                encode_raw_message(bytes, self.process_iac, self.binary_local, buffer);
+                buffer.extend([IAC, GA]);
             }
-            LineModeOption::Unknown(option, data) => {
-                buffer.reserve(7 + data.len());
-                buffer.extend([IAC, SB, LINEMODE, option]);
-                buffer.extend(data);
-                buffer.extend([IAC, SE]);
+            // The mask covers data the peer sends, so its length follows
+            // BINARY in that direction.
+            TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
+                LineModeOption::ForwardMask(ForwardMaskOption::Do(mask)),
+            )) => encode_forward_mask(&mask, forward_mask_length(self.binary_remote), buffer),
+            event => encode_event(&event, buffer),
+        }
+
+        #[cfg(feature = "mccp")]
+        {
+            self.compression.deflate(buffer, start)?;
+
+            if starts_compression {
+                self.compression.start_deflating();
             }
-        },
+        }
+
+        Ok(())
     }
 }
 
-fn encode_raw_message(message: String, buffer: &mut BytesMut) {
-    let bytes = Bytes::from(message);
-    let mut bytes_buffer_size = bytes.len();
-
-    for byte in &bytes {
-        if *byte == IAC {
-            bytes_buffer_size += 1;
+/// Encodes an event without any per-connection state, as UTF-8 and without
+/// negotiation deduplication.
+fn encode_event(event: &TelnetEvent, buffer: &mut BytesMut) {
+    match event {
+        TelnetEvent::Do(option) => encode_negotiate(DO, *option, buffer),
+        TelnetEvent::Dont(option) => encode_negotiate(DONT, *option, buffer),
+        TelnetEvent::Will(option) => encode_negotiate(WILL, *option, buffer),
+        TelnetEvent::Wont(option) => encode_negotiate(WONT, *option, buffer),
+        TelnetEvent::Subnegotiate(sb_type) => encode_sb(sb_type, buffer),
+        TelnetEvent::Message(msg) => {
+            encode_message(Bytes::copy_from_slice(msg.as_bytes()), true, false, buffer)
         }
+        TelnetEvent::RawMessage(msg) => {
+            encode_raw_message(Bytes::copy_from_slice(msg.as_bytes()), true, false, buffer)
+        }
+        TelnetEvent::Prompt(prompt) => {
+            encode_raw_message(Bytes::copy_from_slice(prompt.as_bytes()), true, false, buffer);
+            buffer.extend([IAC, GA]);
+        }
+        TelnetEvent::GoAhead => buffer.extend([IAC, GA]),
+        TelnetEvent::Nop => buffer.extend([IAC, NOP]),
+        TelnetEvent::DataMark => buffer.extend([IAC, DM]),
+        TelnetEvent::EraseCharacter => buffer.extend([IAC, EC]),
+        TelnetEvent::EraseLine => buffer.extend([IAC, EL]),
+        TelnetEvent::UnknownCommand(command) => buffer.extend([IAC, *command]),
+        TelnetEvent::PreEncoded(bytes) => buffer.extend_from_slice(bytes),
+        _ => {}
     }
+}
 
-    buffer.reserve(bytes_buffer_size);
+#[cfg(feature = "unicode")]
+fn decode_utf8(byte_index: usize, buffer: &mut BytesMut, start: u8) -> Option<TelnetEvent> {
+    let length = match start {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        // In theory this should never happen...
+        0xF0..=0xF4 => 4,
+        _ => 1,
+    };
 
-    for byte in &bytes {
-        if *byte == IAC {
-            buffer.extend([IAC, IAC]);
+    if length == 1 {
+        buffer.advance(byte_index + 1);
+        Some(TelnetEvent::Unicode(start as char))
+    } else {
+        if let Ok(s) = std::str::from_utf8(&buffer[byte_index..byte_index + length]) {
+            if s.chars().count() != 1 {
+                // Something weird happened here...
+                // Maybe we should disconnect / fail here instead
+
+                buffer.advance(byte_index + length);
+                return Some(TelnetEvent::Nop);
+            }
+
+            // We can unwrap here since we checked it above.
+            let c = s.chars().next().unwrap();
+
+            buffer.advance(byte_index + length);
+            return Some(TelnetEvent::Unicode(c));
         }
-        buffer.put_u8(*byte);
+
+        // We were unable to parse the unicode...
+        // Discard the input and act like nothing happened!
+
+        buffer.advance(byte_index + length);
+        Some(TelnetEvent::Nop)
     }
 }
 
-fn encode_message(message: String, buffer: &mut BytesMut) {
-    encode_raw_message(message, buffer);
+/// Parses a single negotiation, such as `[IAC, DO, ECHO]` into
+/// `TelnetEvent::Do(TelnetOption::Echo)`, without a codec.
+///
+/// Returns `None` if the bytes are not a WILL, WONT, DO, or DONT command. An
+/// option of 255 must be doubled, so it cannot be parsed from three bytes.
+pub fn parse_negotiation(bytes: &[u8; 3]) -> Option<TelnetEvent> {
+    let command = match decode_command(bytes)? {
+        command @ (Command::DO | Command::DONT | Command::WILL | Command::WONT) => command,
+        _ => return None,
+    };
+
+    let option = match bytes[2] {
+        IAC => return None,
+        byte => TelnetOption::from(byte),
+    };
 
-    if !buffer.ends_with(b"\r\n") {
-        buffer.reserve(2);
-        buffer.extend([b'\r', b'\n']);
+    match command {
+        Command::DO => Some(TelnetEvent::Do(option)),
+        Command::DONT => Some(TelnetEvent::Dont(option)),
+        Command::WILL => Some(TelnetEvent::Will(option)),
+        _ => Some(TelnetEvent::Wont(option)),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Decodes a negotiation whose option byte is at `index`. If the option has
+/// not arrived yet, the command before it is consumed and kept in
+/// `partial_command` until it does.
+fn decode_negotiate(
+    codec: &mut TelnetCodec,
+    index: usize,
+    buffer: &mut BytesMut,
+    command: u8,
+) -> Option<TelnetEvent> {
+    // An option byte of 255 collides with IAC, so a conforming peer doubles
+    // it. Consume the escape as well so that it is not read as the start of
+    // the next command.
+    let length = match buffer.get(index) {
+        Some(&IAC) => match buffer.get(index + 1) {
+            Some(&IAC) => Some(index + 2),
+            Some(_) => Some(index + 1),
+            None => None,
+        },
+        Some(_) => Some(index + 1),
+        None => None,
+    };
 
-    fn setup() -> (TelnetCodec, BytesMut) {
-        let codec = TelnetCodec::new(16);
-        let buffer = BytesMut::new();
-        (codec, buffer)
+    let Some(length) = length else {
+        buffer.advance(index);
+        codec.partial_command = Some(PartialCommand::Negotiation(command));
+        return None;
+    };
+
+    let byte = buffer[index];
+    buffer.advance(length);
+
+    match command {
+        WILL => Some(TelnetEvent::Will(byte.into())),
+        WONT => Some(TelnetEvent::Wont(byte.into())),
+        DO => Some(TelnetEvent::Do(byte.into())),
+        DONT => Some(TelnetEvent::Dont(byte.into())),
+        _ => None,
     }
+}
 
-    mod test_decode {
-        use super::*;
+fn decode_suppress_go_ahead(
+    codec: &mut TelnetCodec,
+    buffer: &mut BytesMut,
+) -> Result<Option<TelnetEvent>> {
+    // Data is passed on as it arrives, as in character mode.
+    if buffer[0] != IAC || !codec.process_iac {
+        let byte = buffer[0];
+        buffer.advance(1);
 
-        #[test]
-        fn test_sga_true() {
-            let (mut codec, mut buffer) = setup();
-            codec.sga = true;
+        return Ok(Some(TelnetEvent::Character(byte)));
+    }
 
-            // when both the codec's internal buffer, and the input buffer are
-            // empty, there's nothing going on.
-            assert!(codec.decode(&mut buffer).unwrap().is_none());
+    let Some(command) = decode_command(buffer) else {
+        return Ok(None);
+    };
 
-            // when the codec's internal buffer is not empty, clear it out and
-            // send it as a message
-            codec.buffer.extend([b'h', b'i', b'y', b'a', b' ', 0xf0, 0x9f, 0x98, 0x81]);
-            assert_eq!(
-                codec.decode(&mut buffer).unwrap().unwrap(),
-                TelnetEvent::Message("hiya 😁".to_string())
+    let event = match command {
+        Command::IAC => {
+            buffer.advance(2);
+            Some(TelnetEvent::Character(IAC))
+        }
+        command @ (Command::DO | Command::DONT | Command::WILL | Command::WONT) => {
+            decode_negotiate(codec, 2, buffer, command.into())
+        }
+        Command::SB => return decode_bytes(codec, &mut 0, buffer),
+        Command::GA => {
+            buffer.advance(2);
+            Some(TelnetEvent::GoAhead)
+        }
+        Command::NOP => {
+            buffer.advance(2);
+            Some(TelnetEvent::Nop)
+        }
+        Command::DM => {
+            buffer.advance(2);
+            Some(TelnetEvent::DataMark)
+        }
+        Command::EC => {
+            buffer.advance(2);
+            Some(TelnetEvent::EraseCharacter)
+        }
+        Command::EL => {
+            buffer.advance(2);
+            Some(TelnetEvent::EraseLine)
+        }
+        Command::SE => {
+            buffer.advance(2);
+            Some(decode_stray_se(codec))
+        }
+        command => {
+            buffer.advance(2);
+            Some(TelnetEvent::UnknownCommand(command.into()))
+        }
+    };
+
+    Ok(event)
+}
+
+fn decode_negotiate_about_window_size(
+    subvec: &[u8],
+    max_window_size: Option<u16>,
+) -> Option<TelnetEvent> {
+    match subvec.len() {
+        4 => {
+            let max = max_window_size.unwrap_or(u16::MAX);
+            let result = SubnegotiationType::WindowSize(
+                ((u16::from(subvec[0]) << 8) | u16::from(subvec[1])).min(max),
+                ((u16::from(subvec[2]) << 8) | u16::from(subvec[3])).min(max),
             );
-            assert!(codec.buffer.is_empty());
+            Some(TelnetEvent::Subnegotiate(result))
+        }
+        _ => None,
+    }
+}
+
+fn decode_linemode(subvec: &[u8], mask_length: usize) -> Option<TelnetEvent> {
+    if subvec.is_empty() {
+        return None;
+    }
+
+    let suboption = match subvec[0] {
+        WILL | WONT | DO | DONT => LineModeOption::ForwardMask(ForwardMaskOption::from(subvec[0])),
+        _ => LineModeOption::from(subvec[0]),
+    };
+
+    match suboption {
+        LineModeOption::SLC(_) => {
+            let slc_data = &subvec[1..];
+
+            // (function, flag, value)
+            let slc_triples = slc_data
+                .chunks_exact(3)
+                .map(|chunk| ((chunk[0], chunk[1]).into(), chunk[2] as char))
+                .collect();
+            Some(TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(LineModeOption::SLC(
+                slc_triples,
+            ))))
+        }
+        LineModeOption::ForwardMask(_) => {
+            // Trailing zero bytes may be left out of the mask.
+            let mut data = subvec[2..].to_vec();
+            data.resize(mask_length, 0);
+
+            let option = match subvec[0] {
+                DO => ForwardMaskOption::Do(data),
+                byte => ForwardMaskOption::from(byte),
+            };
+
+            Some(TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
+                LineModeOption::ForwardMask(option),
+            )))
+        }
+        LineModeOption::Mode(_) | LineModeOption::ModeAck(_) => {
+            let mut mode = LineMode::from(*subvec.get(1)?);
+
+            let option = if mode.contains(LineMode::MODE_ACK) {
+                mode.remove(LineMode::MODE_ACK);
+                LineModeOption::ModeAck(mode)
+            } else {
+                LineModeOption::Mode(mode)
+            };
+
+            Some(TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(option)))
+        }
+        LineModeOption::Unknown(_, _) => {
+            let data = &subvec[1..];
+            Some(TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(LineModeOption::Unknown(
+                subvec[0],
+                Bytes::from(data.to_vec()),
+            ))))
+        }
+    }
+}
+
+fn decode_charset(subvec: &[u8]) -> Option<TelnetEvent> {
+    if subvec.is_empty() {
+        return None;
+    }
+
+    match subvec[0] {
+        CHARSET_REQUEST => {
+            // An offer of a translation table is prefixed with `[TTABLE]` and
+            // its version, before the separator.
+            let (ttable, list) = match subvec[1..].strip_prefix(CHARSET_TTABLE) {
+                Some([version, list @ ..]) => (Some(*version), list),
+                Some([]) => return None,
+                None => (None, &subvec[1..]),
+            };
+
+            let [separator, list @ ..] = list else {
+                return None;
+            };
+
+            // Skip the empty entries left by repeated or trailing separators,
+            // along with any charset that was already offered.
+            let mut charsets: Vec<Bytes> = Vec::new();
+
+            for charset in list.split(|x| x == separator) {
+                if !charset.is_empty() && !charsets.iter().any(|c| c == charset) {
+                    charsets.push(Bytes::from(charset.to_vec()));
+                }
+            }
+
+            if charsets.is_empty() {
+                return None;
+            }
+
+            let result = SubnegotiationType::CharsetRequest(ttable, charsets);
+            Some(TelnetEvent::Subnegotiate(result))
+        }
+        CHARSET_ACCEPTED => {
+            let result = SubnegotiationType::CharsetAccepted(Bytes::from(subvec[1..].to_vec()));
+            Some(TelnetEvent::Subnegotiate(result))
+        }
+        CHARSET_REJECTED => {
+            let result = SubnegotiationType::CharsetRejected;
+            Some(TelnetEvent::Subnegotiate(result))
+        }
+        CHARSET_TTABLE_IS => {
+            let [version, table @ ..] = &subvec[1..] else {
+                return None;
+            };
+
+            let result = SubnegotiationType::CharsetTTableIs(*version, Bytes::from(table.to_vec()));
+            Some(TelnetEvent::Subnegotiate(result))
+        }
+        CHARSET_TTABLE_REJECTED => {
+            let result = SubnegotiationType::CharsetTTableRejected;
+            Some(TelnetEvent::Subnegotiate(result))
+        }
+        CHARSET_TTABLE_ACK => Some(TelnetEvent::Subnegotiate(SubnegotiationType::CharsetTTableAck)),
+        CHARSET_TTABLE_NAK => Some(TelnetEvent::Subnegotiate(SubnegotiationType::CharsetTTableNak)),
+        _ => None,
+    }
+}
+
+fn decode_terminal_speed(subvec: &[u8]) -> Option<TelnetEvent> {
+    let option = match *subvec.first()? {
+        IS => {
+            let speeds = std::str::from_utf8(&subvec[1..]).ok()?;
+            let (transmit, receive) = speeds.split_once(',')?;
+
+            TerminalSpeedOption::Is(transmit.trim().parse().ok()?, receive.trim().parse().ok()?)
+        }
+        SEND => TerminalSpeedOption::Send,
+        _ => return None,
+    };
+
+    Some(TelnetEvent::Subnegotiate(SubnegotiationType::TerminalSpeed(option)))
+}
+
+fn decode_terminal_type(subvec: &[u8]) -> Option<TelnetEvent> {
+    let option = match *subvec.first()? {
+        IS => TerminalTypeOption::Is(String::from_utf8(subvec[1..].to_vec()).ok()?),
+        SEND => TerminalTypeOption::Send,
+        _ => return None,
+    };
+
+    Some(TelnetEvent::Subnegotiate(SubnegotiationType::TerminalType(option)))
+}
+
+fn decode_x_display_location(subvec: &[u8]) -> Option<TelnetEvent> {
+    let option = match *subvec.first()? {
+        IS => XDisplayLocationOption::Is(String::from_utf8(subvec[1..].to_vec()).ok()?),
+        SEND => XDisplayLocationOption::Send,
+        _ => return None,
+    };
+
+    Some(TelnetEvent::Subnegotiate(SubnegotiationType::XDisplayLocation(option)))
+}
+
+fn decode_unknown(option: u8, subvec: Vec<u8>) -> TelnetEvent {
+    TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(option.into(), Bytes::from(subvec)))
+}
+
+fn decode_next_byte(codec: &mut TelnetCodec, buffer_size: &mut usize, byte: u8) {
+    if buffer_size < &mut codec.max_buffer_length {
+        codec.buffer.push(byte);
+        *buffer_size += 1;
+    } else {
+        #[cfg(feature = "metrics")]
+        {
+            codec.metrics.overflows += 1;
+        }
+    }
+}
+
+/// Decodes the data of a subnegotiation, starting at `byte_index`, until its
+/// IAC SE. If the input ends first, everything read so far is consumed and
+/// the frame is kept in `partial_command`, to be resumed with the next input.
+/// A trailing IAC stays in the input until the byte after it arrives.
+///
+/// Returns `None` without a partial command when the frame was discarded.
+fn decode_subnegotiation(
+    codec: &mut TelnetCodec,
+    byte_index: &mut usize,
+    buffer: &mut BytesMut,
+    mut partial: PartialSubnegotiation,
+) -> Result<Option<TelnetEvent>> {
+    loop {
+        // Give up on a frame whose IAC SE never arrives, rather than
+        // buffering it forever. The rest of the frame is still skipped, so
+        // that its data is not read as text, but the error is only reported
+        // once.
+        if let Some(max) = codec.max_subnegotiation_length {
+            if partial.data.len() > max {
+                partial.data.clear();
+                partial.invalid = true;
+
+                if !mem::replace(&mut partial.overflowed, true) {
+                    buffer.advance(*byte_index);
+                    codec.partial_command = Some(PartialCommand::Subnegotiation(partial));
+
+                    return Err(format!("subnegotiation exceeds {max} bytes").into());
+                }
+            }
+        }
+
+        if *byte_index >= buffer.len()
+            || (buffer[*byte_index] == IAC && *byte_index + 1 >= buffer.len())
+        {
+            buffer.advance(*byte_index);
+            codec.partial_command = Some(PartialCommand::Subnegotiation(partial));
+            return Ok(None);
+        }
+
+        match buffer[*byte_index] {
+            IAC => match buffer[*byte_index + 1] {
+                SE => {
+                    let event = decode_subnegotiation_end(
+                        codec,
+                        partial.invalid,
+                        buffer,
+                        *byte_index + 2,
+                        partial.data,
+                        partial.option,
+                    )?;
+
+                    #[cfg(feature = "metrics")]
+                    if event.is_none() {
+                        codec.metrics.resyncs += 1;
+                    }
+
+                    return Ok(event);
+                }
+                IAC => {
+                    partial.data.push(IAC);
+                    *byte_index += 1;
+                }
+                byte if codec.lenient && partial.option == GMCP && byte < SE => {
+                    // Keep the stray IAC as data and decode the next byte
+                    // normally.
+                    partial.data.push(IAC);
+                }
+                _ if codec.lenient && partial.option == GMCP => {
+                    // A real command means the frame was cut short, so drop
+                    // it and decode the command from its IAC.
+                    buffer.advance(*byte_index);
+
+                    #[cfg(feature = "metrics")]
+                    {
+                        codec.metrics.resyncs += 1;
+                    }
+
+                    return Ok(None);
+                }
+                _ => {
+                    partial.invalid = true;
+                    *byte_index += 1;
+                }
+            },
+            byte => partial.data.push(byte),
+        }
+
+        *byte_index += 1;
+    }
+}
+
+fn decode_subnegotiation_end(
+    codec: &TelnetCodec,
+    invalid: bool,
+    buffer: &mut BytesMut,
+    end: usize,
+    subvec: Vec<u8>,
+    option: u8,
+) -> Result<Option<TelnetEvent>> {
+    // Escaped IAC bytes make the frame longer than its decoded payload, so
+    // advance to the end of the frame rather than by the event length. The
+    // frame is consumed even when it cannot be decoded, so that it does not
+    // block the input behind it.
+    buffer.advance(end);
+
+    let event = if invalid {
+        None
+    } else if codec.decode_raw_subnegotiations {
+        Some(decode_unknown(option, subvec))
+    } else if let Some(sb) =
+        codec.subnegotiation_decoders.get(&option).and_then(|decoder| (decoder.0)(&subvec))
+    {
+        Some(TelnetEvent::Subnegotiate(sb))
+    } else {
+        match option {
+            NAWS => decode_negotiate_about_window_size(&subvec, codec.max_window_size),
+            CHARSET => decode_charset(&subvec),
+            // A received forward mask covers the data we send.
+            LINEMODE => decode_linemode(&subvec, forward_mask_length(codec.binary_local)),
+            ENVIRON => decode_env(&subvec),
+            OLD_ENVIRON => decode_old_env(&subvec),
+            TTYPE => decode_terminal_type(&subvec),
+            TSPEED => decode_terminal_speed(&subvec),
+            XDISPLOC => decode_x_display_location(&subvec),
+            AUTHENTICATION => Some(TelnetEvent::Subnegotiate(SubnegotiationType::Authentication(
+                Bytes::from(subvec),
+            ))),
+            ENCRYPT => {
+                Some(TelnetEvent::Subnegotiate(SubnegotiationType::Encrypt(Bytes::from(subvec))))
+            }
+            MSDP => Some(TelnetEvent::Subnegotiate(SubnegotiationType::Msdp(decode_msdp(
+                &subvec,
+                codec.max_msdp_depth,
+            )?))),
+            _ => Some(decode_unknown(option, subvec)),
+        }
+    };
+
+    Ok(event)
+}
+
+/// Returns the event for an `IAC SE` that does not end a subnegotiation, such
+/// as one left behind after a frame was cut short. It has already been
+/// consumed, so decoding carries on after it as a `Nop`, or a
+/// `ProtocolError` when `errors_as_events` is enabled.
+fn decode_stray_se(codec: &mut TelnetCodec) -> TelnetEvent {
+    #[cfg(feature = "metrics")]
+    {
+        codec.metrics.resyncs += 1;
+    }
+
+    if codec.errors_as_events {
+        return TelnetEvent::ProtocolError("received IAC SE outside a subnegotiation".to_string());
+    }
+
+    TelnetEvent::Nop
+}
+
+fn decode_bytes(
+    codec: &mut TelnetCodec,
+    byte_index: &mut usize,
+    buffer: &mut BytesMut,
+) -> Result<Option<TelnetEvent>> {
+    let mut codec_buffer_size = codec.buffer.len();
+
+    'decode: loop {
+        // Everything before `byte_index` has been copied into the codec's
+        // buffer, so it is consumed from the input even when no event is
+        // complete yet. Otherwise it would be buffered again on the next call.
+        if *byte_index >= buffer.len() {
+            buffer.advance(*byte_index);
+            return Ok(None);
+        }
+
+        let after_cr = mem::take(&mut codec.after_cr);
+
+        // Handle matches against the first byte in the buffer.
+        match buffer[*byte_index] {
+            IAC if codec.process_iac => {
+                let Some(command) = decode_command(&buffer[*byte_index..]) else {
+                    buffer.advance(*byte_index);
+                    return Ok(None);
+                };
+
+                // Handle matches against the second byte in the buffer.
+                match command {
+                    Command::IAC => {
+                        decode_next_byte(codec, &mut codec_buffer_size, IAC);
+                        *byte_index += 1;
+                    }
+                    command @ (Command::DO | Command::DONT | Command::WILL | Command::WONT) => {
+                        return Ok(decode_negotiate(
+                            codec,
+                            *byte_index + 2,
+                            buffer,
+                            command.into(),
+                        ));
+                    }
+                    Command::SB => {
+                        // Keep IAC SB until the option byte arrives. Only the
+                        // data before it, which is already buffered, is
+                        // consumed.
+                        if *byte_index + 2 >= buffer.len() {
+                            buffer.advance(*byte_index);
+                            return Ok(None);
+                        }
+
+                        let start = *byte_index;
+                        let option = buffer[*byte_index + 2];
+                        let mut invalid = false;
+
+                        *byte_index += 3;
+
+                        // An option byte of 255 is doubled like any other
+                        // IAC. A lone IAC starts a command instead, so the
+                        // frame has no option and is discarded at its end.
+                        if option == IAC {
+                            if *byte_index >= buffer.len() {
+                                buffer.advance(start);
+                                return Ok(None);
+                            }
+
+                            if buffer[*byte_index] == IAC {
+                                *byte_index += 1;
+                            } else {
+                                invalid = true;
+                                *byte_index -= 1;
+                            }
+                        }
+
+                        let partial = PartialSubnegotiation {
+                            option,
+                            data: Vec::new(),
+                            invalid,
+                            overflowed: false,
+                        };
+                        let event = decode_subnegotiation(codec, byte_index, buffer, partial)?;
+
+                        if event.is_some() || codec.partial_command.is_some() {
+                            return Ok(event);
+                        }
+
+                        // The frame was discarded, so carry on with the input
+                        // after it.
+                        *byte_index = 0;
+                        continue 'decode;
+                    }
+                    Command::NOP => {
+                        buffer.advance(*byte_index + 2);
+                        return Ok(Some(TelnetEvent::Nop));
+                    }
+                    Command::DM => {
+                        // The input before the Data Mark, including anything
+                        // already buffered, was typed before the SYNCH.
+                        if codec.synch.is_some_and(|modifiers| modifiers.flush_in) {
+                            codec.buffer.clear();
+                        }
+
+                        buffer.advance(*byte_index + 2);
+                        return Ok(Some(TelnetEvent::DataMark));
+                    }
+                    Command::EC => {
+                        buffer.advance(*byte_index + 2);
+                        return Ok(Some(TelnetEvent::EraseCharacter));
+                    }
+                    Command::EL => {
+                        buffer.advance(*byte_index + 2);
+                        return Ok(Some(TelnetEvent::EraseLine));
+                    }
+                    Command::GA => {
+                        // A GA in the middle of a line marks a prompt, so
+                        // emit the text before it first and leave the GA for
+                        // the next call.
+                        if !codec.buffer.is_empty() {
+                            let codec_buffer = codec.take_buffer();
+                            buffer.advance(*byte_index);
+
+                            return Ok(Some(codec.line_event(&codec_buffer)?));
+                        }
+
+                        buffer.advance(*byte_index + 2);
+                        return Ok(Some(TelnetEvent::GoAhead));
+                    }
+                    Command::SE => {
+                        buffer.advance(*byte_index + 2);
+                        return Ok(Some(decode_stray_se(codec)));
+                    }
+                    // Every other command is two bytes long, so consume both
+                    // to stay aligned with the stream.
+                    command => {
+                        buffer.advance(*byte_index + 2);
+                        return Ok(Some(TelnetEvent::UnknownCommand(command.into())));
+                    }
+                }
+            }
+            // The LF of a CRLF, or the NUL of a CR NUL, after a CR that
+            // already ended the line.
+            b'\n' | 0 if after_cr => {}
+            // A bare CR ends the line, without waiting to see what follows.
+            b'\r' if codec.line_ending == LineEnding::Cr => {
+                let codec_buffer = codec.take_buffer();
+                codec.after_cr = true;
+                buffer.advance(*byte_index + 1);
+
+                return Ok(Some(codec.line_event(&codec_buffer)?));
+            }
+            b'\n' => {
+                if codec.buffer.ends_with(b"\r") {
+                    // Strip every CR before the LF, so that a doubled CR from
+                    // a misbehaving client does not leak into the message.
+                    let mut codec_buffer = codec.take_buffer();
+                    while codec_buffer.last() == Some(&b'\r') {
+                        codec_buffer.pop();
+                    }
+                    buffer.advance(*byte_index + 1);
+
+                    return Ok(Some(codec.line_event(&codec_buffer)?));
+                }
+
+                decode_next_byte(codec, &mut codec_buffer_size, buffer[*byte_index]);
+            }
+            // The `\n` of a reversed `\n\r` terminator is buffered as data
+            // until the `\r` confirms the end of the line.
+            b'\r'
+                if codec.line_ending == LineEnding::LenientCrlf
+                    && codec.buffer.ends_with(b"\n") =>
+            {
+                let mut codec_buffer = codec.take_buffer();
+                codec_buffer.pop();
+                buffer.advance(*byte_index + 1);
+
+                return Ok(Some(codec.line_event(&codec_buffer)?));
+            }
+            #[cfg(not(feature = "unicode"))]
+            c if !codec.message_mode => {
+                // The buffer is cleared rather than taken, so that character
+                // mode does not allocate for every keystroke.
+                codec.buffer.clear();
+                buffer.advance(*byte_index + 1);
+                return Ok(Some(TelnetEvent::Character(c)));
+            }
+
+            #[cfg(feature = "unicode")]
+            c if !codec.message_mode => {
+                // Unicode support is compiled in but not enabled,
+                // so just pass characters on as they are
+
+                if !codec.unicode {
+                    codec.buffer.clear();
+                    buffer.advance(*byte_index + 1);
+                    return Ok(Some(TelnetEvent::Character(c)));
+                }
+
+                return Ok(decode_utf8(*byte_index, buffer, c));
+            }
+            // A NUL after a bare CR only marks the CR as such in the NVT.
+            0 if !codec.binary_remote && codec.buffer.ends_with(b"\r") => {}
+            // A long line is returned in chunks as it arrives. A CR may be the
+            // start of the line ending, so it is buffered first.
+            c if c != b'\r'
+                && codec.chunk_size.is_some_and(|size| codec.buffer.len() >= size.max(1)) =>
+            {
+                buffer.advance(*byte_index);
+                return Ok(Some(codec.take_chunk()?));
+            }
+            // Control characters skip the line, leaving the text around them
+            // to be buffered as usual.
+            c if codec.control_chars_as_events && c < 0x20 && c != b'\r' => {
+                buffer.advance(*byte_index + 1);
+                return Ok(Some(TelnetEvent::Character(c)));
+            }
+            _ => decode_next_byte(codec, &mut codec_buffer_size, buffer[*byte_index]),
+        };
+
+        *byte_index += 1;
+    }
+}
+
+fn encode_negotiate(opt: u8, subopt: TelnetOption, buf: &mut BytesMut) {
+    buf.reserve(3);
+    buf.put_u8(IAC);
+
+    match opt {
+        DO => buf.put_u8(DO),
+        DONT => buf.put_u8(DONT),
+        WILL => buf.put_u8(WILL),
+        WONT => buf.put_u8(WONT),
+        _ => unreachable!(),
+    }
+
+    let option: u8 = subopt.into();
+    buf.put_u8(option);
+
+    if option == IAC {
+        buf.put_u8(IAC);
+    }
+}
+
+fn encode_sb(sb: &SubnegotiationType, buffer: &mut BytesMut) {
+    let start = buffer.len();
+    encode_sb_frame(sb, buffer);
+
+    // `len` sizes buffers and counts bytes without encoding, so it must never
+    // overstate the frame. Escaping can only make the frame longer.
+    debug_assert!(
+        buffer.len() - start >= sb.len().saturating_add(5),
+        "SubnegotiationType::len overstates the encoded length of {sb:?}"
+    );
+}
+
+fn encode_sb_frame(sb: &SubnegotiationType, buffer: &mut BytesMut) {
+    match sb {
+        SubnegotiationType::WindowSize(width, height) => {
+            // A dimension such as 255 contains an IAC byte, which must be
+            // escaped like any other subnegotiation data.
+            let [width_high, width_low] = width.to_be_bytes();
+            let [height_high, height_low] = height.to_be_bytes();
+            encode_sb_bytes(NAWS, &[width_high, width_low, height_high, height_low], buffer);
+        }
+        SubnegotiationType::CharsetRequest(ttable, charsets) => {
+            let charset_lens = charsets.iter().map(|c| c.len()).sum::<usize>();
+            let spaces = charsets.len().saturating_sub(1);
+
+            buffer.reserve(16 + charset_lens + spaces);
+            let sep = b' ';
+            buffer.extend([IAC, SB, CHARSET, CHARSET_REQUEST]);
+
+            if let Some(version) = ttable {
+                buffer.extend(CHARSET_TTABLE);
+                buffer.put_u8(*version);
+
+                // A version of 255 is data, so it must be escaped.
+                if *version == IAC {
+                    buffer.put_u8(IAC);
+                }
+            }
+
+            buffer.put_u8(sep);
+
+            for (i, charset) in charsets.iter().enumerate() {
+                buffer.extend(charset);
+                if i < charsets.len() - 1 {
+                    buffer.put_u8(sep);
+                }
+            }
+
+            buffer.extend([IAC, SE]);
+        }
+        SubnegotiationType::CharsetAccepted(charset) => {
+            buffer.reserve(6 + charset.len());
+            buffer.extend([IAC, SB, CHARSET, CHARSET_ACCEPTED]);
+            buffer.extend(charset);
+            buffer.extend([IAC, SE]);
+        }
+        SubnegotiationType::CharsetRejected => {
+            buffer.reserve(6);
+            buffer.extend([IAC, SB, CHARSET, CHARSET_REJECTED, IAC, SE]);
+        }
+        SubnegotiationType::CharsetTTableIs(version, table) => {
+            let mut bytes = Vec::with_capacity(2 + table.len());
+            bytes.extend([CHARSET_TTABLE_IS, *version]);
+            bytes.extend_from_slice(table);
+
+            encode_sb_bytes(CHARSET, &bytes, buffer);
+        }
+        SubnegotiationType::CharsetTTableRejected => {
+            buffer.reserve(6);
+            buffer.extend([IAC, SB, CHARSET, CHARSET_TTABLE_REJECTED, IAC, SE]);
+        }
+        SubnegotiationType::CharsetTTableAck => {
+            buffer.reserve(6);
+            buffer.extend([IAC, SB, CHARSET, CHARSET_TTABLE_ACK, IAC, SE]);
+        }
+        SubnegotiationType::CharsetTTableNak => {
+            buffer.reserve(6);
+            buffer.extend([IAC, SB, CHARSET, CHARSET_TTABLE_NAK, IAC, SE]);
+        }
+        SubnegotiationType::Environment(op) => {
+            buffer.extend([IAC, SB, ENVIRON]);
+            encode_env_op(op.clone(), buffer);
+            buffer.extend([IAC, SE]);
+        }
+        SubnegotiationType::OldEnvironment(op) => {
+            buffer.extend([IAC, SB, OLD_ENVIRON]);
+            encode_old_env_op(op.clone(), buffer);
+            buffer.extend([IAC, SE]);
+        }
+        SubnegotiationType::TerminalSpeed(TerminalSpeedOption::Is(transmit, receive)) => {
+            let speeds = format!("{transmit},{receive}");
+            buffer.reserve(6 + speeds.len());
+            buffer.extend([IAC, SB, TSPEED, IS]);
+            buffer.extend(speeds.as_bytes());
+            buffer.extend([IAC, SE]);
+        }
+        SubnegotiationType::TerminalSpeed(TerminalSpeedOption::Send) => {
+            buffer.reserve(6);
+            buffer.extend([IAC, SB, TSPEED, SEND, IAC, SE]);
+        }
+        SubnegotiationType::TerminalType(TerminalTypeOption::Is(name)) => {
+            // The name is escaped like any other subnegotiation data.
+            let mut bytes = Vec::with_capacity(1 + name.len());
+            bytes.push(IS);
+            bytes.extend_from_slice(name.as_bytes());
+
+            encode_sb_bytes(TTYPE, &bytes, buffer);
+        }
+        SubnegotiationType::TerminalType(TerminalTypeOption::Send) => {
+            buffer.reserve(6);
+            buffer.extend([IAC, SB, TTYPE, SEND, IAC, SE]);
+        }
+        SubnegotiationType::XDisplayLocation(XDisplayLocationOption::Is(location)) => {
+            // The location is escaped like any other subnegotiation data.
+            let mut bytes = Vec::with_capacity(1 + location.len());
+            bytes.push(IS);
+            bytes.extend_from_slice(location.as_bytes());
+
+            encode_sb_bytes(XDISPLOC, &bytes, buffer);
+        }
+        SubnegotiationType::XDisplayLocation(XDisplayLocationOption::Send) => {
+            buffer.reserve(6);
+            buffer.extend([IAC, SB, XDISPLOC, SEND, IAC, SE]);
+        }
+        SubnegotiationType::Authentication(bytes) => {
+            encode_sb_bytes(AUTHENTICATION, bytes, buffer);
+        }
+        SubnegotiationType::Encrypt(bytes) => encode_sb_bytes(ENCRYPT, bytes, buffer),
+        SubnegotiationType::Msdp(vars) => {
+            buffer.reserve(sb.len().saturating_add(5));
+            buffer.extend([IAC, SB, MSDP]);
+            encode_msdp(vars, buffer);
+            buffer.extend([IAC, SE]);
+        }
+        SubnegotiationType::Unknown(option, bytes) => {
+            encode_sb_bytes((*option).into(), bytes, buffer)
+        }
+        SubnegotiationType::LineMode(mode) => match mode {
+            LineModeOption::Mode(mode) => {
+                buffer.reserve(7);
+                buffer.extend([IAC, SB, LINEMODE, MODE, (*mode).into(), IAC, SE]);
+            }
+            LineModeOption::ModeAck(mode) => {
+                let mode = *mode | LineMode::MODE_ACK;
+                buffer.reserve(7);
+                buffer.extend([IAC, SB, LINEMODE, MODE, mode.into(), IAC, SE]);
+            }
+            LineModeOption::SLC(values) => {
+                // 4: Subnegotiation begin values.len() * 3: each entry
+                // symbolizes a triple of bytes:
+                // - Function
+                // - Modifiers (acknowledgement, urgency etc.)
+                // - Character 2: Subnegotiation end
+
+                buffer.reserve(6 + values.len() * 3);
+                buffer.extend([IAC, SB, LINEMODE, LINEMODE_SLC]);
+
+                for &(dispatch, char) in values {
+                    let (first, second) = dispatch.into();
+                    buffer.extend([first, second, char as u8]);
+                }
+
+                buffer.extend([IAC, SE]);
+            }
+            LineModeOption::ForwardMask(ForwardMaskOption::Do(data)) => {
+                // Without a codec, the length of the mask decides whether it
+                // is sent in the binary form.
+                encode_forward_mask(data, forward_mask_length(data.len() > 16), buffer);
+            }
+            LineModeOption::ForwardMask(option) => {
+                buffer.reserve(7);
+                buffer.extend([
+                    IAC,
+                    SB,
+                    LINEMODE,
+                    option.clone().into(),
+                    LINEMODE_FORWARD_MASK,
+                    IAC,
+                    SE,
+                ]);
+            }
+            LineModeOption::Unknown(option, data) => {
+                buffer.reserve(7 + data.len());
+                buffer.extend([IAC, SB, LINEMODE, *option]);
+                buffer.extend(data);
+                buffer.extend([IAC, SE]);
+            }
+        },
+    }
+}
+
+/// Returns the length of a LINEMODE forward mask, which is 32 bytes when
+/// BINARY is in effect for the data it covers and 16 bytes otherwise.
+fn forward_mask_length(binary: bool) -> usize {
+    if binary {
+        32
+    } else {
+        16
+    }
+}
+
+/// Encodes a LINEMODE DO FORWARDMASK subnegotiation, with the mask padded or
+/// truncated to `length` bytes.
+fn encode_forward_mask(mask: &[u8], length: usize, buffer: &mut BytesMut) {
+    let mut payload = vec![DO, LINEMODE_FORWARD_MASK];
+    payload.extend(mask.iter().copied().take(length));
+    payload.resize(2 + length, 0);
+
+    encode_sb_bytes(LINEMODE, &payload, buffer);
+}
+
+/// Encodes a subnegotiation with an opaque payload, doubling any IAC bytes.
+fn encode_sb_bytes(option: u8, bytes: &[u8], buffer: &mut BytesMut) {
+    let mut bytes_buffer_size = bytes.len() + 5;
+
+    for byte in bytes {
+        if *byte == IAC {
+            bytes_buffer_size += 1;
+        }
+    }
+
+    buffer.reserve(bytes_buffer_size);
+
+    // IAC SUB OPTION, with an option of 255 doubled like the data
+    buffer.extend([IAC, SB, option]);
+    if option == IAC {
+        buffer.put_u8(IAC);
+    }
+
+    // Write to the buffer
+    for byte in bytes {
+        if *byte == IAC {
+            buffer.extend([IAC, IAC]);
+        } else {
+            buffer.put_u8(*byte);
+        }
+    }
+
+    // IAC SUBNEGOTIATION END
+    buffer.extend([IAC, SE]);
+}
+
+/// Returns true if the byte at `index` is a CR that is not followed by LF.
+/// Outside of binary mode, this is sent as CR NUL.
+fn is_bare_cr(bytes: &[u8], index: usize) -> bool {
+    bytes[index] == b'\r' && bytes.get(index + 1) != Some(&b'\n')
+}
+
+/// Returns the encoded length of message bytes, including doubled IAC bytes
+/// and the NUL sent after a bare CR outside of binary mode.
+fn escaped_len(bytes: &[u8], escape_iac: bool, binary: bool) -> usize {
+    if !escape_iac {
+        return bytes.len();
+    }
+
+    let iacs = bytes.iter().filter(|&&byte| byte == IAC).count();
+    let bare_crs =
+        if binary { 0 } else { (0..bytes.len()).filter(|&index| is_bare_cr(bytes, index)).count() };
+
+    bytes.len() + iacs + bare_crs
+}
+
+fn encode_raw_message(bytes: Bytes, escape_iac: bool, binary: bool, buffer: &mut BytesMut) {
+    if !escape_iac {
+        buffer.extend(bytes);
+        return;
+    }
+
+    buffer.reserve(escaped_len(&bytes, escape_iac, binary));
+
+    for (index, byte) in bytes.iter().enumerate() {
+        if *byte == IAC {
+            buffer.extend([IAC, IAC]);
+        } else if !binary && is_bare_cr(&bytes, index) {
+            buffer.extend([b'\r', 0]);
+        } else {
+            buffer.put_u8(*byte);
+        }
+    }
+}
+
+fn encode_message(bytes: Bytes, escape_iac: bool, binary: bool, buffer: &mut BytesMut) {
+    // Check the message rather than the buffer, which may already end with
+    // the line ending of a previous message.
+    let terminated = bytes.ends_with(b"\r\n");
+    encode_raw_message(bytes, escape_iac, binary, buffer);
+
+    if !terminated {
+        buffer.reserve(2);
+        buffer.extend([b'\r', b'\n']);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (TelnetCodec, BytesMut) {
+        let codec = TelnetCodec::new(16);
+        let buffer = BytesMut::new();
+        (codec, buffer)
+    }
+
+    mod test_decode {
+        use super::*;
+        use crate::constants::{AO, MXP, SGA};
+
+        #[test]
+        fn test_sga_true() {
+            let (mut codec, mut buffer) = setup();
+            codec.sga = true;
+
+            // when both the codec's internal buffer, and the input buffer are
+            // empty, there's nothing going on.
+            assert!(codec.decode(&mut buffer).unwrap().is_none());
+
+            // when the codec's internal buffer is not empty, clear it out and
+            // send it as a message
+            codec.buffer.extend([b'h', b'i', b'y', b'a', b' ', 0xf0, 0x9f, 0x98, 0x81]);
+            assert_eq!(
+                codec.decode(&mut buffer).unwrap().unwrap(),
+                TelnetEvent::Message("hiya 😁".to_string())
+            );
+            assert!(codec.buffer.is_empty());
+
+            // when the codec's internal buffer is empty, and the input buffer
+            // has data, decode as a SuppressGoAhead
+            buffer.extend([IAC]);
+            assert!(codec.decode(&mut buffer).unwrap().is_none());
+            assert!(codec.buffer.is_empty());
+            assert_eq!(buffer.as_ref(), &[IAC]);
+            buffer.extend([IAC]); // Add a second, as two are interpreted as a single IAC
+            assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Character(IAC));
+            assert!(codec.buffer.is_empty());
+            assert!(buffer.is_empty());
+
+            // Wait for the option byte of a partial negotiation
+            buffer.extend([IAC, WILL]);
+            assert!(codec.decode(&mut buffer).unwrap().is_none());
+            assert!(codec.buffer.is_empty());
+            assert!(buffer.is_empty());
+            buffer.extend([SGA]);
+            assert_eq!(
+                codec.decode(&mut buffer).unwrap().unwrap(),
+                TelnetEvent::Will(TelnetOption::SuppressGoAhead)
+            );
+            assert!(buffer.is_empty());
+
+            // Two-byte commands are consumed
+            buffer.extend([IAC, GA, IAC, NOP]);
+            assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::GoAhead);
+            assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Nop);
+            assert!(buffer.is_empty());
+
+            // Unsupported two-byte commands are surfaced
+            buffer.extend([IAC, AO, IAC, NOP]);
+            assert_eq!(
+                codec.decode(&mut buffer).unwrap().unwrap(),
+                TelnetEvent::UnknownCommand(AO)
+            );
+            assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Nop);
+            assert!(buffer.is_empty());
+
+            // Erase Character and Erase Line have their own events
+            buffer.extend([IAC, EC, IAC, EL]);
+            assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::EraseCharacter);
+            assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::EraseLine);
+            assert!(buffer.is_empty());
+
+            // Subnegotiations are decoded as usual
+            buffer.extend([IAC, SB, NAWS, 0x00, 0x50, 0x00, 0x50, IAC, SE]);
+            assert_eq!(
+                codec.decode(&mut buffer).unwrap().unwrap(),
+                TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(80, 80))
+            );
+            assert!(buffer.is_empty());
+
+            // Data is decoded a byte at a time, up to a partial command
+            buffer.extend([b'h', b'i', IAC]);
+            assert_eq!(
+                codec.decode_all(&mut buffer).unwrap(),
+                vec![TelnetEvent::Character(b'h'), TelnetEvent::Character(b'i')]
+            );
+            assert!(codec.buffer.is_empty());
+            assert_eq!(buffer.as_ref(), &[IAC]);
+        }
+
+        mod test_sga_false {
+            use super::*;
+
+            #[test]
+            fn test_buffer_starts_with_newline() {
+                let (mut codec, mut buffer) = setup();
+
+                codec.buffer.extend([b'c', b'o', b'o', b'l', b'!', b'\r']);
+                buffer.extend([b'\n', b'y', b'e', b's']);
+
+                // when the newline completes a \r\n sequence, send the contents
+                // of the codec's internal buffer as a message
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("cool!".to_string())
+                );
+                assert!(codec.buffer.is_empty());
+                assert_eq!(buffer.as_ref(), b"yes");
+
+                // When the character does not complete a \r\n sequence, and is
+                // not IAC, move it from the input buffer to the codec's
+                // internal buffer.
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                assert_eq!(&codec.buffer, b"yes");
+                assert!(buffer.is_empty());
+            }
+
+            #[test]
+            fn test_mid_line_go_ahead() {
+                let (mut codec, mut buffer) = setup();
+
+                buffer.extend(b"hel");
+                buffer.extend([IAC, GA]);
+                buffer.extend(b"lo\r\n");
+
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![
+                        TelnetEvent::Message("hel".to_string()),
+                        TelnetEvent::GoAhead,
+                        TelnetEvent::Message("lo".to_string()),
+                    ]
+                );
+                assert!(buffer.is_empty());
+            }
+
+            #[test]
+            fn test_go_ahead_own_read() {
+                let (mut codec, mut buffer) = setup();
+
+                buffer.extend(b"login: ");
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                assert_eq!(codec.pending(), b"login: ");
+
+                // The GA arrives alone, and both of its bytes are consumed.
+                buffer.extend([IAC, GA]);
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![TelnetEvent::Message("login: ".to_string()), TelnetEvent::GoAhead]
+                );
+                assert!(buffer.is_empty());
+                assert!(codec.pending().is_empty());
+
+                // No stray GA byte leaks into the next line.
+                buffer.extend(b"bob\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap(),
+                    Some(TelnetEvent::Message("bob".to_string()))
+                );
+            }
+
+            #[test]
+            fn test_pending_prompt() {
+                let (mut codec, mut buffer) = setup();
+                assert!(!codec.has_pending_prompt());
+
+                buffer.extend(b"login: ");
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                assert!(codec.has_pending_prompt());
+                assert_eq!(codec.pending(), b"login: ");
+                assert!(buffer.is_empty());
+
+                assert_eq!(
+                    codec.flush_prompt().unwrap(),
+                    Some(TelnetEvent::Prompt("login: ".to_string()))
+                );
+                assert!(!codec.has_pending_prompt());
+                assert_eq!(codec.flush_prompt().unwrap(), None);
+
+                // The prompt is sent back as text followed by a Go Ahead
+                codec.encode(TelnetEvent::Prompt("> ".to_string()), &mut buffer).unwrap();
+                assert_eq!(buffer.as_ref(), b"> \xFF\xF9");
+            }
+
+            #[test]
+            fn test_line_split_across_reads() {
+                let (mut codec, mut buffer) = setup();
+                codec.max_buffer_length = 1024;
+
+                // An unterminated line is buffered once, however many reads
+                // it takes to complete.
+                let art = b" /\\_/\\  ( o.o )  > ^ <  ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~";
+                buffer.extend(art);
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                assert!(buffer.is_empty());
+
+                buffer.extend(b" \x1b[H\r\n");
+                let mut expected = String::from_utf8(art.to_vec()).unwrap();
+                expected.push_str(" \x1b[H");
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![TelnetEvent::Message(expected)]
+                );
+                assert!(buffer.is_empty());
+
+                // A partial command keeps only its own bytes in the input
+                buffer.extend(b"ab");
+                buffer.extend([IAC]);
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                assert_eq!(buffer.as_ref(), &[IAC]);
+
+                buffer.extend([NOP]);
+                buffer.extend(b"c\r\n");
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![TelnetEvent::Nop, TelnetEvent::Message("abc".to_string())]
+                );
+            }
+
+            #[test]
+            fn test_buffer_capacity() {
+                let mut codec = TelnetCodec::with_buffer_capacity(1024, 128);
+                let mut buffer = BytesMut::new();
+                assert!(codec.buffer.capacity() >= 128);
+
+                buffer.extend(b"hello\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap(),
+                    Some(TelnetEvent::Message("hello".to_string()))
+                );
+                assert!(codec.buffer.capacity() >= 128);
+
+                // The buffer is reused rather than reallocated for each line
+                let pointer = codec.buffer.as_ptr();
+                buffer.extend(b"world\r\n");
+                codec.decode(&mut buffer).unwrap();
+                assert_eq!(codec.buffer.as_ptr(), pointer);
+
+                let codec = TelnetCodec::builder(1024).initial_buffer_capacity(256).build();
+                assert!(codec.buffer.capacity() >= 256);
+                assert_eq!(codec.max_buffer_length, 1024);
+            }
+
+            #[test]
+            fn test_repeated_cr() {
+                let (mut codec, mut buffer) = setup();
+
+                buffer.extend(b"abc\r\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap(),
+                    Some(TelnetEvent::Message("abc".to_string()))
+                );
+
+                // The same, with the first CR at the end of an earlier read
+                buffer.extend(b"abc\r");
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                buffer.extend(b"\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap(),
+                    Some(TelnetEvent::Message("abc".to_string()))
+                );
+            }
+
+            #[test]
+            fn test_multiple_lines() {
+                let (mut codec, mut buffer) = setup();
+
+                // Each line is returned on its own, and the input buffer is
+                // advanced past exactly one line at a time.
+                buffer.extend(b"line1\r\nline2\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("line1".to_string())
+                );
+                assert!(codec.buffer.is_empty());
+                assert_eq!(buffer.as_ref(), b"line2\r\n");
+
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("line2".to_string())
+                );
+                assert!(codec.buffer.is_empty());
+                assert!(buffer.is_empty());
+
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+            }
+
+            #[test]
+            fn test_lenient_crlf() {
+                let (mut codec, mut buffer) = setup();
+
+                // A reversed terminator is only data by default...
+                buffer.extend(b"line1\n\r");
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+
+                // ...but ends the line in lenient mode, as does CRLF
+                let (mut codec, mut buffer) = setup();
+                codec.line_ending = LineEnding::LenientCrlf;
+
+                buffer.extend(b"line1\n\rline2\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("line1".to_string())
+                );
+                assert_eq!(buffer.as_ref(), b"line2\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("line2".to_string())
+                );
+                assert!(buffer.is_empty());
+            }
+
+            #[test]
+            fn test_cr_line_ending() {
+                let (mut codec, mut buffer) = setup();
+                codec.line_ending = LineEnding::Cr;
+
+                // Each line is returned as soon as its CR arrives
+                buffer.extend(b"line1\rline2\r");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap(),
+                    Some(TelnetEvent::Message("line1".to_string()))
+                );
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap(),
+                    Some(TelnetEvent::Message("line2".to_string()))
+                );
+                assert!(buffer.is_empty());
+
+                // An LF after the CR is dropped, even in the next read, but
+                // one that follows anything else is data
+                buffer.extend(b"\nline3\r\r\n");
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![
+                        TelnetEvent::Message("line3".to_string()),
+                        TelnetEvent::Message(String::new()),
+                    ]
+                );
+
+                buffer.extend(b"a\nb\r\x00");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap(),
+                    Some(TelnetEvent::Message("a\nb".to_string()))
+                );
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                assert!(buffer.is_empty());
+                assert!(codec.pending().is_empty());
+            }
+
+            #[test]
+            fn test_cr_nul() {
+                let (mut codec, mut buffer) = setup();
+
+                // The NUL after a bare CR is dropped...
+                buffer.extend(b"a\r\0b\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("a\rb".to_string())
+                );
+
+                // ...unless BINARY is in effect for received data
+                codec.binary_remote = true;
+                buffer.extend(b"a\r\0b\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("a\r\0b".to_string())
+                );
+            }
+
+            #[test]
+            fn test_coalesce_messages() {
+                let (mut codec, mut buffer) = setup();
+                codec.coalesce_messages = true;
+
+                // Consecutive lines are merged, and the event that ends the
+                // run is returned on the next call.
+                buffer.extend(b"line1\r\nline2\r\n");
+                buffer.extend([IAC, NOP]);
+                buffer.extend(b"line3\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("line1\nline2".to_string())
+                );
+                assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Nop);
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("line3".to_string())
+                );
+                assert!(buffer.is_empty());
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+            }
+
+            #[test]
+            fn test_control_chars_as_events() {
+                let (mut codec, mut buffer) = setup();
+                codec.control_chars_as_events = true;
+
+                // Ctrl-C in the middle of a line, then a tab
+                buffer.extend(b"ab\x03c\td\r\n");
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![
+                        TelnetEvent::Character(0x03),
+                        TelnetEvent::Character(b'\t'),
+                        TelnetEvent::Message("abcd".to_string()),
+                    ]
+                );
+                assert!(buffer.is_empty());
+
+                // The NUL after a bare CR is still dropped
+                buffer.extend(b"a\r\x00b\r\n");
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![TelnetEvent::Message("a\rb".to_string())]
+                );
+            }
+
+            #[test]
+            fn test_chunk_size() {
+                let (mut codec, mut buffer) = setup();
+                codec.chunk_size = Some(4);
+
+                buffer.extend(b"abcdefghij\r\nabc\r\n");
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![
+                        TelnetEvent::MessageChunk { data: "abcd".to_string(), last: false },
+                        TelnetEvent::MessageChunk { data: "efgh".to_string(), last: false },
+                        TelnetEvent::MessageChunk { data: "ij".to_string(), last: true },
+                        TelnetEvent::Message("abc".to_string()),
+                    ]
+                );
+
+                // A character split by the chunk boundary starts the next chunk
+                buffer.extend("abcé\r\n".as_bytes());
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![
+                        TelnetEvent::MessageChunk { data: "abc".to_string(), last: false },
+                        TelnetEvent::MessageChunk { data: "é".to_string(), last: true },
+                    ]
+                );
+            }
+
+            #[test]
+            fn test_peek() {
+                let (mut codec, mut buffer) = setup();
+
+                buffer.extend(b"hi");
+                assert_eq!(codec.peek(&buffer), None);
+
+                // A command in the middle of a line comes out first
+                buffer.extend([IAC, IAC, IAC, WILL, NAWS]);
+                assert_eq!(codec.peek(&buffer), Some(WILL));
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap(),
+                    Some(TelnetEvent::Will(TelnetOption::NAWS))
+                );
+
+                buffer.extend(b"\r\n");
+                assert_eq!(codec.peek(&buffer), Some(0x00));
+                assert!(codec.decode(&mut buffer).unwrap().unwrap().is_data());
+
+                // A negotiation split across reads
+                buffer.extend([IAC, DO]);
+                assert_eq!(codec.peek(&buffer), Some(DO));
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                assert_eq!(codec.peek(&buffer), Some(DO));
+                assert!(buffer.is_empty());
+            }
+
+            #[test]
+            fn test_collapse_blank_lines() {
+                let (mut codec, mut buffer) = setup();
+                codec.collapse_blank_lines = true;
+
+                buffer.extend(b"a\r\n\r\n\r\n\r\nb\r\n\r\n");
+                buffer.extend([IAC, NOP]);
+                buffer.extend(b"\r\n");
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![
+                        TelnetEvent::Message("a".to_string()),
+                        TelnetEvent::Message(String::new()),
+                        TelnetEvent::Message("b".to_string()),
+                        TelnetEvent::Message(String::new()),
+                        TelnetEvent::Nop,
+                        TelnetEvent::Message(String::new()),
+                    ]
+                );
+
+                // A run split across reads is still collapsed.
+                buffer.extend(b"\r\n");
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                assert!(buffer.is_empty());
+            }
+
+            #[test]
+            fn test_process_iac_disabled() {
+                let (mut codec, mut buffer) = setup();
+                codec.process_iac = false;
+
+                buffer.extend([b'a', IAC, WILL, b'b', b'\r', b'\n']);
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message(String::from_utf8_lossy(&[b'a', IAC, WILL, b'b']).into())
+                );
+                assert!(buffer.is_empty());
+
+                codec.message_mode = false;
+                buffer.extend([IAC]);
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Character(IAC)
+                );
+            }
+
+            #[test]
+            #[cfg(feature = "charset")]
+            fn test_charset() {
+                let (mut codec, mut buffer) = setup();
+                codec.set_charset("LATIN-1").unwrap();
+
+                buffer.extend(b"caf\xE9\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("café".to_string())
+                );
+
+                assert!(codec.set_charset("NOT-A-CHARSET").is_err());
+            }
+
+            #[test]
+            #[cfg(feature = "charset")]
+            fn test_accept_charset() {
+                let (mut codec, mut buffer) = setup();
+                let request = SubnegotiationType::CharsetRequest(
+                    None,
+                    vec![Bytes::from_static(b"utf-8"), Bytes::from_static(b"latin1")],
+                );
+
+                assert_eq!(
+                    codec.accept_charset(&request, &[Charset::Latin1, Charset::Utf8]),
+                    TelnetEvent::Subnegotiate(SubnegotiationType::CharsetAccepted(
+                        Bytes::from_static(b"latin1")
+                    ))
+                );
+
+                buffer.extend(b"caf\xE9\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("café".to_string())
+                );
+
+                assert_eq!(
+                    codec.accept_charset(&request, &[Charset::UsAscii]),
+                    TelnetEvent::Subnegotiate(SubnegotiationType::CharsetRejected)
+                );
+            }
+
+            #[test]
+            fn test_invalid_utf8() {
+                for (policy, expected) in [
+                    (InvalidUtf8Policy::Lossy, "caf\u{FFFD}!"),
+                    (InvalidUtf8Policy::Drop, "caf!"),
+                    (InvalidUtf8Policy::Latin1, "café!"),
+                ] {
+                    let (mut codec, mut buffer) = setup();
+                    codec.invalid_utf8 = policy;
+
+                    buffer.extend(b"caf\xE9!\r\n");
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Message(expected.to_string())
+                    );
+                }
+
+                let (mut codec, mut buffer) = setup();
+                codec.invalid_utf8 = InvalidUtf8Policy::Error;
+
+                buffer.extend(b"caf\xE9!\r\n");
+                assert!(matches!(
+                    codec.decode(&mut buffer),
+                    Err(TelnetError { kind: crate::error::TelnetErrorType::Codec, .. })
+                ));
+
+                // Valid messages are unaffected
+                buffer.extend(b"cafe\r\n");
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("cafe".to_string())
+                );
+            }
+
+            #[test]
+            fn test_overflow() {
+                let (mut codec, mut buffer) = setup();
+
+                buffer.extend([b'a'; 10]);
+                buffer.extend([b'z'; 10]);
+
+                assert!(codec.decode(&mut buffer).unwrap().is_none());
+
+                assert_eq!(&codec.buffer[..=9], &[b'a'; 10]);
+                assert_eq!(&codec.buffer[10..], &[b'z'; 6]);
+                assert!(buffer.is_empty());
+            }
+
+            mod test_iac {
+                use super::*;
+                use crate::{
+                    constants::{
+                        AYT, BINARY, ECHO, MSDP_TABLE_CLOSE, MSDP_TABLE_OPEN, MSDP_VAL, MSDP_VAR,
+                    },
+                    msdp::MsdpValue,
+                };
+
+                #[test]
+                fn test_double_iac() {
+                    let (mut codec, mut buffer) = setup();
+
+                    // a doubled IAC on the wire is interpreted as a single byte
+                    // of data
+                    buffer.extend([IAC, IAC]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert_eq!(&codec.buffer, &[IAC]);
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_double_iac_in_line() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.invalid_utf8 = InvalidUtf8Policy::Latin1;
+
+                    // The pair is a single data byte, wherever it falls in
+                    // the line
+                    buffer.extend([b'a', IAC, IAC, b'b', b'\r', b'\n']);
+                    buffer.extend([b'a', IAC, IAC, b'\r', b'\n']);
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![
+                            TelnetEvent::Message("a\u{ff}b".to_string()),
+                            TelnetEvent::Message("a\u{ff}".to_string()),
+                        ]
+                    );
+                    assert!(codec.buffer.is_empty());
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_decode_with_consumed() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend(b"he");
+                    assert_eq!(codec.decode_with_consumed(&mut buffer).unwrap(), None);
+
+                    buffer.extend(b"llo");
+                    buffer.extend([IAC, IAC, b'\r', b'\n', IAC, WILL, IAC, IAC, IAC, NOP]);
+                    assert_eq!(
+                        codec.decode_with_consumed(&mut buffer).unwrap(),
+                        Some((TelnetEvent::Message("hello\u{FFFD}".to_string()), 9))
+                    );
+                    assert_eq!(
+                        codec.decode_with_consumed(&mut buffer).unwrap(),
+                        Some((TelnetEvent::Will(TelnetOption::Unknown(IAC)), 4))
+                    );
+                    assert_eq!(
+                        codec.decode_with_consumed(&mut buffer).unwrap(),
+                        Some((TelnetEvent::Nop, 2))
+                    );
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_do() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, DO, ECHO]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Do(TelnetOption::Echo)
+                    );
+                    assert!(codec.buffer.is_empty());
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_dont() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, DONT, ECHO]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Dont(TelnetOption::Echo)
+                    );
+                    assert!(codec.buffer.is_empty());
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_will() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, WILL, ECHO]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Will(TelnetOption::Echo)
+                    );
+                    assert!(codec.buffer.is_empty());
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_wont() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, WONT, ECHO]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Wont(TelnetOption::Echo)
+                    );
+                    assert!(codec.buffer.is_empty());
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_track_options_sga() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.track_options = true;
+                    codec.sga_follows_negotiation = true;
+
+                    codec
+                        .encode(TelnetEvent::Will(TelnetOption::SuppressGoAhead), &mut buffer)
+                        .unwrap();
+                    assert!(!codec.sga);
+
+                    buffer.clear();
+                    buffer.extend([IAC, DO, SGA]);
+                    codec.decode(&mut buffer).unwrap();
+                    assert!(codec.sga);
+                    assert_eq!(
+                        codec.options.received_do(TelnetOption::SuppressGoAhead),
+                        Some(true)
+                    );
+
+                    // Text after the negotiation is still decoded
+                    buffer.extend(b"ok\r\n");
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        Vec::from(b"ok\r\n".map(TelnetEvent::Character))
+                    );
+                    assert!(buffer.is_empty());
+
+                    buffer.extend([IAC, DONT, SGA]);
+                    codec.decode(&mut buffer).unwrap();
+                    assert!(!codec.sga);
+
+                    // The flag is left alone when tracking is disabled
+                    let (mut codec, mut buffer) = setup();
+                    codec.sga_follows_negotiation = true;
+                    codec
+                        .encode(TelnetEvent::Will(TelnetOption::SuppressGoAhead), &mut buffer)
+                        .unwrap();
+                    buffer.clear();
+                    buffer.extend([IAC, DO, SGA]);
+                    codec.decode(&mut buffer).unwrap();
+                    assert!(!codec.sga);
+
+                    // By default, lines are still decoded as messages
+                    let (mut codec, mut buffer) = setup();
+                    codec.track_options = true;
+                    codec
+                        .encode(TelnetEvent::Will(TelnetOption::SuppressGoAhead), &mut buffer)
+                        .unwrap();
+                    buffer.clear();
+                    buffer.extend([IAC, DO, SGA]);
+                    buffer.extend(b"ok\r\n");
+                    codec.decode(&mut buffer).unwrap();
+                    assert!(codec.options.local_enabled(TelnetOption::SuppressGoAhead));
+                    assert!(!codec.sga);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Message("ok".to_string())
+                    );
+                }
+
+                #[test]
+                fn test_track_options_binary() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.track_options = true;
+
+                    // Each direction is enabled on its own
+                    let mut output = BytesMut::new();
+                    codec.encode(TelnetEvent::Do(TelnetOption::Binary), &mut output).unwrap();
+                    buffer.extend([IAC, WILL, BINARY]);
+                    codec.decode(&mut buffer).unwrap();
+                    assert!(codec.binary_remote);
+                    assert!(!codec.binary_local);
+
+                    codec.encode(TelnetEvent::Will(TelnetOption::Binary), &mut output).unwrap();
+                    buffer.extend([IAC, DO, BINARY]);
+                    codec.decode(&mut buffer).unwrap();
+                    assert!(codec.binary_local);
+
+                    buffer.extend([IAC, WONT, BINARY]);
+                    codec.decode(&mut buffer).unwrap();
+                    assert!(!codec.binary_remote);
+                    assert!(codec.binary_local);
+                }
+
+                #[test]
+                fn test_resize() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.track_options = true;
+
+                    // Nothing is sent until NAWS is agreed
+                    assert_eq!(codec.resize(80, 24), None);
+
+                    let mut output = BytesMut::new();
+                    codec.encode(TelnetEvent::Will(TelnetOption::NAWS), &mut output).unwrap();
+                    buffer.extend([IAC, DO, NAWS]);
+                    codec.decode(&mut buffer).unwrap();
+
+                    assert_eq!(
+                        codec.resize(80, 24),
+                        Some(TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(80, 24)))
+                    );
+                    assert_eq!(codec.resize(80, 24), None);
+                    assert_eq!(
+                        codec.resize(120, 40),
+                        Some(TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(120, 40)))
+                    );
+                }
+
+                #[test]
+                fn test_pending_negotiations() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.track_options = true;
+
+                    let mut output = BytesMut::new();
+                    codec.encode(TelnetEvent::Do(TelnetOption::NAWS), &mut output).unwrap();
+                    codec.encode(TelnetEvent::Will(TelnetOption::GMCP), &mut output).unwrap();
+                    assert_eq!(codec.pending_negotiations().len(), 2);
+                    assert_eq!(
+                        codec.pending_negotiations()[&TelnetOption::NAWS].event,
+                        TelnetEvent::Do(TelnetOption::NAWS)
+                    );
+
+                    // Only an answer in the other direction clears it
+                    buffer.extend([IAC, DO, NAWS]);
+                    codec.decode(&mut buffer).unwrap();
+                    assert_eq!(codec.pending_negotiations().len(), 2);
+
+                    buffer.extend([IAC, WONT, NAWS, IAC, DONT, GMCP]);
+                    codec.decode_all(&mut buffer).unwrap();
+                    assert!(codec.pending_negotiations().is_empty());
+                }
+
+                #[test]
+                fn test_decode_eof_trailing_iac() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend(b"hello\r\n\xFF");
+                    assert_eq!(
+                        codec.decode_eof(&mut buffer).unwrap(),
+                        Some(TelnetEvent::Message("hello".to_string()))
+                    );
+                    assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+                    assert!(buffer.is_empty());
+
+                    // An unterminated final line is still returned
+                    buffer.extend(b"bye\xFF");
+                    assert_eq!(
+                        codec.decode_eof(&mut buffer).unwrap(),
+                        Some(TelnetEvent::Message("bye".to_string()))
+                    );
+                    assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+                    assert!(buffer.is_empty());
+
+                    // A negotiation cut off by the end of the stream is also
+                    // dropped, rather than completed by the next stream.
+                    buffer.extend([IAC, DO]);
+                    assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+                    buffer.extend(b"ok\r\n");
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap(),
+                        Some(TelnetEvent::Message("ok".to_string()))
+                    );
+                }
+
+                #[test]
+                fn test_deny() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.deny(TelnetOption::Encrypt);
+
+                    buffer.extend([IAC, WILL, ENCRYPT, IAC, DO, ENCRYPT, IAC, WONT, ENCRYPT]);
+                    buffer.extend([IAC, WILL, ECHO]);
+
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![
+                            TelnetEvent::Will(TelnetOption::Encrypt),
+                            TelnetEvent::Do(TelnetOption::Encrypt),
+                            TelnetEvent::Wont(TelnetOption::Encrypt),
+                            TelnetEvent::Will(TelnetOption::Echo),
+                        ]
+                    );
+                    assert_eq!(
+                        codec.take_outgoing(),
+                        vec![
+                            TelnetEvent::Dont(TelnetOption::Encrypt),
+                            TelnetEvent::Wont(TelnetOption::Encrypt),
+                        ]
+                    );
+                    assert!(codec.take_outgoing().is_empty());
+                }
+
+                #[test]
+                fn test_parse_negotiation() {
+                    assert_eq!(
+                        parse_negotiation(&[IAC, DO, ECHO]),
+                        Some(TelnetEvent::Do(TelnetOption::Echo))
+                    );
+                    assert_eq!(
+                        parse_negotiation(&[IAC, WONT, GMCP]),
+                        Some(TelnetEvent::Wont(TelnetOption::GMCP))
+                    );
+                    assert_eq!(parse_negotiation(&[IAC, SB, ECHO]), None);
+                    assert_eq!(parse_negotiation(&[b'a', DO, ECHO]), None);
+                    assert_eq!(parse_negotiation(&[IAC, DO, IAC]), None);
+                }
+
+                #[test]
+                fn test_partial_negotiation() {
+                    let (mut codec, mut buffer) = setup();
+
+                    // A lone IAC is left in the input until the command arrives
+                    buffer.extend([IAC]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert_eq!(buffer.as_ref(), &[IAC]);
+
+                    // The command is then kept by the codec until the option
+                    // byte arrives, rather than parsed again.
+                    buffer.extend([DO]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert!(buffer.is_empty());
+                    assert!(codec.buffer.is_empty());
+
+                    buffer.extend([ECHO, IAC, WILL]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Do(TelnetOption::Echo)
+                    );
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert!(buffer.is_empty());
+
+                    buffer.extend([ECHO]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Will(TelnetOption::Echo)
+                    );
+                }
+
+                #[test]
+                fn test_iac_option() {
+                    let (mut codec, mut buffer) = setup();
+
+                    // Wait to see whether the option byte is escaped
+                    buffer.extend([IAC, DO, IAC]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert_eq!(buffer.as_ref(), &[IAC]);
+
+                    buffer.extend([IAC, IAC, WILL, ECHO]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Do(TelnetOption::Unknown(IAC))
+                    );
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Will(TelnetOption::Echo)
+                    );
+                    assert!(buffer.is_empty());
+
+                    // An unescaped option byte is still accepted
+                    buffer.extend([IAC, DO, IAC, b'h']);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Do(TelnetOption::Unknown(IAC))
+                    );
+                    assert_eq!(buffer.as_ref(), b"h");
+                }
+
+                #[test]
+                fn test_command_byte_option() {
+                    let (mut codec, mut buffer) = setup();
+
+                    // An option byte that collides with a command is still
+                    // just an option, and the byte after it starts afresh
+                    buffer.extend([IAC, DO, SB, IAC, WILL, SE, IAC, NOP]);
+                    buffer.extend(b"ok\r\n");
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![
+                            TelnetEvent::Do(TelnetOption::Unknown(SB)),
+                            TelnetEvent::Will(TelnetOption::Unknown(SE)),
+                            TelnetEvent::Nop,
+                            TelnetEvent::Message("ok".to_string()),
+                        ]
+                    );
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_nop() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, NOP]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), Some(TelnetEvent::Nop));
+                    assert!(codec.buffer.is_empty());
+                    assert!(buffer.is_empty());
+                }
 
-            // when the codec's internal buffer is empty, and the input buffer
-            // has data, decode as a SuppressGoAhead
-            buffer.extend([IAC]);
-            assert!(codec.decode(&mut buffer).unwrap().is_none());
-            assert!(codec.buffer.is_empty());
-            assert_eq!(buffer.as_ref(), &[IAC]);
-            buffer.extend([IAC]); // Add a second, as two are interpreted as a single IAC
-            assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Character(IAC));
-            assert!(codec.buffer.is_empty());
-            assert!(buffer.is_empty());
+                #[test]
+                fn test_erase() {
+                    let (mut codec, mut buffer) = setup();
 
-            // Ignore IAC followed by non-IAC
-            buffer.extend([IAC, WILL]);
-            assert!(codec.decode(&mut buffer).unwrap().is_none());
-            assert!(codec.buffer.is_empty());
-            assert_eq!(buffer.as_ref(), &[IAC, WILL]);
+                    buffer.extend([b'a', IAC, EC, b'b', IAC, EL, b'\r', b'\n']);
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![
+                            TelnetEvent::EraseCharacter,
+                            TelnetEvent::EraseLine,
+                            TelnetEvent::Message("ab".to_string())
+                        ]
+                    );
+                    assert!(buffer.is_empty());
+                }
 
-            // Ignore non-IAC followed by IAC
-            buffer.extend([WILL, IAC]);
-            assert!(codec.decode(&mut buffer).unwrap().is_none());
-            assert!(codec.buffer.is_empty());
-            assert_eq!(buffer.as_ref(), &[IAC, WILL, WILL, IAC]); // previous stuff is still there
-        }
+                #[test]
+                fn test_data_mark() {
+                    let (mut codec, mut buffer) = setup();
 
-        mod test_sga_false {
-            use super::*;
+                    // Without SYNCH handling, the line is kept
+                    buffer.extend(b"ab");
+                    buffer.extend([IAC, DM]);
+                    buffer.extend(b"c\r\n");
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![TelnetEvent::DataMark, TelnetEvent::Message("abc".to_string())]
+                    );
 
-            #[test]
-            fn test_buffer_starts_with_newline() {
-                let (mut codec, mut buffer) = setup();
+                    // With FLUSHIN, the input before the mark is discarded
+                    codec.synch = Some(Modifiers {
+                        level: crate::linemode::Level::Value,
+                        ack: false,
+                        flush_in: true,
+                        flush_out: false,
+                    });
+                    buffer.extend(b"ab");
+                    buffer.extend([IAC, DM]);
+                    buffer.extend(b"c\r\n");
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![TelnetEvent::DataMark, TelnetEvent::Message("c".to_string())]
+                    );
+                }
 
-                codec.buffer.extend([b'c', b'o', b'o', b'l', b'!', b'\r']);
-                buffer.extend([b'\n', b'y', b'e', b's']);
+                #[test]
+                fn test_unknown_command() {
+                    let (mut codec, mut buffer) = setup();
 
-                // when the newline completes a \r\n sequence, send the contents
-                // of the codec's internal buffer as a message
-                assert_eq!(
-                    codec.decode(&mut buffer).unwrap().unwrap(),
-                    TelnetEvent::Message("cool!".to_string())
-                );
-                assert!(codec.buffer.is_empty());
-                assert_eq!(buffer.as_ref(), &[b'y', b'e', b's']);
+                    buffer.extend([b'h', IAC, AYT, b'i', IAC, 200, b'\r', b'\n']);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap(),
+                        Some(TelnetEvent::UnknownCommand(AYT))
+                    );
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap(),
+                        Some(TelnetEvent::UnknownCommand(200))
+                    );
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap(),
+                        Some(TelnetEvent::Message("hi".to_string()))
+                    );
+                    assert!(buffer.is_empty());
+                }
 
-                // When the character does not complete a \r\n sequence, and is
-                // not IAC, append it to the codec's internal buffer, but do not
-                // remove it from the input buffer.
-                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
-                assert_eq!(&codec.buffer, &[b'y', b'e', b's']);
-                assert_eq!(buffer.as_ref(), &[b'y', b'e', b's']);
-            }
+                #[test]
+                fn test_stray_se() {
+                    let (mut codec, mut buffer) = setup();
 
-            #[test]
-            fn test_overflow() {
-                let (mut codec, mut buffer) = setup();
+                    buffer.extend([b'h', IAC, SE, b'i', b'\r', b'\n']);
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![TelnetEvent::Nop, TelnetEvent::Message("hi".to_string())]
+                    );
 
-                buffer.extend([b'a'; 10]);
-                buffer.extend([b'z'; 10]);
+                    codec.errors_as_events = true;
+                    buffer.extend([IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap(),
+                        Some(TelnetEvent::ProtocolError(
+                            "received IAC SE outside a subnegotiation".to_string()
+                        ))
+                    );
+                    assert!(buffer.is_empty());
+                }
 
-                assert!(codec.decode(&mut buffer).unwrap().is_none());
+                #[test]
+                fn test_sb_naws() {
+                    let (mut codec, mut buffer) = setup();
 
-                assert_eq!(&codec.buffer[..=9], &[b'a'; 10]);
-                assert_eq!(&codec.buffer[10..], &[b'z'; 6]);
+                    buffer.extend([IAC, SB, NAWS, 0x00, 0x50, 0x00, 0x50, IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(80, 80))
+                    );
+                    assert!(codec.buffer.is_empty());
+                    assert!(buffer.is_empty());
+                }
 
-                assert_eq!(&buffer[..=9], &[b'a'; 10]);
-                assert_eq!(&buffer[10..], &[b'z'; 10]);
-            }
+                #[test]
+                fn test_sb_naws_max_window_size() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.max_window_size = Some(1000);
 
-            mod test_iac {
-                use super::*;
-                use crate::constants::ECHO;
+                    buffer.extend([IAC, SB, NAWS, 0, 0, IAC, IAC, IAC, IAC, IAC, SE]);
+                    buffer.extend([IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE]);
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![
+                            TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(0, 1000)),
+                            TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(80, 24)),
+                        ]
+                    );
+                }
 
                 #[test]
-                fn test_double_iac() {
+                fn test_sb_empty() {
                     let (mut codec, mut buffer) = setup();
 
-                    // a doubled IAC on the wire is interpreted as a single byte
-                    // of data
-                    buffer.extend([IAC, IAC]);
+                    // An empty payload is passed through for options without
+                    // a dedicated decoder
+                    buffer.extend([IAC, SB, MXP, IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                            TelnetOption::MXP,
+                            Bytes::new()
+                        ))
+                    );
+                    assert!(buffer.is_empty());
+
+                    // ...and discarded for options that require one, without
+                    // blocking the input that follows
+                    for option in [NAWS, CHARSET, LINEMODE, ENVIRON, TTYPE, TSPEED, XDISPLOC] {
+                        buffer.extend([IAC, SB, option, IAC, SE, IAC, NOP]);
+                        assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Nop);
+                        assert!(buffer.is_empty());
+                    }
+                }
+
+                #[test]
+                fn test_sb_environ_control_bytes() {
+                    use crate::env::{EnvironmentKind, EnvironmentOperation, WellKnownVariable};
+
+                    let (mut codec, mut buffer) = setup();
+
+                    // Newlines, other control bytes, the ENVIRON markers, and
+                    // IAC are all data inside a value
+                    let value = vec![b'a', b'\n', b'\r', 0, 1, 2, 3, 0x1b, IAC, IAC, b'z'];
+                    let op = EnvironmentOperation::Is(vec![(
+                        EnvironmentKind::WellKnown(Some(WellKnownVariable::Display)),
+                        Some(value),
+                    )]);
+
+                    for sb in [
+                        SubnegotiationType::Environment(op.clone()),
+                        SubnegotiationType::OldEnvironment(op),
+                    ] {
+                        buffer.extend(sb.to_bytes());
+                        assert_eq!(
+                            codec.decode(&mut buffer).unwrap().unwrap(),
+                            TelnetEvent::Subnegotiate(sb)
+                        );
+                        assert!(buffer.is_empty());
+                    }
+                }
+
+                #[test]
+                fn test_sb_environ_roundtrip() {
+                    use crate::env::{EnvironmentKind, EnvironmentOperation, WellKnownVariable};
+
+                    let (mut codec, mut buffer) = setup();
+
+                    // Names and values containing every byte that must be
+                    // escaped: VAR, VALUE, ESC, USERVAR, and IAC.
+                    let name = "N\x00\x01\x02\x03";
+                    let value = vec![b'v', 0, 1, 2, 3, IAC, IAC, b'!'];
+                    let vars = vec![
+                        (
+                            EnvironmentKind::WellKnown(Some(WellKnownVariable::User)),
+                            Some(b"rob".to_vec()),
+                        ),
+                        (EnvironmentKind::UserDefined(Some(name.to_string())), Some(value)),
+                        (EnvironmentKind::UserDefined(Some("EMPTY".to_string())), Some(Vec::new())),
+                        (EnvironmentKind::WellKnown(Some(WellKnownVariable::Display)), None),
+                    ];
+                    let operations = [
+                        EnvironmentOperation::Is(vars.clone()),
+                        EnvironmentOperation::Info(vars),
+                        EnvironmentOperation::Send(vec![
+                            EnvironmentKind::WellKnown(Some(WellKnownVariable::User)),
+                            EnvironmentKind::UserDefined(Some(name.to_string())),
+                            EnvironmentKind::WellKnown(None),
+                            EnvironmentKind::UserDefined(None),
+                        ]),
+                    ];
+
+                    for op in operations {
+                        for sb in [
+                            SubnegotiationType::Environment(op.clone()),
+                            SubnegotiationType::OldEnvironment(op.clone()),
+                        ] {
+                            codec
+                                .encode(TelnetEvent::Subnegotiate(sb.clone()), &mut buffer)
+                                .unwrap();
+                            assert_eq!(
+                                codec.decode(&mut buffer).unwrap(),
+                                Some(TelnetEvent::Subnegotiate(sb))
+                            );
+                            assert!(buffer.is_empty());
+                        }
+                    }
+                }
+
+                #[test]
+                fn test_sb_raw() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.decode_raw_subnegotiations = true;
+
+                    let frame = [IAC, SB, NAWS, 0, 80, IAC, IAC, 24, IAC, SE];
+                    buffer.extend(frame);
+                    let event = codec.decode(&mut buffer).unwrap().unwrap();
+                    assert_eq!(
+                        event,
+                        TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                            TelnetOption::NAWS,
+                            Bytes::from_static(&[0, 80, IAC, 24])
+                        ))
+                    );
+                    assert!(buffer.is_empty());
+
+                    codec.encode(event, &mut buffer).unwrap();
+                    assert_eq!(buffer.as_ref(), &frame);
+                }
+
+                #[test]
+                fn test_register_subnegotiation() {
+                    let (mut codec, mut buffer) = setup();
+
+                    // A made up protocol that strips a version prefix
+                    codec.register_subnegotiation(
+                        100,
+                        Box::new(|data| {
+                            let payload = data.strip_prefix(b"v1 ")?;
+
+                            Some(SubnegotiationType::Unknown(
+                                TelnetOption::Unknown(100),
+                                Bytes::copy_from_slice(payload),
+                            ))
+                        }),
+                    );
+
+                    buffer.extend([IAC, SB, 100]);
+                    buffer.extend(b"v1 a");
+                    buffer.extend([IAC, IAC, IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap(),
+                        Some(TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                            TelnetOption::Unknown(100),
+                            Bytes::from_static(&[b'a', IAC])
+                        )))
+                    );
+
+                    // Anything the decoder does not accept falls back.
+                    buffer.extend([IAC, SB, 100]);
+                    buffer.extend(b"v2 a");
+                    buffer.extend([IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap(),
+                        Some(TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                            TelnetOption::Unknown(100),
+                            Bytes::from_static(b"v2 a")
+                        )))
+                    );
+                }
+
+                #[test]
+                fn test_sb_max_length() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.max_subnegotiation_length = Some(8);
+
+                    // A frame within the limit waits for more input...
+                    buffer.extend([IAC, SB, GMCP]);
+                    buffer.extend([b'a'; 8]);
                     assert_eq!(codec.decode(&mut buffer).unwrap(), None);
-                    assert_eq!(&codec.buffer, &[IAC]);
-                    assert_eq!(buffer.as_ref(), &[IAC, IAC]);
+                    assert!(buffer.is_empty());
+
+                    // ...until it grows past it without being terminated
+                    buffer.extend([SB, b'a']);
+                    assert!(codec.decode(&mut buffer).is_err());
+                    assert_eq!(buffer.as_ref(), b"a");
+
+                    // The rest of the frame is skipped, rather than read as text
+                    buffer.extend(b"fgh");
+                    buffer.extend([IAC, SE]);
+                    buffer.extend(b"ok\r\n");
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![TelnetEvent::Message("ok".to_string())]
+                    );
+                    assert!(buffer.is_empty());
                 }
 
                 #[test]
-                fn test_do() {
+                fn test_sb_max_length_as_event() {
                     let (mut codec, mut buffer) = setup();
+                    codec.max_subnegotiation_length = Some(8);
+                    codec.errors_as_events = true;
 
-                    buffer.extend([IAC, DO, ECHO]);
+                    // The error is the only event from the frame
+                    buffer.extend([IAC, SB, GMCP]);
+                    buffer.extend(b"abcdefghijk\r\nlmn");
+                    buffer.extend([IAC, SE]);
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![TelnetEvent::ProtocolError(
+                            "subnegotiation exceeds 8 bytes".to_string()
+                        )]
+                    );
+                    assert!(buffer.is_empty());
+                    assert!(codec.pending().is_empty());
+
+                    buffer.extend([IAC, NOP]);
+                    assert_eq!(codec.decode_all(&mut buffer).unwrap(), vec![TelnetEvent::Nop]);
+                }
+
+                #[test]
+                fn test_sb_iac_option() {
+                    let (mut codec, mut buffer) = setup();
+
+                    // A doubled option byte is read as option 255
+                    buffer.extend([IAC, SB, IAC]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert_eq!(buffer.as_ref(), &[IAC, SB, IAC]);
+
+                    buffer.extend([IAC, 1, 2, IAC, SE]);
                     assert_eq!(
                         codec.decode(&mut buffer).unwrap().unwrap(),
-                        TelnetEvent::Do(TelnetOption::Echo)
+                        TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                            TelnetOption::Unknown(IAC),
+                            Bytes::from_static(&[1, 2])
+                        ))
                     );
-                    assert!(codec.buffer.is_empty());
                     assert!(buffer.is_empty());
+
+                    // A lone IAC in its place leaves the frame without an
+                    // option, so it is discarded
+                    buffer.extend([IAC, SB, IAC, SE, IAC, NOP]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Nop);
+                    assert!(buffer.is_empty());
+
+                    let mut encoded = BytesMut::new();
+                    codec
+                        .encode(
+                            TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                                TelnetOption::Unknown(IAC),
+                                Bytes::from_static(&[1]),
+                            )),
+                            &mut encoded,
+                        )
+                        .unwrap();
+                    assert_eq!(encoded.as_ref(), &[IAC, SB, IAC, IAC, 1, IAC, SE]);
                 }
 
                 #[test]
-                fn test_dont() {
+                fn test_sb_split_option() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, SB]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert_eq!(buffer.as_ref(), &[IAC, SB]);
+
+                    buffer.extend([NAWS, 0, 80, 0, 24, IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(80, 24))
+                    );
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_sb_resume() {
+                    let (mut codec, mut buffer) = setup();
+
+                    // The data read so far is kept by the codec, except for a
+                    // trailing IAC, which waits for the byte after it.
+                    buffer.extend(b"hi");
+                    buffer.extend([IAC, SB, GMCP]);
+                    buffer.extend(b"Core.Hello ");
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert!(buffer.is_empty());
+
+                    buffer.extend([b'a', IAC]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert_eq!(buffer.as_ref(), &[IAC]);
+
+                    buffer.extend([IAC, b'b', IAC]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    assert_eq!(buffer.as_ref(), &[IAC]);
+
+                    buffer.extend([SE]);
+                    buffer.extend(b"\r\n");
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![
+                            TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                                TelnetOption::GMCP,
+                                Bytes::from_static(b"Core.Hello a\xFFb")
+                            )),
+                            TelnetEvent::Message("hi".to_string()),
+                        ]
+                    );
+                    assert!(buffer.is_empty());
+
+                    // An invalid frame is discarded once it ends, and the
+                    // input after it is decoded as usual.
+                    buffer.extend([IAC, SB, NAWS, 0, 80]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                    buffer.extend([IAC, SE, IAC, NOP]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), Some(TelnetEvent::Nop));
+                }
+
+                #[test]
+                fn test_sb_terminal_speed() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, SB, TSPEED, IS]);
+                    buffer.extend(b"38400,9600");
+                    buffer.extend([IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::TerminalSpeed(
+                            TerminalSpeedOption::Is(38400, 9600)
+                        ))
+                    );
+                    assert!(buffer.is_empty());
+
+                    buffer.extend([IAC, SB, TSPEED, SEND, IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::TerminalSpeed(
+                            TerminalSpeedOption::Send
+                        ))
+                    );
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_sb_terminal_type() {
+                    let (mut codec, mut buffer) = setup();
+                    codec.track_options = true;
+                    assert_eq!(codec.terminal_type(), None);
+
+                    buffer.extend([IAC, SB, TTYPE, SEND, IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::TerminalType(
+                            TerminalTypeOption::Send
+                        ))
+                    );
+                    assert_eq!(codec.terminal_type(), None);
+
+                    // An escaped 0xFF is not valid UTF-8, so the frame is
+                    // dropped, but it still ends at its IAC SE
+                    buffer.extend([IAC, SB, TTYPE, IS, b'x', IAC, IAC, IAC, SE, IAC, NOP]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Nop);
+                    assert!(buffer.is_empty());
+
+                    // The most recently reported type is kept
+                    for name in ["xterm-256color", "VT100"] {
+                        buffer.extend([IAC, SB, TTYPE, IS]);
+                        buffer.extend(name.as_bytes());
+                        buffer.extend([IAC, SE]);
+                        assert_eq!(
+                            codec.decode(&mut buffer).unwrap().unwrap(),
+                            TelnetEvent::Subnegotiate(SubnegotiationType::TerminalType(
+                                TerminalTypeOption::Is(name.to_string())
+                            ))
+                        );
+                    }
+                    assert_eq!(codec.terminal_type(), Some("VT100"));
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_sb_x_display_location() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, SB, XDISPLOC, IS]);
+                    buffer.extend(b"host:0.0");
+                    buffer.extend([IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::XDisplayLocation(
+                            XDisplayLocationOption::Is("host:0.0".to_string())
+                        ))
+                    );
+                    assert!(buffer.is_empty());
+
+                    buffer.extend([IAC, SB, XDISPLOC, SEND, IAC, SE]);
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::XDisplayLocation(
+                            XDisplayLocationOption::Send
+                        ))
+                    );
+                    assert!(buffer.is_empty());
+
+                    // An escaped 0xFF is not valid UTF-8, so the frame is
+                    // dropped, but it still ends at its IAC SE
+                    buffer.extend([IAC, SB, XDISPLOC, IS, b'h', IAC, IAC, IAC, SE, IAC, NOP]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Nop);
+                    assert!(buffer.is_empty());
+                }
+
+                #[test]
+                fn test_sb_authentication() {
                     let (mut codec, mut buffer) = setup();
 
-                    buffer.extend([IAC, DONT, ECHO]);
+                    buffer.extend([IAC, SB, AUTHENTICATION, 1, 2, IAC, IAC, 3, IAC, SE]);
                     assert_eq!(
                         codec.decode(&mut buffer).unwrap().unwrap(),
-                        TelnetEvent::Dont(TelnetOption::Echo)
+                        TelnetEvent::Subnegotiate(SubnegotiationType::Authentication(
+                            Bytes::from_static(&[1, 2, IAC, 3])
+                        ))
                     );
-                    assert!(codec.buffer.is_empty());
-                    assert!(buffer.is_empty());
                 }
 
                 #[test]
-                fn test_will() {
+                fn test_sb_encrypt() {
                     let (mut codec, mut buffer) = setup();
 
-                    buffer.extend([IAC, WILL, ECHO]);
+                    buffer.extend([IAC, SB, ENCRYPT, 1, 2, 3, IAC, SE]);
                     assert_eq!(
                         codec.decode(&mut buffer).unwrap().unwrap(),
-                        TelnetEvent::Will(TelnetOption::Echo)
+                        TelnetEvent::Subnegotiate(SubnegotiationType::Encrypt(Bytes::from_static(
+                            &[1, 2, 3]
+                        )))
                     );
-                    assert!(codec.buffer.is_empty());
                     assert!(buffer.is_empty());
                 }
 
                 #[test]
-                fn test_wont() {
+                fn test_sb_msdp_depth() {
                     let (mut codec, mut buffer) = setup();
+                    codec.max_msdp_depth = 2;
 
-                    buffer.extend([IAC, WONT, ECHO]);
+                    // Two nested tables are accepted
+                    buffer.extend([IAC, SB, MSDP, MSDP_VAR, b'A', MSDP_VAL, MSDP_TABLE_OPEN]);
+                    buffer.extend([MSDP_VAR, b'B', MSDP_VAL, MSDP_TABLE_OPEN, MSDP_VAR, b'C']);
+                    buffer.extend([MSDP_VAL, b'1', MSDP_TABLE_CLOSE, MSDP_TABLE_CLOSE, IAC, SE]);
                     assert_eq!(
                         codec.decode(&mut buffer).unwrap().unwrap(),
-                        TelnetEvent::Wont(TelnetOption::Echo)
+                        TelnetEvent::Subnegotiate(SubnegotiationType::Msdp(vec![(
+                            "A".to_string(),
+                            MsdpValue::Table(vec![(
+                                "B".to_string(),
+                                MsdpValue::Table(vec![(
+                                    "C".to_string(),
+                                    MsdpValue::String("1".to_string())
+                                )])
+                            )])
+                        )]))
                     );
-                    assert!(codec.buffer.is_empty());
+
+                    // A third is an error, and the frame is skipped
+                    buffer.extend([IAC, SB, MSDP, MSDP_VAR, b'A']);
+                    for _ in 0..3 {
+                        buffer.extend([MSDP_VAL, MSDP_TABLE_OPEN, MSDP_VAR, b'A']);
+                    }
+                    buffer.extend([MSDP_VAL, b'1']);
+                    buffer.extend([MSDP_TABLE_CLOSE; 3]);
+                    buffer.extend([IAC, SE, IAC, NOP]);
+
+                    let error = codec.decode(&mut buffer).unwrap_err();
+                    assert!(matches!(error.kind, TelnetErrorType::Codec));
+                    assert_eq!(codec.decode(&mut buffer).unwrap().unwrap(), TelnetEvent::Nop);
                     assert!(buffer.is_empty());
                 }
 
                 #[test]
-                fn test_nop() {
+                fn test_sb_gmcp_lenient() {
                     let (mut codec, mut buffer) = setup();
+                    let frame = [IAC, SB, GMCP, b'a', IAC, b'b', IAC, SE];
 
-                    buffer.extend([IAC, NOP]);
+                    // A stray IAC makes the subnegotiation invalid by default
+                    buffer.extend(frame);
                     assert_eq!(codec.decode(&mut buffer).unwrap(), None);
-                    assert!(codec.buffer.is_empty());
-                    assert_eq!(buffer.as_ref(), &[IAC, NOP]);
-                }
 
-                #[test]
-                fn test_sb_naws() {
                     let (mut codec, mut buffer) = setup();
+                    codec.lenient = true;
 
-                    buffer.extend([IAC, SB, NAWS, 0x00, 0x50, 0x00, 0x50, IAC, SE]);
+                    buffer.extend(frame);
                     assert_eq!(
                         codec.decode(&mut buffer).unwrap().unwrap(),
-                        TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(80, 80))
+                        TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                            TelnetOption::GMCP,
+                            Bytes::from_static(&[b'a', IAC, b'b'])
+                        ))
+                    );
+                    assert!(buffer.is_empty());
+
+                    // A real command is not swallowed as data, and the frame
+                    // it cut short is dropped
+                    buffer.extend([IAC, SB, GMCP, b'a', IAC, WILL, ECHO, b'o', b'k', b'\r', b'\n']);
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![
+                            TelnetEvent::Will(TelnetOption::Echo),
+                            TelnetEvent::Message("ok".to_string())
+                        ]
+                    );
+
+                    buffer.extend([IAC, SB, GMCP, b'a', IAC, NOP, IAC, SB, GMCP, b'b', IAC, SE]);
+                    assert_eq!(
+                        codec.decode_all(&mut buffer).unwrap(),
+                        vec![
+                            TelnetEvent::Nop,
+                            TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                                TelnetOption::GMCP,
+                                Bytes::from_static(b"b")
+                            ))
+                        ]
                     );
-                    assert!(codec.buffer.is_empty());
                     assert!(buffer.is_empty());
                 }
 
@@ -821,13 +3958,90 @@ mod tests {
 
                     assert_eq!(
                         codec.decode(&mut buffer).unwrap().unwrap(),
-                        TelnetEvent::Subnegotiate(SubnegotiationType::CharsetRequest(vec![
-                            Bytes::from("UTF-8"),
-                            Bytes::from("US-ASCII")
-                        ]))
+                        TelnetEvent::Subnegotiate(SubnegotiationType::CharsetRequest(
+                            None,
+                            vec![Bytes::from("UTF-8"), Bytes::from("US-ASCII")]
+                        ))
                     );
                     assert!(codec.buffer.is_empty());
                     assert!(buffer.is_empty());
+
+                    // Empty and repeated entries are dropped
+                    buffer.extend([IAC, SB, CHARSET, CHARSET_REQUEST, b' ']);
+                    buffer.extend(b"UTF-8  US-ASCII UTF-8 ");
+                    buffer.extend([IAC, SE]);
+
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::CharsetRequest(
+                            None,
+                            vec![Bytes::from("UTF-8"), Bytes::from("US-ASCII")]
+                        ))
+                    );
+
+                    // A request without any charsets is invalid
+                    buffer.extend([IAC, SB, CHARSET, CHARSET_REQUEST, b' ', b' ', IAC, SE]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                }
+
+                #[test]
+                fn test_sb_charset_request_ttable() {
+                    let (mut codec, mut buffer) = setup();
+
+                    buffer.extend([IAC, SB, CHARSET, CHARSET_REQUEST]);
+                    buffer.extend(b"[TTABLE]\x01;UTF-8;US-ASCII");
+                    buffer.extend([IAC, SE]);
+
+                    let event = codec.decode(&mut buffer).unwrap().unwrap();
+                    assert_eq!(
+                        event,
+                        TelnetEvent::Subnegotiate(SubnegotiationType::CharsetRequest(
+                            Some(1),
+                            vec![Bytes::from("UTF-8"), Bytes::from("US-ASCII")]
+                        ))
+                    );
+
+                    codec.encode(event, &mut buffer).unwrap();
+                    assert_eq!(
+                        buffer.as_ref(),
+                        b"\xFF\xFA\x2A\x01[TTABLE]\x01 UTF-8 US-ASCII\xFF\xF0" as &[u8]
+                    );
+                    buffer.clear();
+
+                    // The version must be followed by a separator.
+                    buffer.extend([IAC, SB, CHARSET, CHARSET_REQUEST]);
+                    buffer.extend(b"[TTABLE]\x01");
+                    buffer.extend([IAC, SE]);
+                    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+                }
+
+                #[test]
+                fn test_sb_charset_ttable_is() {
+                    let (mut codec, mut buffer) = setup();
+
+                    // A table byte of 255 is doubled
+                    let sb =
+                        SubnegotiationType::CharsetTTableIs(1, Bytes::from_static(b";UTF-8;\xFF"));
+                    codec.encode(TelnetEvent::Subnegotiate(sb.clone()), &mut buffer).unwrap();
+                    assert_eq!(
+                        buffer.as_ref(),
+                        b"\xFF\xFA\x2A\x04\x01;UTF-8;\xFF\xFF\xFF\xF0" as &[u8]
+                    );
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap(),
+                        Some(TelnetEvent::Subnegotiate(sb))
+                    );
+
+                    for sb in
+                        [SubnegotiationType::CharsetTTableAck, SubnegotiationType::CharsetTTableNak]
+                    {
+                        codec.encode(TelnetEvent::Subnegotiate(sb.clone()), &mut buffer).unwrap();
+                        assert_eq!(buffer.len(), 6);
+                        assert_eq!(
+                            codec.decode(&mut buffer).unwrap(),
+                            Some(TelnetEvent::Subnegotiate(sb))
+                        );
+                    }
                 }
 
                 #[test]
@@ -866,59 +4080,489 @@ mod tests {
                 fn test_sb_charset_ttable_rejected() {
                     let (mut codec, mut buffer) = setup();
 
-                    buffer.extend([IAC, SB, CHARSET, CHARSET_TTABLE_REJECTED, IAC, SE]);
+                    buffer.extend([IAC, SB, CHARSET, CHARSET_TTABLE_REJECTED, IAC, SE]);
+
+                    assert_eq!(
+                        codec.decode(&mut buffer).unwrap().unwrap(),
+                        TelnetEvent::Subnegotiate(SubnegotiationType::CharsetTTableRejected)
+                    );
+                    assert!(codec.buffer.is_empty());
+                    assert!(buffer.is_empty());
+                }
+            }
+        }
+
+        #[cfg(feature = "mccp")]
+        mod test_mccp {
+            use std::io::Write;
+
+            use flate2::{write::ZlibEncoder, Compression};
+
+            use super::*;
+            use crate::constants::{MCCP2, MCCP3};
+
+            fn compress(data: &[u8], finish: bool) -> Vec<u8> {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+
+                if finish {
+                    encoder.finish().unwrap()
+                } else {
+                    encoder.flush().unwrap();
+                    encoder.get_ref().clone()
+                }
+            }
+
+            #[test]
+            fn test_mccp2() {
+                let (mut codec, mut buffer) = setup();
+
+                buffer.extend([IAC, SB, MCCP2, IAC, SE]);
+                buffer.extend(compress(b"hello\r\n\xff\xfb\x01", true));
+                buffer.extend(b"after\r\n");
+
+                assert_eq!(
+                    codec.decode_all(&mut buffer).unwrap(),
+                    vec![
+                        TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                            TelnetOption::MCCP2,
+                            Bytes::new()
+                        )),
+                        TelnetEvent::Message("hello".to_string()),
+                        TelnetEvent::Will(TelnetOption::Echo),
+                        TelnetEvent::Message("after".to_string()),
+                    ]
+                );
+                assert!(buffer.is_empty());
+            }
+
+            #[test]
+            fn test_mccp2_split_input() {
+                let (mut codec, mut buffer) = setup();
+                let mut input = vec![IAC, SB, MCCP2, IAC, SE];
+                input.extend(compress(b"one\r\ntwo\r\n", false));
+
+                // The compressed stream is inflated as it arrives
+                let mut events = Vec::new();
+                for byte in input {
+                    buffer.extend([byte]);
+                    events.extend(codec.decode_all(&mut buffer).unwrap());
+                }
+
+                assert_eq!(
+                    events[1..],
+                    [
+                        TelnetEvent::Message("one".to_string()),
+                        TelnetEvent::Message("two".to_string())
+                    ]
+                );
+            }
+
+            #[test]
+            fn test_mccp2_eof() {
+                let (mut codec, mut buffer) = setup();
+
+                buffer.extend([IAC, SB, MCCP2, IAC, SE]);
+                buffer.extend(compress(b"line\r\nprompt> ", true));
+
+                codec.decode(&mut buffer).unwrap();
+                assert_eq!(
+                    codec.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("line".to_string())
+                );
+
+                // The unterminated line is still flushed when the stream ends
+                assert_eq!(
+                    codec.decode_eof(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("prompt> ".to_string())
+                );
+                assert!(codec.decode_eof(&mut buffer).unwrap().is_none());
+
+                // Garbage after the start of compression is an error
+                let (mut codec, mut buffer) = setup();
+                buffer.extend([IAC, SB, MCCP2, IAC, SE, 0x00, 0x01, 0x02, 0x03]);
+                codec.decode(&mut buffer).unwrap();
+                assert!(codec.decode(&mut buffer).is_err());
+            }
+
+            #[test]
+            fn test_mccp3() {
+                let (mut client, mut buffer) = setup();
+                let mut server = TelnetCodec::new(16);
+                let start = TelnetEvent::Subnegotiate(SubnegotiationType::Unknown(
+                    TelnetOption::MCCP3,
+                    Bytes::new(),
+                ));
+
+                // The subnegotiation itself is sent uncompressed
+                client.encode(start, &mut buffer).unwrap();
+                assert_eq!(buffer.as_ref(), &[IAC, SB, MCCP3, IAC, SE]);
+
+                client.encode(TelnetEvent::Message("north".to_string()), &mut buffer).unwrap();
+                assert_ne!(&buffer[5..], b"north\r\n");
+                client.encode(TelnetEvent::Do(TelnetOption::Echo), &mut buffer).unwrap();
+
+                assert_eq!(
+                    server.decode_all(&mut buffer).unwrap()[1..],
+                    [
+                        TelnetEvent::Message("north".to_string()),
+                        TelnetEvent::Do(TelnetOption::Echo)
+                    ]
+                );
+
+                // Each event is flushed, so it can be decoded as soon as it
+                // arrives
+                client.encode(TelnetEvent::Message("south".to_string()), &mut buffer).unwrap();
+                assert_eq!(
+                    server.decode(&mut buffer).unwrap().unwrap(),
+                    TelnetEvent::Message("south".to_string())
+                );
+            }
+        }
+    }
+
+    mod test_codec {
+        use super::*;
+        use crate::constants::ECHO;
+
+        #[test]
+        fn test_recommended_read_buffer() {
+            assert_eq!(TelnetCodec::new(16).recommended_read_buffer(), 8 * 1024);
+            assert_eq!(TelnetCodec::new(8192).recommended_read_buffer(), 16386);
+            assert_eq!(TelnetCodec::new(usize::MAX).recommended_read_buffer(), usize::MAX);
+        }
+
+        #[test]
+        fn test_decode_all() {
+            let (mut codec, mut buffer) = setup();
+
+            buffer.extend([IAC, DO, ECHO]);
+            buffer.extend(b"hi\r\n");
+            buffer.extend([IAC, WILL, ECHO, IAC, WONT]);
+
+            assert_eq!(
+                codec.decode_all(&mut buffer).unwrap(),
+                vec![
+                    TelnetEvent::Do(TelnetOption::Echo),
+                    TelnetEvent::Message("hi".to_string()),
+                    TelnetEvent::Will(TelnetOption::Echo),
+                ]
+            );
+            assert!(buffer.is_empty());
+        }
+
+        #[test]
+        fn test_max_events_per_poll() {
+            let (mut codec, mut buffer) = setup();
+            codec.max_events_per_poll = Some(2);
+
+            buffer.extend([IAC, DO, ECHO, IAC, NOP, IAC, WILL, ECHO]);
+
+            assert_eq!(
+                codec.decode_all(&mut buffer).unwrap(),
+                vec![TelnetEvent::Do(TelnetOption::Echo), TelnetEvent::Nop]
+            );
+            assert_eq!(buffer.as_ref(), &[IAC, WILL, ECHO]);
+
+            assert_eq!(
+                codec.decode_all(&mut buffer).unwrap(),
+                vec![TelnetEvent::Will(TelnetOption::Echo)]
+            );
+            assert!(buffer.is_empty());
+        }
+
+        #[test]
+        #[cfg(feature = "metrics")]
+        fn test_metrics() {
+            let (mut codec, mut buffer) = setup();
+
+            buffer.extend([IAC, DO, ECHO]);
+            buffer.extend(b"hi\r\n");
+            buffer.extend([IAC, SB, NAWS, 0x00, 0x50, 0x00, 0x50, IAC, SE]);
+            codec.decode_all(&mut buffer).unwrap();
+
+            let metrics = codec.metrics();
+            assert_eq!(metrics.bytes, 16);
+            assert_eq!(metrics.negotiations, 1);
+            assert_eq!(metrics.messages, 1);
+            assert_eq!(metrics.subnegotiations, 1);
+            assert_eq!(metrics.overflows, 0);
+
+            buffer.extend([b'a'; 20]);
+            codec.decode(&mut buffer).unwrap();
+            assert_eq!(codec.metrics().overflows, 4);
+        }
+
+        #[test]
+        fn test_decode_slice() {
+            let (mut codec, _) = setup();
+
+            let input = [IAC, DO, ECHO, IAC, WILL];
+            let (event, consumed) = codec.decode_slice(&input).unwrap();
+            assert_eq!(event, Some(TelnetEvent::Do(TelnetOption::Echo)));
+            assert_eq!(consumed, 3);
+
+            // The partial command is kept by the codec.
+            let (event, consumed) = codec.decode_slice(&input[consumed..]).unwrap();
+            assert_eq!(event, None);
+            assert_eq!(consumed, 2);
+
+            let (event, consumed) = codec.decode_slice(&[ECHO]).unwrap();
+            assert_eq!(event, Some(TelnetEvent::Will(TelnetOption::Echo)));
+            assert_eq!(consumed, 1);
+        }
+
+        #[test]
+        fn test_supported_options() {
+            let (codec, _) = setup();
+            let options = codec.supported_options();
+
+            assert!(options.contains(&TelnetOption::NAWS));
+            assert!(options.contains(&TelnetOption::OldEnviron));
+            assert!(options.contains(&TelnetOption::GMCP));
+            assert_eq!(options.contains(&TelnetOption::MCCP2), cfg!(feature = "mccp"));
+            assert_eq!(options.contains(&TelnetOption::Charset), cfg!(feature = "charset"));
+        }
+    }
+
+    mod test_encode {
+        use super::*;
+        use crate::{
+            constants::{
+                AYT, ECHO, LINEMODE_EDIT, LINEMODE_MODE_ACK, LINEMODE_TRAPSIG, MSSP, SLC_ABORT,
+                SLC_BRK, SLC_DEFAULT, SLC_SYNCH,
+            },
+            env::{EnvironmentKind, EnvironmentOperation, WellKnownVariable},
+            linemode::{Dispatch, SlcFunction},
+            msdp::MsdpValue,
+        };
+
+        #[test]
+        fn test_message() {
+            let (mut codec, mut buffer) = setup();
+            codec.encode(TelnetEvent::Message("hiya 😁".to_string()), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), b"hiya \xF0\x9F\x98\x81\r\n");
+
+            let (mut codec, mut buffer) = setup();
+            let msg = "this message is larger than the max buffer length".to_string();
+            assert!(msg.len() > codec.max_buffer_length);
+            codec.encode(TelnetEvent::Message(msg), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), b"this message is larger than the max buffer length\r\n");
+        }
+
+        #[test]
+        fn test_client_echo() {
+            let (mut codec, mut buffer) = setup();
+            let event = codec.suppress_client_echo();
+            assert!(codec.client_echo_suppressed);
+            codec.encode(event, &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, WILL, ECHO]);
+
+            buffer.clear();
+            let event = codec.restore_client_echo();
+            assert!(!codec.client_echo_suppressed);
+            codec.encode(event, &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, WONT, ECHO]);
+        }
+
+        #[test]
+        #[cfg(feature = "unicode")]
+        fn test_unicode() {
+            let (mut codec, mut buffer) = setup();
+            codec.message_mode = false;
+            codec.unicode = true;
+            codec.sga = false;
+
+            buffer.extend(b"\xC3\xA4");
+
+            let result = codec.decode(&mut buffer);
+
+            assert!(matches!(result, Ok(Some(TelnetEvent::Unicode('ä')))));
+        }
+
+        #[test]
+        fn test_raw_message() {
+            let (mut codec, mut buffer) = setup();
+            codec.encode(TelnetEvent::RawMessage("hiya 😁".to_string()), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), b"hiya \xF0\x9F\x98\x81");
+        }
+
+        #[test]
+        fn test_wire_len() {
+            let (mut codec, mut buffer) = setup();
+            codec.dedupe_negotiation = true;
+
+            let events = [
+                TelnetEvent::Message("caf\u{e9}".to_string()),
+                TelnetEvent::Message("done\r\n".to_string()),
+                TelnetEvent::RawMessage("> ".to_string()),
+                TelnetEvent::Prompt("> ".to_string()),
+                TelnetEvent::Do(TelnetOption::NAWS),
+                TelnetEvent::Will(TelnetOption::Unknown(IAC)),
+                TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(255, 24)),
+                TelnetEvent::GoAhead,
+                TelnetEvent::UnknownCommand(AYT),
+                TelnetEvent::PreEncoded(Bytes::from_static(&[IAC, NOP, b'\r'])),
+            ];
+
+            for event in events {
+                let before = buffer.len();
+                let expected = codec.wire_len(&event);
+                codec.encode(event, &mut buffer).unwrap();
+                assert_eq!(buffer.len() - before, expected);
+            }
+
+            // A repeated negotiation is not sent again
+            assert_eq!(codec.wire_len(&TelnetEvent::Do(TelnetOption::NAWS)), 0);
+        }
+
+        #[test]
+        fn test_wire_len_forward_mask() {
+            let (mut codec, mut buffer) = setup();
+
+            for binary in [false, true] {
+                codec.binary_remote = binary;
+
+                let event = || {
+                    TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
+                        LineModeOption::ForwardMask(ForwardMaskOption::Do(vec![0x01, 0x02])),
+                    ))
+                };
+
+                buffer.clear();
+                codec.encode(event(), &mut buffer).unwrap();
+                assert_eq!(codec.wire_len(&event()), buffer.len());
+            }
+        }
+
+        #[test]
+        fn test_sb_len() {
+            let subnegotiations = [
+                SubnegotiationType::WindowSize(255, 255),
+                SubnegotiationType::CharsetRequest(
+                    Some(IAC),
+                    vec![Bytes::from_static(b"UTF-8"), Bytes::from_static(b"US-ASCII")],
+                ),
+                SubnegotiationType::LineMode(LineModeOption::ForwardMask(ForwardMaskOption::Do(
+                    vec![IAC; 20],
+                ))),
+                SubnegotiationType::Environment(EnvironmentOperation::Is(vec![
+                    (EnvironmentKind::UserDefined(Some("TERM".to_string())), Some(Vec::new())),
+                    (EnvironmentKind::WellKnown(Some(WellKnownVariable::User)), None),
+                ])),
+                SubnegotiationType::Environment(EnvironmentOperation::Send(vec![
+                    EnvironmentKind::WellKnown(None),
+                ])),
+                SubnegotiationType::TerminalType(TerminalTypeOption::Is("xterm".to_string())),
+                SubnegotiationType::CharsetTTableIs(IAC, Bytes::from_static(&[IAC, 1])),
+                SubnegotiationType::CharsetTTableAck,
+                SubnegotiationType::Unknown(TelnetOption::GMCP, Bytes::from_static(&[IAC, 1])),
+            ];
+
+            // `len` sizes buffers and counts bytes without encoding, so it
+            // must never overstate the frame. Escaped IAC bytes can only make
+            // the frame longer.
+            for sb in subnegotiations {
+                let len = sb.len() + 5;
+                assert!(sb.to_bytes().len() >= len, "{sb:?}");
+            }
+        }
+
+        #[test]
+        fn test_bare_cr() {
+            let (mut codec, mut buffer) = setup();
+
+            codec
+                .encode(TelnetEvent::RawMessage("50%\r100%\r\n".to_string()), &mut buffer)
+                .unwrap();
+            assert_eq!(buffer.as_ref(), b"50%\r\x00100%\r\n");
+
+            // In binary mode, the CR is sent as is
+            buffer.clear();
+            codec.binary_local = true;
+            codec.encode(TelnetEvent::RawMessage("50%\r".to_string()), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), b"50%\r");
+        }
+
+        #[test]
+        fn test_ansi_message() {
+            let (mut codec, mut buffer) = setup();
+
+            let text = "\x1b[38;2;255;128;0morange\x1b[0m \u{9b}1m";
+            codec.encode(TelnetEvent::ansi_message(text), &mut buffer).unwrap();
 
-                    assert_eq!(
-                        codec.decode(&mut buffer).unwrap().unwrap(),
-                        TelnetEvent::Subnegotiate(SubnegotiationType::CharsetTTableRejected)
-                    );
-                    assert!(codec.buffer.is_empty());
-                    assert!(buffer.is_empty());
-                }
-            }
+            let mut expected = text.as_bytes().to_vec();
+            expected.extend(b"\r\n");
+            assert_eq!(buffer.as_ref(), expected.as_slice());
         }
-    }
 
-    mod test_encode {
-        use super::*;
-        use crate::{
-            constants::{ECHO, LINEMODE_EDIT, SLC_ABORT, SLC_BRK, SLC_SYNCH},
-            linemode::{Dispatch, SlcFunction},
-        };
+        #[test]
+        #[cfg(feature = "charset")]
+        fn test_ansi_message_latin1() {
+            let (mut codec, mut buffer) = setup();
+            codec.set_charset("ISO-8859-1").unwrap();
+
+            codec.encode(TelnetEvent::ansi_message("\x1b[1m\u{ff}\x1b[0m"), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), b"\x1b[1m\xFF\xFF\x1b[0m\r\n");
+        }
 
         #[test]
-        fn test_message() {
+        #[cfg(feature = "charset")]
+        fn test_charset() {
             let (mut codec, mut buffer) = setup();
-            codec.encode(TelnetEvent::Message("hiya 😁".to_string()), &mut buffer).unwrap();
-            assert_eq!(buffer.as_ref(), b"hiya \xF0\x9F\x98\x81\r\n");
+            codec.set_charset("LATIN-1").unwrap();
+            codec.encode(TelnetEvent::Message("café".to_string()), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), b"caf\xE9\r\n");
 
+            // 'ÿ' is 0xFF in LATIN-1, so it must be doubled after transcoding.
             let (mut codec, mut buffer) = setup();
-            let msg = "this message is larger than the max buffer length".to_string();
-            assert!(msg.len() > codec.max_buffer_length);
-            codec.encode(TelnetEvent::Message(msg), &mut buffer).unwrap();
-            assert_eq!(buffer.as_ref(), b"this message is larger than the max buffer length\r\n");
+            codec.set_charset("LATIN-1").unwrap();
+            codec.encode(TelnetEvent::RawMessage("ÿ".to_string()), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, IAC]);
+
+            // ...unless IAC processing is disabled.
+            buffer.clear();
+            codec.process_iac = false;
+            codec.encode(TelnetEvent::RawMessage("ÿ".to_string()), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC]);
         }
 
         #[test]
-        #[cfg(feature = "unicode")]
-        fn test_unicode() {
-            let (mut codec, mut buffer) = setup();
-            codec.message_mode = false;
-            codec.unicode = true;
-            codec.sga = false;
+        fn test_mud_presets() {
+            let mut codec = TelnetCodec::mud_server();
+            assert!(!codec.sga);
+            assert!(codec.message_mode);
+            assert!(codec.track_options);
+            assert_eq!(codec.max_buffer_length, 4096);
+
+            let mut buffer = BytesMut::new();
+            for event in codec.take_outgoing() {
+                codec.encode(event, &mut buffer).unwrap();
+            }
+            assert_eq!(buffer.as_ref(), &[IAC, WILL, GMCP, IAC, WILL, MSSP, IAC, DO, NAWS]);
+            assert_eq!(codec.options.sent_will(TelnetOption::GMCP), Some(true));
 
-            buffer.extend(b"\xC3\xA4");
+            let mut codec = TelnetCodec::mud_client();
+            assert!(codec.track_options);
+            assert_eq!(codec.take_outgoing(), vec![TelnetEvent::Will(TelnetOption::NAWS)]);
+        }
 
-            let result = codec.decode(&mut buffer);
+        #[test]
+        fn test_keepalive() {
+            let (mut codec, mut buffer) = setup();
+            codec.encode(TelnetEvent::keepalive(), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, NOP]);
 
-            assert!(matches!(result, Ok(Some(TelnetEvent::Unicode('ä')))));
+            assert_eq!(codec.decode(&mut buffer).unwrap(), Some(TelnetEvent::Nop));
+            assert!(buffer.is_empty());
         }
 
         #[test]
-        fn test_raw_message() {
+        fn test_erase() {
             let (mut codec, mut buffer) = setup();
-            codec.encode(TelnetEvent::RawMessage("hiya 😁".to_string()), &mut buffer).unwrap();
-            assert_eq!(buffer.as_ref(), b"hiya \xF0\x9F\x98\x81");
+            codec.encode(TelnetEvent::EraseCharacter, &mut buffer).unwrap();
+            codec.encode(TelnetEvent::EraseLine, &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, EC, IAC, EL]);
         }
 
         #[test]
@@ -949,6 +4593,109 @@ mod tests {
             assert_eq!(buffer.as_ref(), &[IAC, WONT, ECHO]);
         }
 
+        #[test]
+        fn test_pre_encoded() {
+            let (mut codec, mut buffer) = setup();
+
+            // Written verbatim, with no IAC doubling, CR NUL, or line ending
+            let frame = [IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE, b'a', b'\r'];
+            codec
+                .encode(TelnetEvent::PreEncoded(Bytes::copy_from_slice(&frame)), &mut buffer)
+                .unwrap();
+            assert_eq!(buffer.as_ref(), &frame);
+            assert_eq!(TelnetEvent::PreEncoded(Bytes::copy_from_slice(&frame)).len(), frame.len());
+        }
+
+        #[test]
+        fn test_encode_to_vec() {
+            assert_eq!(TelnetEvent::Do(TelnetOption::Echo).encode_to_vec(), [IAC, DO, ECHO]);
+            assert_eq!(TelnetEvent::Message("hi".to_string()).encode_to_vec(), b"hi\r\n");
+            assert_eq!(TelnetEvent::Nop.encode_to_vec(), [IAC, NOP]);
+            assert_eq!(TelnetEvent::GoAhead.encode_to_vec(), [IAC, GA]);
+            assert!(TelnetEvent::Character(b'a').encode_to_vec().is_empty());
+
+            let event = TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(80, 24));
+            let bytes = event.encode_to_vec();
+            let (mut codec, mut buffer) = setup();
+            codec.encode(event, &mut buffer).unwrap();
+            assert_eq!(bytes, buffer.as_ref());
+        }
+
+        #[test]
+        fn test_forward_subnegotiation() {
+            let (mut codec, mut buffer) = setup();
+            buffer.extend([IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE]);
+            let input = buffer.clone().freeze();
+
+            let Some(TelnetEvent::Subnegotiate(sb)) = codec.decode(&mut buffer).unwrap() else {
+                panic!("expected a subnegotiation");
+            };
+
+            codec.encode(TelnetEvent::Subnegotiate(sb.clone()), &mut buffer).unwrap();
+            assert_eq!(buffer.freeze(), input);
+            assert_eq!(sb, SubnegotiationType::WindowSize(80, 24));
+        }
+
+        #[test]
+        fn test_subnegotiation_to_bytes() {
+            let sb = SubnegotiationType::WindowSize(80, 24);
+            assert_eq!(sb.to_bytes().as_ref(), &[IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE]);
+
+            let (mut codec, mut buffer) = setup();
+            let sb = SubnegotiationType::Unknown(TelnetOption::GMCP, Bytes::from_static(&[IAC]));
+            let bytes = sb.to_bytes();
+            codec.encode(TelnetEvent::Subnegotiate(sb), &mut buffer).unwrap();
+            assert_eq!(bytes, buffer.freeze());
+        }
+
+        #[test]
+        fn test_gmcp_supports_set() {
+            let sb = SubnegotiationType::gmcp_supports_set(&["Char 1", "Room 1", "Quo\"te\\"]);
+            assert_eq!(
+                sb,
+                SubnegotiationType::Unknown(
+                    TelnetOption::GMCP,
+                    Bytes::from_static(br#"Core.Supports.Set ["Char 1","Room 1","Quo\"te\\"]"#)
+                )
+            );
+
+            assert_eq!(
+                SubnegotiationType::gmcp_supports_set(&[]).to_bytes().as_ref(),
+                b"\xFF\xFA\xC9Core.Supports.Set []\xFF\xF0"
+            );
+        }
+
+        #[test]
+        fn test_iac_option() {
+            let (mut codec, mut buffer) = setup();
+            codec.encode(TelnetEvent::Will(TelnetOption::Unknown(IAC)), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, WILL, IAC, IAC]);
+        }
+
+        #[test]
+        fn test_dedupe_negotiation() {
+            let (mut codec, mut buffer) = setup();
+
+            // without deduplication, repeated negotiations are always sent
+            codec.encode(TelnetEvent::Will(TelnetOption::Echo), &mut buffer).unwrap();
+            codec.encode(TelnetEvent::Will(TelnetOption::Echo), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, WILL, ECHO, IAC, WILL, ECHO]);
+
+            let (mut codec, mut buffer) = setup();
+            codec.dedupe_negotiation = true;
+
+            codec.encode(TelnetEvent::Will(TelnetOption::Echo), &mut buffer).unwrap();
+            codec.encode(TelnetEvent::Will(TelnetOption::Echo), &mut buffer).unwrap();
+            codec.encode(TelnetEvent::Do(TelnetOption::Echo), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, WILL, ECHO, IAC, DO, ECHO]);
+            buffer.clear();
+
+            // a change of state is always sent
+            codec.encode(TelnetEvent::Wont(TelnetOption::Echo), &mut buffer).unwrap();
+            codec.encode(TelnetEvent::Will(TelnetOption::Echo), &mut buffer).unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, WONT, ECHO, IAC, WILL, ECHO]);
+        }
+
         #[test]
         fn test_sb_naws() {
             let (mut codec, mut buffer) = setup();
@@ -961,15 +4708,126 @@ mod tests {
             assert_eq!(buffer.as_ref(), &[IAC, SB, NAWS, 0x00, 0x50, 0x00, 0x50, IAC, SE]);
         }
 
+        #[test]
+        fn test_sb_terminal_speed() {
+            let (mut codec, mut buffer) = setup();
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::TerminalSpeed(
+                        TerminalSpeedOption::Is(38400, 9600),
+                    )),
+                    &mut buffer,
+                )
+                .unwrap();
+            assert_eq!(&buffer.as_ref()[0..=3], &[IAC, SB, TSPEED, IS]);
+            assert_eq!(&buffer.as_ref()[4..], b"38400,9600\xFF\xF0" as &[u8]);
+
+            let (mut codec, mut buffer) = setup();
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::TerminalSpeed(
+                        TerminalSpeedOption::Send,
+                    )),
+                    &mut buffer,
+                )
+                .unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, SB, TSPEED, SEND, IAC, SE]);
+        }
+
+        #[test]
+        fn test_sb_terminal_type() {
+            let (mut codec, mut buffer) = setup();
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::TerminalType(
+                        TerminalTypeOption::Is("xterm".to_string()),
+                    )),
+                    &mut buffer,
+                )
+                .unwrap();
+            assert_eq!(&buffer.as_ref()[0..=3], &[IAC, SB, TTYPE, IS]);
+            assert_eq!(&buffer.as_ref()[4..], b"xterm\xFF\xF0" as &[u8]);
+
+            // A name decodes back from its frame
+            let sb = SubnegotiationType::TerminalType(TerminalTypeOption::Is("ANSI-ÿ".to_string()));
+            buffer.clear();
+            codec.encode(TelnetEvent::Subnegotiate(sb.clone()), &mut buffer).unwrap();
+            assert_eq!(codec.decode(&mut buffer).unwrap(), Some(TelnetEvent::Subnegotiate(sb)));
+        }
+
+        #[test]
+        fn test_sb_x_display_location() {
+            let (mut codec, mut buffer) = setup();
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::XDisplayLocation(
+                        XDisplayLocationOption::Is("host:0.0".to_string()),
+                    )),
+                    &mut buffer,
+                )
+                .unwrap();
+            assert_eq!(&buffer.as_ref()[0..=3], &[IAC, SB, XDISPLOC, IS]);
+            assert_eq!(&buffer.as_ref()[4..], b"host:0.0\xFF\xF0" as &[u8]);
+
+            // A location decodes back from its frame
+            let sb = SubnegotiationType::XDisplayLocation(XDisplayLocationOption::Is(
+                "hôst:0.0".to_string(),
+            ));
+            buffer.clear();
+            codec.encode(TelnetEvent::Subnegotiate(sb.clone()), &mut buffer).unwrap();
+            assert_eq!(codec.decode(&mut buffer).unwrap(), Some(TelnetEvent::Subnegotiate(sb)));
+        }
+
+        #[test]
+        fn test_sb_authentication() {
+            let (mut codec, mut buffer) = setup();
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::Authentication(
+                        Bytes::from_static(&[1, 2, IAC, 3]),
+                    )),
+                    &mut buffer,
+                )
+                .unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, SB, AUTHENTICATION, 1, 2, IAC, IAC, 3, IAC, SE]);
+        }
+
+        #[test]
+        fn test_sb_encrypt() {
+            let (mut codec, mut buffer) = setup();
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::Encrypt(Bytes::from_static(&[
+                        1, 2, 3,
+                    ]))),
+                    &mut buffer,
+                )
+                .unwrap();
+            assert_eq!(buffer.as_ref(), &[IAC, SB, ENCRYPT, 1, 2, 3, IAC, SE]);
+        }
+
+        #[test]
+        fn test_sb_msdp() {
+            let (mut codec, mut buffer) = setup();
+            let sb = SubnegotiationType::Msdp(vec![(
+                "REPORT".to_string(),
+                MsdpValue::Array(vec![MsdpValue::String("HP".to_string())]),
+            )]);
+            codec.encode(TelnetEvent::Subnegotiate(sb.clone()), &mut buffer).unwrap();
+
+            assert_eq!(buffer.as_ref(), b"\xFF\xFAE\x01REPORT\x02\x05\x02HP\x06\xFF\xF0".as_ref());
+            assert_eq!(buffer.len(), sb.len() + 5);
+        }
+
         #[test]
         fn test_sb_charset_request() {
             let (mut codec, mut buffer) = setup();
             codec
                 .encode(
-                    TelnetEvent::Subnegotiate(SubnegotiationType::CharsetRequest(vec![
-                        Bytes::from("UTF-8"),
-                        Bytes::from("US-ASCII"),
-                    ])),
+                    TelnetEvent::Subnegotiate(SubnegotiationType::CharsetRequest(
+                        None,
+                        vec![Bytes::from("UTF-8"), Bytes::from("US-ASCII")],
+                    )),
                     &mut buffer,
                 )
                 .unwrap();
@@ -977,6 +4835,26 @@ mod tests {
             assert_eq!(&buffer.as_ref()[5..], b"UTF-8 US-ASCII\xFF\xF0" as &[u8]);
         }
 
+        #[test]
+        fn test_sb_charset_names() {
+            use crate::subnegotiation::Charset;
+
+            let request = SubnegotiationType::CharsetRequest(
+                None,
+                vec![Charset::Utf8.into(), Bytes::from("latin1"), Bytes::from("KOI8-R")],
+            );
+            assert_eq!(
+                request.charsets(),
+                Some(vec![Charset::Utf8, Charset::Latin1, Charset::Other("KOI8-R".to_string())])
+            );
+
+            let accepted = SubnegotiationType::CharsetAccepted(Charset::UsAscii.into());
+            assert_eq!(accepted, SubnegotiationType::CharsetAccepted(Bytes::from("US-ASCII")));
+            assert_eq!(accepted.charsets(), Some(vec![Charset::UsAscii]));
+
+            assert_eq!(SubnegotiationType::CharsetRejected.charsets(), None);
+        }
+
         #[test]
         fn test_sb_charset_accepted() {
             let (mut codec, mut buffer) = setup();
@@ -1019,7 +4897,7 @@ mod tests {
             codec
                 .encode(
                     TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(LineModeOption::Mode(
-                        LINEMODE_EDIT,
+                        LineMode::EDIT,
                     ))),
                     &mut buffer,
                 )
@@ -1037,12 +4915,39 @@ mod tests {
                 TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(LineModeOption::Mode(
                     mode,
                 ))) => {
-                    assert_eq!(mode, LINEMODE_EDIT);
+                    assert_eq!(mode, LineMode::EDIT);
                 }
                 _ => panic!("Bad decode!"),
             };
         }
 
+        #[test]
+        fn test_sb_linemode_mode_ack() {
+            let (mut codec, mut buffer) = setup();
+            let mode = LINEMODE_EDIT | LINEMODE_TRAPSIG;
+            buffer.extend([IAC, SB, LINEMODE, MODE, mode | LINEMODE_MODE_ACK, IAC, SE]);
+            assert_eq!(
+                codec.decode(&mut buffer).unwrap().unwrap(),
+                TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(LineModeOption::ModeAck(
+                    LineMode::EDIT | LineMode::TRAPSIG
+                )))
+            );
+            assert!(buffer.is_empty());
+
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
+                        LineModeOption::ModeAck(LineMode::EDIT | LineMode::TRAPSIG),
+                    )),
+                    &mut buffer,
+                )
+                .unwrap();
+            assert_eq!(
+                buffer.as_ref(),
+                &[IAC, SB, LINEMODE, MODE, mode | LINEMODE_MODE_ACK, IAC, SE]
+            );
+        }
+
         #[test]
         fn test_sb_linemode_slc_encode() {
             let (mut codec, mut buffer) = setup();
@@ -1117,6 +5022,26 @@ mod tests {
             assert_eq!(buffer.as_ref(), &[IAC, SB, LINEMODE, 123, 1, 2, 3, 4, 5, 6, IAC, SE]);
         }
 
+        #[test]
+        fn test_sb_linemode_slc_default_request() {
+            let (mut codec, mut buffer) = setup();
+            let frame = [IAC, SB, LINEMODE, LINEMODE_SLC, 0, SLC_DEFAULT, 0, IAC, SE];
+
+            let request = LineModeOption::slc_default_request();
+            assert!(request.is_slc_default_request());
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(request.clone())),
+                    &mut buffer,
+                )
+                .unwrap();
+            assert_eq!(buffer.as_ref(), &frame);
+
+            let event = codec.decode(&mut buffer).unwrap().unwrap();
+            assert_eq!(event, TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(request)));
+            assert!(buffer.is_empty());
+        }
+
         #[test]
         fn test_sb_linemode_slc_decode() {
             let (mut codec, mut buffer) = setup();
@@ -1201,6 +5126,48 @@ mod tests {
             }
         }
 
+        #[test]
+        fn test_sb_linemode_fmask_binary() {
+            let (mut codec, mut buffer) = setup();
+            codec.binary_local = true;
+            codec.binary_remote = true;
+
+            let mut mask = vec![0; 32];
+            mask[0] = IAC;
+            mask[31] = 1;
+
+            codec
+                .encode(
+                    TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
+                        LineModeOption::ForwardMask(ForwardMaskOption::Do(mask.clone())),
+                    )),
+                    &mut buffer,
+                )
+                .unwrap();
+
+            // The IAC in the mask is doubled
+            assert_eq!(&buffer[..7], &[IAC, SB, LINEMODE, DO, LINEMODE_FORWARD_MASK, IAC, IAC]);
+            assert_eq!(buffer.len(), 7 + 31 + 2);
+
+            assert_eq!(
+                codec.decode(&mut buffer).unwrap().unwrap(),
+                TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
+                    LineModeOption::ForwardMask(ForwardMaskOption::Do(mask)),
+                ))
+            );
+
+            // A short mask is padded to the binary length
+            buffer.extend([IAC, SB, LINEMODE, DO, LINEMODE_FORWARD_MASK, 1, IAC, SE]);
+            let mut expected = vec![0; 32];
+            expected[0] = 1;
+            assert_eq!(
+                codec.decode(&mut buffer).unwrap().unwrap(),
+                TelnetEvent::Subnegotiate(SubnegotiationType::LineMode(
+                    LineModeOption::ForwardMask(ForwardMaskOption::Do(expected)),
+                ))
+            );
+        }
+
         #[test]
         fn test_sb_linemode_fmask_encode() {
             let (mut codec, mut buffer) = setup();
@@ -1242,5 +5209,161 @@ mod tests {
                 ]
             )
         }
+
+        #[test]
+        fn test_offer() {
+            let (codec, _) = setup();
+
+            assert_eq!(
+                codec.offer(&[
+                    TelnetOption::GMCP,
+                    TelnetOption::MSSP,
+                    TelnetOption::NAWS,
+                    TelnetOption::TerminalType,
+                    TelnetOption::Echo,
+                ]),
+                vec![
+                    TelnetEvent::Will(TelnetOption::GMCP),
+                    TelnetEvent::Will(TelnetOption::MSSP),
+                    TelnetEvent::Do(TelnetOption::NAWS),
+                    TelnetEvent::Do(TelnetOption::TerminalType),
+                    TelnetEvent::Will(TelnetOption::Echo),
+                ]
+            );
+        }
+    }
+
+    mod test_roundtrip {
+        use proptest::{collection::vec, prelude::*, strategy::LazyJust};
+
+        use super::*;
+
+        fn option() -> impl Strategy<Value = TelnetOption> {
+            any::<u8>().prop_map(TelnetOption::from)
+        }
+
+        fn bytes() -> impl Strategy<Value = Bytes> {
+            vec(any::<u8>(), 0..32).prop_map(Bytes::from)
+        }
+
+        fn subnegotiation() -> impl Strategy<Value = SubnegotiationType> {
+            // Options with a dedicated decoder never come back as `Unknown`.
+            let unknown = any::<u8>().prop_filter("option has a dedicated decoder", |option| {
+                ![
+                    NAWS,
+                    CHARSET,
+                    LINEMODE,
+                    ENVIRON,
+                    OLD_ENVIRON,
+                    TTYPE,
+                    TSPEED,
+                    XDISPLOC,
+                    AUTHENTICATION,
+                    ENCRYPT,
+                    MSDP,
+                ]
+                .contains(option)
+            });
+            // Input after a received MCCP2 or MCCP3 subnegotiation is
+            // compressed.
+            #[cfg(feature = "mccp")]
+            let unknown = unknown.prop_filter("option starts compression", |option| {
+                ![crate::constants::MCCP2, crate::constants::MCCP3].contains(option)
+            });
+
+            prop_oneof![
+                (any::<u16>(), any::<u16>())
+                    .prop_map(|(width, height)| SubnegotiationType::WindowSize(width, height)),
+                (any::<u32>(), any::<u32>()).prop_map(|(transmit, receive)| {
+                    SubnegotiationType::TerminalSpeed(TerminalSpeedOption::Is(transmit, receive))
+                }),
+                LazyJust::new(|| SubnegotiationType::TerminalSpeed(TerminalSpeedOption::Send)),
+                "[ -~]*".prop_map(|name| SubnegotiationType::TerminalType(TerminalTypeOption::Is(
+                    name
+                ))),
+                LazyJust::new(|| SubnegotiationType::TerminalType(TerminalTypeOption::Send)),
+                "[ -~]*".prop_map(|location| {
+                    SubnegotiationType::XDisplayLocation(XDisplayLocationOption::Is(location))
+                }),
+                LazyJust::new(|| SubnegotiationType::XDisplayLocation(
+                    XDisplayLocationOption::Send
+                )),
+                bytes().prop_map(SubnegotiationType::Authentication),
+                bytes().prop_map(SubnegotiationType::Encrypt),
+                (unknown, bytes()).prop_map(|(option, bytes)| {
+                    SubnegotiationType::Unknown(option.into(), bytes)
+                }),
+            ]
+        }
+
+        fn unknown_command() -> impl Strategy<Value = u8> {
+            any::<u8>().prop_filter("command has a dedicated decoder", |command| {
+                !matches!(
+                    Command::from(*command),
+                    Command::NOP
+                        | Command::DM
+                        | Command::EC
+                        | Command::EL
+                        | Command::GA
+                        | Command::SB
+                        | Command::SE
+                        | Command::WILL
+                        | Command::WONT
+                        | Command::DO
+                        | Command::DONT
+                        | Command::IAC
+                )
+            })
+        }
+
+        fn event() -> impl Strategy<Value = TelnetEvent> {
+            // Decoded messages never contain the line ending they were split
+            // on.
+            prop_oneof![
+                "[^\r\n]*".prop_map(TelnetEvent::Message),
+                option().prop_map(TelnetEvent::Do),
+                option().prop_map(TelnetEvent::Dont),
+                option().prop_map(TelnetEvent::Will),
+                option().prop_map(TelnetEvent::Wont),
+                LazyJust::new(|| TelnetEvent::GoAhead),
+                LazyJust::new(|| TelnetEvent::Nop),
+                LazyJust::new(|| TelnetEvent::DataMark),
+                unknown_command().prop_map(TelnetEvent::UnknownCommand),
+                subnegotiation().prop_map(TelnetEvent::Subnegotiate),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn test_roundtrip(events in vec(event(), 0..16)) {
+                let mut codec = TelnetCodec::new(usize::MAX);
+                let mut buffer = BytesMut::new();
+
+                for event in &events {
+                    let event = match event {
+                        TelnetEvent::Message(message) => TelnetEvent::Message(message.clone()),
+                        TelnetEvent::Do(option) => TelnetEvent::Do(*option),
+                        TelnetEvent::Dont(option) => TelnetEvent::Dont(*option),
+                        TelnetEvent::Will(option) => TelnetEvent::Will(*option),
+                        TelnetEvent::Wont(option) => TelnetEvent::Wont(*option),
+                        TelnetEvent::Subnegotiate(sb) => {
+                            buffer.extend(sb.to_bytes());
+                            continue;
+                        }
+                        TelnetEvent::GoAhead => TelnetEvent::GoAhead,
+                        TelnetEvent::DataMark => TelnetEvent::DataMark,
+                        TelnetEvent::UnknownCommand(command) => {
+                            TelnetEvent::UnknownCommand(*command)
+                        }
+                        _ => TelnetEvent::Nop,
+                    };
+
+                    codec.encode(event, &mut buffer).unwrap();
+                }
+
+                prop_assert_eq!(codec.decode_all(&mut buffer).unwrap(), events);
+                prop_assert!(buffer.is_empty());
+            }
+        }
     }
 }
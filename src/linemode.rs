@@ -1,11 +1,80 @@
+use std::ops::{BitOr, BitOrAssign};
+
 use crate::constants::{
-    DO, DONT, SLC_ABORT, SLC_ABORTC, SLC_ACK, SLC_AO, SLC_AYT, SLC_BRK, SLC_BRKC, SLC_DSUSPC,
-    SLC_EC, SLC_EL, SLC_EOF, SLC_EOFCHAR, SLC_EOR, SLC_EORC, SLC_EW, SLC_EXIT, SLC_FLUSHIN,
-    SLC_FLUSHOUT, SLC_FORW1, SLC_FORW2, SLC_IP, SLC_LEVELBITS, SLC_LNEXT, SLC_LP, SLC_MCL, SLC_MCR,
-    SLC_MCUB, SLC_MCUF, SLC_MCWL, SLC_MCWR, SLC_REPRINT, SLC_RP, SLC_SUSP, SLC_SUSPC, SLC_SUSPCHAR,
-    SLC_SYNCH, SLC_XOFF, SLC_XOFFC, SLC_XON, SLC_XONC, WILL, WONT,
+    DO, DONT, LINEMODE_EDIT, LINEMODE_LIT_ECHO, LINEMODE_MODE_ACK, LINEMODE_SOFT_TAB,
+    LINEMODE_TRAPSIG, SLC_ABORT, SLC_ABORTC, SLC_ACK, SLC_AO, SLC_AYT, SLC_BRK, SLC_BRKC,
+    SLC_DSUSPC, SLC_EC, SLC_EL, SLC_EOF, SLC_EOFCHAR, SLC_EOR, SLC_EORC, SLC_EW, SLC_EXIT,
+    SLC_FLUSHIN, SLC_FLUSHOUT, SLC_FORW1, SLC_FORW2, SLC_IP, SLC_LEVELBITS, SLC_LNEXT, SLC_LP,
+    SLC_MCL, SLC_MCR, SLC_MCUB, SLC_MCUF, SLC_MCWL, SLC_MCWR, SLC_REPRINT, SLC_RP, SLC_SUSP,
+    SLC_SUSPC, SLC_SUSPCHAR, SLC_SYNCH, SLC_XOFF, SLC_XOFFC, SLC_XON, SLC_XONC, WILL, WONT,
 };
 
+/// A set of LINEMODE MODE flags, as sent in `IAC SB LINEMODE MODE <mask> IAC
+/// SE`. Flags can be combined with `|`, e.g. `LineMode::EDIT |
+/// LineMode::TRAPSIG`.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, Hash)]
+pub struct LineMode(u8);
+
+impl LineMode {
+    /// The client should perform all line editing locally.
+    pub const EDIT: LineMode = LineMode(LINEMODE_EDIT);
+    /// The client should translate interrupts and signals to their Telnet
+    /// equivalents.
+    pub const TRAPSIG: LineMode = LineMode(LINEMODE_TRAPSIG);
+    /// Acknowledges a mode sent by the other side.
+    pub const MODE_ACK: LineMode = LineMode(LINEMODE_MODE_ACK);
+    /// The client should expand tabs to spaces.
+    pub const SOFT_TAB: LineMode = LineMode(LINEMODE_SOFT_TAB);
+    /// The client should echo non-printable characters literally.
+    pub const LIT_ECHO: LineMode = LineMode(LINEMODE_LIT_ECHO);
+
+    /// Returns a mode with no flags set.
+    pub const fn empty() -> Self {
+        LineMode(0)
+    }
+
+    /// Returns the raw mode byte.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Returns true if every flag in `other` is also set in this mode.
+    pub const fn contains(self, other: LineMode) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Unsets every flag in `other`.
+    pub fn remove(&mut self, other: LineMode) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for LineMode {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        LineMode(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for LineMode {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl From<u8> for LineMode {
+    fn from(value: u8) -> Self {
+        LineMode(value)
+    }
+}
+
+impl From<LineMode> for u8 {
+    fn from(mode: LineMode) -> u8 {
+        mode.0
+    }
+}
+
 /// Represents the support level of Telnet's Special Linemode Characters (SLC).
 /// This enum categorizes the possible states or capabilities associated with a
 /// specific SLC function, reflecting its configurability and support status.
@@ -418,6 +487,15 @@ mod tests {
         assert_eq!(result, expected, "Failed to parse SLC_SYNCH into SlcFunction::Synch");
     }
 
+    #[test]
+    fn test_linemode_flags() {
+        let mode = LineMode::EDIT | LineMode::TRAPSIG;
+        assert_eq!(u8::from(mode), LINEMODE_EDIT | LINEMODE_TRAPSIG);
+        assert!(mode.contains(LineMode::EDIT));
+        assert!(!mode.contains(LineMode::MODE_ACK));
+        assert_eq!(LineMode::from(u8::from(mode)), mode);
+    }
+
     #[test]
     fn test_modifiers_from_byte() {
         let input = SLC_ACK | SLC_FLUSHIN; // Example combining two modifier flags
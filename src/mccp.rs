@@ -0,0 +1,122 @@
+use bytes::{Buf, BytesMut};
+use flate2::{Compress, Compression as Level, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::error::TelnetError;
+
+type Result<T> = std::result::Result<T, TelnetError>;
+
+/// The state of MCCP compression on a connection.
+#[derive(Debug, Default)]
+pub(crate) struct Compression {
+    /// Inflates the input, from when the peer starts compressing it until
+    /// the end of its compressed stream.
+    inflate: Option<Decompress>,
+    /// Input that has been inflated but not decoded yet, followed by any
+    /// input received after the end of the compressed stream.
+    pub(crate) inflated: BytesMut,
+    /// Deflates the output, from when the codec starts compressing it.
+    deflate: Option<Compress>,
+}
+
+impl Compression {
+    /// Treats all input after the current event as compressed.
+    pub(crate) fn start_inflating(&mut self) {
+        self.inflate = Some(Decompress::new(true));
+    }
+
+    /// Returns true if input must be decoded from `inflated`, rather than
+    /// as received.
+    pub(crate) fn is_active(&self) -> bool {
+        self.inflate.is_some() || !self.inflated.is_empty()
+    }
+
+    /// Moves all of `input` into `inflated`, inflating it while the
+    /// compressed stream lasts. `FlushDecompress::Finish` drains any output
+    /// the decompressor is still holding, for when the connection ends.
+    pub(crate) fn inflate(&mut self, input: &mut BytesMut, flush: FlushDecompress) -> Result<()> {
+        let Some(inflate) = &mut self.inflate else {
+            // Input after the end of the compressed stream is decoded after
+            // the inflated input before it.
+            if !self.inflated.is_empty() {
+                self.inflated.extend_from_slice(input);
+                input.clear();
+            }
+
+            return Ok(());
+        };
+
+        let mut chunk = [0; 4096];
+
+        loop {
+            let (total_in, total_out) = (inflate.total_in(), inflate.total_out());
+
+            let status = inflate
+                .decompress(input, &mut chunk, flush)
+                .map_err(|error| format!("received invalid MCCP compressed data: {error}"))?;
+
+            let consumed = (inflate.total_in() - total_in) as usize;
+            let produced = (inflate.total_out() - total_out) as usize;
+
+            input.advance(consumed);
+            self.inflated.extend_from_slice(&chunk[..produced]);
+
+            if status == Status::StreamEnd {
+                // The peer has stopped compressing, so the rest of the input
+                // is uncompressed.
+                self.inflate = None;
+                self.inflated.extend_from_slice(input);
+                input.clear();
+
+                return Ok(());
+            }
+
+            if consumed == 0 && produced < chunk.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Compresses all output encoded after the current event. The stream is
+    /// flushed after every event, so the peer can decode each one as soon as
+    /// it arrives.
+    pub(crate) fn start_deflating(&mut self) {
+        if self.deflate.is_none() {
+            self.deflate = Some(Compress::new(Level::default(), true));
+        }
+    }
+
+    /// Compresses the output encoded from `start` onwards, in place.
+    pub(crate) fn deflate(&mut self, buffer: &mut BytesMut, start: usize) -> Result<()> {
+        let Some(deflate) = &mut self.deflate else {
+            return Ok(());
+        };
+
+        let raw = buffer.split_off(start);
+        let mut input = &raw[..];
+        let mut chunk = [0; 4096];
+
+        loop {
+            let (total_in, total_out) = (deflate.total_in(), deflate.total_out());
+
+            deflate
+                .compress(input, &mut chunk, FlushCompress::Sync)
+                .map_err(|error| format!("failed to compress MCCP data: {error}"))?;
+
+            let consumed = (deflate.total_in() - total_in) as usize;
+            let produced = (deflate.total_out() - total_out) as usize;
+
+            input = &input[consumed..];
+            buffer.extend_from_slice(&chunk[..produced]);
+
+            if input.is_empty() && produced < chunk.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Drops any input that has not been decoded, and stops inflating.
+    pub(crate) fn clear(&mut self) {
+        self.inflate = None;
+        self.inflated.clear();
+    }
+}
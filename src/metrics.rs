@@ -0,0 +1,44 @@
+use crate::event::TelnetEvent;
+
+/// Counters describing the traffic seen by a `TelnetCodec` while decoding.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CodecMetrics {
+    /// The number of input bytes consumed by the decoder.
+    pub bytes: u64,
+    /// The number of `Message` events decoded.
+    pub messages: u64,
+    /// The number of single character events decoded.
+    pub characters: u64,
+    /// The number of DO, DONT, WILL, and WONT events decoded.
+    pub negotiations: u64,
+    /// The number of subnegotiation events decoded.
+    pub subnegotiations: u64,
+    /// The number of data bytes dropped because the line buffer was full.
+    pub overflows: u64,
+    /// The number of malformed subnegotiations that were discarded, including
+    /// stray `IAC SE`s outside of any subnegotiation.
+    pub resyncs: u64,
+}
+
+impl CodecMetrics {
+    /// Records a call to `decode` which consumed `bytes` from the input and
+    /// produced `event`.
+    pub(crate) fn record(&mut self, bytes: usize, event: Option<&TelnetEvent>) {
+        self.bytes += bytes as u64;
+
+        match event {
+            Some(TelnetEvent::Message(_)) => self.messages += 1,
+            Some(TelnetEvent::Character(_)) => self.characters += 1,
+            #[cfg(feature = "unicode")]
+            Some(TelnetEvent::Unicode(_)) => self.characters += 1,
+            Some(
+                TelnetEvent::Do(_)
+                | TelnetEvent::Dont(_)
+                | TelnetEvent::Will(_)
+                | TelnetEvent::Wont(_),
+            ) => self.negotiations += 1,
+            Some(TelnetEvent::Subnegotiate(_)) => self.subnegotiations += 1,
+            _ => {}
+        }
+    }
+}
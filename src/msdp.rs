@@ -0,0 +1,259 @@
+use bytes::{BufMut, BytesMut};
+
+use crate::{
+    constants::{
+        MSDP_ARRAY_CLOSE, MSDP_ARRAY_OPEN, MSDP_TABLE_CLOSE, MSDP_TABLE_OPEN, MSDP_VAL, MSDP_VAR,
+    },
+    error::TelnetError,
+};
+
+type Result<T> = std::result::Result<T, TelnetError>;
+
+/// The value of an MSDP variable.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MsdpValue {
+    String(String),
+    /// An `MSDP_ARRAY_OPEN` ... `MSDP_ARRAY_CLOSE` list of values. A variable
+    /// given more than one `MSDP_VAL` is decoded as an array as well.
+    Array(Vec<MsdpValue>),
+    /// An `MSDP_TABLE_OPEN` ... `MSDP_TABLE_CLOSE` list of variables, in the
+    /// order they were sent.
+    Table(Vec<(String, MsdpValue)>),
+}
+
+impl MsdpValue {
+    /// Returns the number of bytes the value is encoded as, not counting the
+    /// `MSDP_VAL` before it.
+    pub fn encoded_size(&self) -> usize {
+        match self {
+            MsdpValue::String(value) => value.len(),
+            MsdpValue::Array(values) => values
+                .iter()
+                .map(|value| value.encoded_size().saturating_add(1))
+                .fold(2, usize::saturating_add),
+            MsdpValue::Table(vars) => vars.iter().map(var_size).fold(2, usize::saturating_add),
+        }
+    }
+}
+
+/// Returns the number of bytes a variable is encoded as.
+pub(crate) fn var_size((name, value): &(String, MsdpValue)) -> usize {
+    name.len().saturating_add(value.encoded_size()).saturating_add(2)
+}
+
+/// A table or array that has been opened but not closed yet.
+enum Container {
+    /// The variables read so far, and the name of the variable whose value is
+    /// being read.
+    Table(Vec<(String, MsdpValue)>, Option<String>),
+    Array(Vec<MsdpValue>),
+}
+
+impl Container {
+    fn push(&mut self, value: MsdpValue) -> Result<()> {
+        match self {
+            Container::Array(values) => values.push(value),
+            Container::Table(vars, name) => match (name.take(), vars.last_mut()) {
+                (Some(name), _) => vars.push((name, value)),
+                // Another MSDP_VAL for the same variable
+                (None, Some((_, MsdpValue::Array(values)))) => values.push(value),
+                (None, Some((_, last))) => {
+                    let first = std::mem::replace(last, MsdpValue::Array(Vec::new()));
+                    *last = MsdpValue::Array(vec![first, value]);
+                }
+                (None, None) => {
+                    return Err("received an MSDP_VAL without an MSDP_VAR".to_string().into())
+                }
+            },
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads text up to the next MSDP command byte, returning it along with the
+/// index it ends at.
+fn decode_text(subvec: &[u8], start: usize) -> (String, usize) {
+    let end = subvec[start..]
+        .iter()
+        .position(|byte| (MSDP_VAR..=MSDP_ARRAY_CLOSE).contains(byte))
+        .map_or(subvec.len(), |length| start + length);
+
+    (String::from_utf8_lossy(&subvec[start..end]).into_owned(), end)
+}
+
+/// Decodes the variables of an MSDP subnegotiation.
+///
+/// Nested tables and arrays are tracked on an explicit stack rather than by
+/// recursion, so a peer cannot exhaust the call stack. Nesting deeper than
+/// `max_depth` is a `Codec` error, as is a malformed or unterminated
+/// structure.
+pub fn decode_msdp(subvec: &[u8], max_depth: usize) -> Result<Vec<(String, MsdpValue)>> {
+    let mut stack = vec![Container::Table(Vec::new(), None)];
+    let mut index = 0;
+
+    while let Some(&byte) = subvec.get(index) {
+        index += 1;
+
+        match byte {
+            MSDP_VAR => {
+                let (name, end) = decode_text(subvec, index);
+                index = end;
+
+                match stack.last_mut() {
+                    Some(Container::Table(_, pending)) => *pending = Some(name),
+                    _ => return Err("received an MSDP_VAR inside an array".to_string().into()),
+                }
+            }
+            MSDP_VAL => match subvec.get(index) {
+                Some(&open @ (MSDP_TABLE_OPEN | MSDP_ARRAY_OPEN)) => {
+                    index += 1;
+
+                    // The outermost table does not count towards the depth.
+                    if stack.len() > max_depth {
+                        return Err(format!(
+                            "received MSDP nested deeper than the limit of {max_depth}"
+                        )
+                        .into());
+                    }
+
+                    stack.push(if open == MSDP_TABLE_OPEN {
+                        Container::Table(Vec::new(), None)
+                    } else {
+                        Container::Array(Vec::new())
+                    });
+                }
+                _ => {
+                    let (value, end) = decode_text(subvec, index);
+                    index = end;
+
+                    if let Some(container) = stack.last_mut() {
+                        container.push(MsdpValue::String(value))?;
+                    }
+                }
+            },
+            MSDP_TABLE_CLOSE | MSDP_ARRAY_CLOSE if stack.len() > 1 => {
+                let value = match (stack.pop(), byte) {
+                    (Some(Container::Table(vars, _)), MSDP_TABLE_CLOSE) => MsdpValue::Table(vars),
+                    (Some(Container::Array(values)), MSDP_ARRAY_CLOSE) => MsdpValue::Array(values),
+                    _ => return Err("received a mismatched MSDP close".to_string().into()),
+                };
+
+                if let Some(container) = stack.last_mut() {
+                    container.push(value)?;
+                }
+            }
+            _ => return Err(format!("received unexpected MSDP byte {byte}").into()),
+        }
+    }
+
+    match stack.pop() {
+        Some(Container::Table(vars, _)) if stack.is_empty() => Ok(vars),
+        _ => Err("received an MSDP table or array that is never closed".to_string().into()),
+    }
+}
+
+fn encode_value(value: &MsdpValue, buffer: &mut BytesMut) {
+    match value {
+        // UTF-8 never contains an IAC byte, so text needs no escaping.
+        MsdpValue::String(value) => buffer.extend_from_slice(value.as_bytes()),
+        MsdpValue::Array(values) => {
+            buffer.put_u8(MSDP_ARRAY_OPEN);
+            for value in values {
+                buffer.put_u8(MSDP_VAL);
+                encode_value(value, buffer);
+            }
+            buffer.put_u8(MSDP_ARRAY_CLOSE);
+        }
+        MsdpValue::Table(vars) => {
+            buffer.put_u8(MSDP_TABLE_OPEN);
+            encode_msdp(vars, buffer);
+            buffer.put_u8(MSDP_TABLE_CLOSE);
+        }
+    }
+}
+
+/// Encodes MSDP variables as subnegotiation data, without the surrounding
+/// `IAC SB MSDP` and `IAC SE`.
+pub fn encode_msdp(vars: &[(String, MsdpValue)], buffer: &mut BytesMut) {
+    for (name, value) in vars {
+        buffer.put_u8(MSDP_VAR);
+        buffer.extend_from_slice(name.as_bytes());
+        buffer.put_u8(MSDP_VAL);
+        encode_value(value, buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> MsdpValue {
+        MsdpValue::String(value.to_string())
+    }
+
+    #[test]
+    fn test_decode_msdp() {
+        let vars = decode_msdp(
+            b"\x01ROOM\x02\x03\x01VNUM\x026008\x01EXITS\x02\x03\x01n\x026011\x04\x04\
+              \x01REPORT\x02\x05\x02HEALTH\x02MANA\x06\x01LIST\x02A\x02B",
+            8,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vars,
+            vec![
+                (
+                    "ROOM".to_string(),
+                    MsdpValue::Table(vec![
+                        ("VNUM".to_string(), string("6008")),
+                        (
+                            "EXITS".to_string(),
+                            MsdpValue::Table(vec![("n".to_string(), string("6011"))])
+                        ),
+                    ])
+                ),
+                ("REPORT".to_string(), MsdpValue::Array(vec![string("HEALTH"), string("MANA")])),
+                ("LIST".to_string(), MsdpValue::Array(vec![string("A"), string("B")])),
+            ]
+        );
+
+        let mut buffer = BytesMut::new();
+        encode_msdp(&vars[..2], &mut buffer);
+        assert_eq!(decode_msdp(&buffer, 8).unwrap(), vars[..2]);
+        assert_eq!(buffer.len(), vars[..2].iter().map(var_size).sum::<usize>());
+    }
+
+    #[test]
+    fn test_decode_msdp_depth() {
+        let mut nested = b"\x01A".to_vec();
+        for _ in 0..3 {
+            nested.extend([MSDP_VAL, MSDP_TABLE_OPEN, MSDP_VAR, b'A']);
+        }
+        nested.extend([MSDP_VAL, b'1']);
+        nested.extend([MSDP_TABLE_CLOSE; 3]);
+
+        assert!(decode_msdp(&nested, 3).is_ok());
+        assert!(decode_msdp(&nested, 2).is_err());
+
+        // A hostile peer cannot exhaust the stack, even without a limit.
+        let hostile = [MSDP_VAR, b'A']
+            .into_iter()
+            .chain([MSDP_VAL, MSDP_ARRAY_OPEN].repeat(1_000_000))
+            .collect::<Vec<_>>();
+        assert!(decode_msdp(&hostile, usize::MAX).is_err());
+        assert!(decode_msdp(&hostile, 64).is_err());
+    }
+
+    #[test]
+    fn test_decode_msdp_invalid() {
+        assert!(decode_msdp(b"\x02VALUE", 8).is_err());
+        assert!(decode_msdp(b"NAME", 8).is_err());
+        assert!(decode_msdp(b"\x01A\x02\x03\x06", 8).is_err());
+        assert!(decode_msdp(b"\x01A\x02\x05\x01B\x06", 8).is_err());
+        assert!(decode_msdp(b"\x01A\x02\x03", 8).is_err());
+        assert!(decode_msdp(b"\x04", 8).is_err());
+        assert_eq!(decode_msdp(b"", 8).unwrap(), Vec::new());
+    }
+}
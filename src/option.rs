@@ -1,6 +1,18 @@
-use crate::constants::{
-    BINARY, CHARSET, ECHO, ENVIRON, GA, GMCP, LINEMODE, MCCP2, MSP, MSSP, MXP, NAWS,
-    REMOTE_FLOW_CONTROL, SGA, STATUS, TELOPT_EOR, TIMING_MARK,
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    str::FromStr,
+    time::Instant,
+};
+
+use crate::{
+    constants::{
+        AUTHENTICATION, BINARY, CHARSET, DO, DONT, ECHO, ENCRYPT, ENVIRON, GA, GMCP, LINEMODE,
+        MCCP2, MCCP3, MSDP, MSP, MSSP, MXP, NAWS, OLD_ENVIRON, REMOTE_FLOW_CONTROL, SGA, STATUS,
+        TELOPT_EOR, TIMING_MARK, TSPEED, TTYPE, WILL, WONT, XDISPLOC,
+    },
+    error::TelnetError,
+    event::TelnetEvent,
 };
 
 /// Represents all Telnet options supported by Nectar.
@@ -36,7 +48,9 @@ pub enum TelnetOption {
     EndOfRecord,
     Charset,
     MCCP2,
+    MCCP3,
     GMCP,
+    MSDP,
     MSSP,
     MSP,
     MXP,
@@ -47,10 +61,52 @@ pub enum TelnetOption {
     RemoteFlowControl,
     Binary,
     Environ,
+    /// Allows the client to report its terminal type, such as
+    /// `"xterm-256color"`.
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc1091> for more information.
+    TerminalType,
+    /// Allows the client to report its transmit and receive baud rates.
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc1079> for more information.
+    TerminalSpeed,
+    /// Allows the client to report the location of its X display.
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc1096> for more information.
+    XDisplayLocation,
+    /// The original environment option, which predates `Environ`. Its
+    /// subnegotiations use the legacy VAR and VALUE byte values.
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc1408> for more information.
+    OldEnviron,
+    /// Negotiates an authentication mechanism. Nectar only handles the
+    /// framing; the mechanism itself is left to the application.
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc2941> for more information.
+    Authentication,
+    /// Negotiates encryption of the data stream. Nectar only handles the
+    /// framing; encryption itself is left to the application.
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc2946> for more information.
+    Encrypt,
     /// A generic marker indicating an unknown option.
     Unknown(u8),
 }
 
+/// The options whose subnegotiations nectar decodes into typed values, rather
+/// than passing them through as raw bytes. Servers can advertise these and
+/// refuse everything else.
+pub const DEFAULT_MUD_OPTIONS: &[TelnetOption] = &[
+    TelnetOption::NAWS,
+    TelnetOption::Charset,
+    TelnetOption::LineMode,
+    TelnetOption::Environ,
+    TelnetOption::OldEnviron,
+    TelnetOption::TerminalType,
+    TelnetOption::TerminalSpeed,
+    TelnetOption::XDisplayLocation,
+];
+
 impl From<u8> for TelnetOption {
     fn from(byte: u8) -> Self {
         match byte {
@@ -60,7 +116,9 @@ impl From<u8> for TelnetOption {
             TELOPT_EOR => TelnetOption::EndOfRecord,
             CHARSET => TelnetOption::Charset,
             MCCP2 => TelnetOption::MCCP2,
+            MCCP3 => TelnetOption::MCCP3,
             GMCP => TelnetOption::GMCP,
+            MSDP => TelnetOption::MSDP,
             MSSP => TelnetOption::MSSP,
             MSP => TelnetOption::MSP,
             MXP => TelnetOption::MXP,
@@ -71,6 +129,12 @@ impl From<u8> for TelnetOption {
             REMOTE_FLOW_CONTROL => TelnetOption::RemoteFlowControl,
             BINARY => TelnetOption::Binary,
             ENVIRON => TelnetOption::Environ,
+            TTYPE => TelnetOption::TerminalType,
+            TSPEED => TelnetOption::TerminalSpeed,
+            XDISPLOC => TelnetOption::XDisplayLocation,
+            OLD_ENVIRON => TelnetOption::OldEnviron,
+            AUTHENTICATION => TelnetOption::Authentication,
+            ENCRYPT => TelnetOption::Encrypt,
             _ => TelnetOption::Unknown(byte),
         }
     }
@@ -85,7 +149,9 @@ impl From<TelnetOption> for u8 {
             TelnetOption::EndOfRecord => TELOPT_EOR,
             TelnetOption::Charset => CHARSET,
             TelnetOption::MCCP2 => MCCP2,
+            TelnetOption::MCCP3 => MCCP3,
             TelnetOption::GMCP => GMCP,
+            TelnetOption::MSDP => MSDP,
             TelnetOption::MSSP => MSSP,
             TelnetOption::MSP => MSP,
             TelnetOption::MXP => MXP,
@@ -96,7 +162,253 @@ impl From<TelnetOption> for u8 {
             TelnetOption::RemoteFlowControl => REMOTE_FLOW_CONTROL,
             TelnetOption::Binary => BINARY,
             TelnetOption::Environ => ENVIRON,
+            TelnetOption::TerminalType => TTYPE,
+            TelnetOption::TerminalSpeed => TSPEED,
+            TelnetOption::XDisplayLocation => XDISPLOC,
+            TelnetOption::OldEnviron => OLD_ENVIRON,
+            TelnetOption::Authentication => AUTHENTICATION,
+            TelnetOption::Encrypt => ENCRYPT,
             TelnetOption::Unknown(byte) => byte,
         }
     }
 }
+
+impl Display for TelnetOption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TelnetOption::Echo => "ECHO",
+            TelnetOption::GoAhead => "GA",
+            TelnetOption::SuppressGoAhead => "SGA",
+            TelnetOption::EndOfRecord => "EOR",
+            TelnetOption::Charset => "CHARSET",
+            TelnetOption::MCCP2 => "MCCP2",
+            TelnetOption::MCCP3 => "MCCP3",
+            TelnetOption::GMCP => "GMCP",
+            TelnetOption::MSDP => "MSDP",
+            TelnetOption::MSSP => "MSSP",
+            TelnetOption::MSP => "MSP",
+            TelnetOption::MXP => "MXP",
+            TelnetOption::NAWS => "NAWS",
+            TelnetOption::Status => "STATUS",
+            TelnetOption::TimingMark => "TIMING_MARK",
+            TelnetOption::LineMode => "LINEMODE",
+            TelnetOption::RemoteFlowControl => "REMOTE_FLOW_CONTROL",
+            TelnetOption::Binary => "BINARY",
+            TelnetOption::Environ => "ENVIRON",
+            TelnetOption::TerminalType => "TTYPE",
+            TelnetOption::TerminalSpeed => "TSPEED",
+            TelnetOption::XDisplayLocation => "XDISPLOC",
+            TelnetOption::OldEnviron => "OLD_ENVIRON",
+            TelnetOption::Authentication => "AUTHENTICATION",
+            TelnetOption::Encrypt => "ENCRYPT",
+            TelnetOption::Unknown(byte) => return write!(f, "{byte}"),
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// Parses an option from its name (case-insensitive), as printed by the
+/// `Display` implementation, or from its numeric code (e.g. `"201"`).
+impl FromStr for TelnetOption {
+    type Err = TelnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(byte) = s.parse::<u8>() {
+            return Ok(TelnetOption::from(byte));
+        }
+
+        let option = match s.to_ascii_uppercase().as_str() {
+            "ECHO" => TelnetOption::Echo,
+            "GA" => TelnetOption::GoAhead,
+            "SGA" => TelnetOption::SuppressGoAhead,
+            "EOR" => TelnetOption::EndOfRecord,
+            "CHARSET" => TelnetOption::Charset,
+            "MCCP2" => TelnetOption::MCCP2,
+            "MCCP3" => TelnetOption::MCCP3,
+            "GMCP" => TelnetOption::GMCP,
+            "MSDP" => TelnetOption::MSDP,
+            "MSSP" => TelnetOption::MSSP,
+            "MSP" => TelnetOption::MSP,
+            "MXP" => TelnetOption::MXP,
+            "NAWS" => TelnetOption::NAWS,
+            "STATUS" => TelnetOption::Status,
+            "TIMING_MARK" => TelnetOption::TimingMark,
+            "LINEMODE" => TelnetOption::LineMode,
+            "REMOTE_FLOW_CONTROL" => TelnetOption::RemoteFlowControl,
+            "BINARY" => TelnetOption::Binary,
+            "ENVIRON" => TelnetOption::Environ,
+            "TTYPE" => TelnetOption::TerminalType,
+            "TSPEED" => TelnetOption::TerminalSpeed,
+            "XDISPLOC" => TelnetOption::XDisplayLocation,
+            "OLD_ENVIRON" => TelnetOption::OldEnviron,
+            "AUTHENTICATION" => TelnetOption::Authentication,
+            "ENCRYPT" => TelnetOption::Encrypt,
+            _ => return Err(format!("unknown telnet option: {s}").into()),
+        };
+
+        Ok(option)
+    }
+}
+
+/// Tracks the negotiation commands exchanged for each option during a
+/// session.
+#[derive(Debug, Default)]
+pub struct OptionState {
+    /// Whether the last command we sent for an option was WILL (`true`) or
+    /// WONT (`false`).
+    sent_local: HashMap<TelnetOption, bool>,
+    /// Whether the last command we sent for an option was DO (`true`) or DONT
+    /// (`false`).
+    sent_remote: HashMap<TelnetOption, bool>,
+    /// Whether the last command received for an option was DO (`true`) or
+    /// DONT (`false`).
+    received_local: HashMap<TelnetOption, bool>,
+    /// Whether the last command received for an option was WILL (`true`) or
+    /// WONT (`false`).
+    received_remote: HashMap<TelnetOption, bool>,
+}
+
+impl OptionState {
+    /// Returns `Some(true)` if the last command sent for the option was WILL,
+    /// `Some(false)` if it was WONT, or `None` if neither has been sent.
+    pub fn sent_will(&self, option: TelnetOption) -> Option<bool> {
+        self.sent_local.get(&option).copied()
+    }
+
+    /// Returns `Some(true)` if the last command sent for the option was DO,
+    /// `Some(false)` if it was DONT, or `None` if neither has been sent.
+    pub fn sent_do(&self, option: TelnetOption) -> Option<bool> {
+        self.sent_remote.get(&option).copied()
+    }
+
+    /// Returns `Some(true)` if the last command received for the option was
+    /// WILL, `Some(false)` if it was WONT, or `None` if neither has been
+    /// received.
+    pub fn received_will(&self, option: TelnetOption) -> Option<bool> {
+        self.received_remote.get(&option).copied()
+    }
+
+    /// Returns `Some(true)` if the last command received for the option was
+    /// DO, `Some(false)` if it was DONT, or `None` if neither has been
+    /// received.
+    pub fn received_do(&self, option: TelnetOption) -> Option<bool> {
+        self.received_local.get(&option).copied()
+    }
+
+    /// Returns true if we sent WILL and the other side agreed with DO.
+    pub fn local_enabled(&self, option: TelnetOption) -> bool {
+        self.sent_will(option) == Some(true) && self.received_do(option) == Some(true)
+    }
+
+    /// Returns true if we sent DO and the other side agreed with WILL.
+    pub fn remote_enabled(&self, option: TelnetOption) -> bool {
+        self.sent_do(option) == Some(true) && self.received_will(option) == Some(true)
+    }
+
+    /// Records an incoming negotiation command.
+    pub(crate) fn record_received(&mut self, command: u8, option: TelnetOption) {
+        let (map, enabled) = match command {
+            WILL => (&mut self.received_remote, true),
+            WONT => (&mut self.received_remote, false),
+            DO => (&mut self.received_local, true),
+            DONT => (&mut self.received_local, false),
+            _ => return,
+        };
+
+        map.insert(option, enabled);
+    }
+
+    /// Returns true if the command repeats the last command sent for the same
+    /// option and direction.
+    pub(crate) fn repeats_sent(&self, command: u8, option: TelnetOption) -> bool {
+        match command {
+            WILL => self.sent_will(option) == Some(true),
+            WONT => self.sent_will(option) == Some(false),
+            DO => self.sent_do(option) == Some(true),
+            DONT => self.sent_do(option) == Some(false),
+            _ => false,
+        }
+    }
+
+    /// Records an outgoing negotiation command. Returns false if it repeats
+    /// the last command sent for the same option and direction.
+    pub(crate) fn record_sent(&mut self, command: u8, option: TelnetOption) -> bool {
+        let (map, enabled) = match command {
+            WILL => (&mut self.sent_local, true),
+            WONT => (&mut self.sent_local, false),
+            DO => (&mut self.sent_remote, true),
+            DONT => (&mut self.sent_remote, false),
+            _ => return true,
+        };
+
+        map.insert(option, enabled) != Some(enabled)
+    }
+}
+
+/// A negotiation that was sent but has not been answered yet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PendingNegotiation {
+    /// The negotiation that was sent: a `Do`, `Dont`, `Will`, or `Wont`.
+    pub event: TelnetEvent,
+    /// When the negotiation was encoded.
+    pub sent_at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(TelnetOption::GMCP.to_string(), "GMCP");
+        assert_eq!(TelnetOption::TimingMark.to_string(), "TIMING_MARK");
+        assert_eq!(TelnetOption::Unknown(200).to_string(), "200");
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("naws".parse::<TelnetOption>().unwrap(), TelnetOption::NAWS);
+        assert_eq!("Echo".parse::<TelnetOption>().unwrap(), TelnetOption::Echo);
+        assert_eq!("200".parse::<TelnetOption>().unwrap(), TelnetOption::Unknown(200));
+        assert_eq!("201".parse::<TelnetOption>().unwrap(), TelnetOption::GMCP);
+        assert!("NOT_AN_OPTION".parse::<TelnetOption>().is_err());
+        assert!("256".parse::<TelnetOption>().is_err());
+    }
+
+    #[test]
+    fn test_record_sent() {
+        let mut state = OptionState::default();
+        assert!(state.record_sent(WILL, TelnetOption::Echo));
+        assert!(!state.record_sent(WILL, TelnetOption::Echo));
+        assert_eq!(state.sent_will(TelnetOption::Echo), Some(true));
+        assert_eq!(state.sent_do(TelnetOption::Echo), None);
+
+        assert!(state.record_sent(DO, TelnetOption::Echo));
+        assert!(state.record_sent(WONT, TelnetOption::Echo));
+        assert!(state.record_sent(WILL, TelnetOption::Echo));
+    }
+
+    #[test]
+    fn test_record_received() {
+        let mut state = OptionState::default();
+        state.record_sent(WILL, TelnetOption::Echo);
+        assert!(!state.local_enabled(TelnetOption::Echo));
+
+        state.record_received(DO, TelnetOption::Echo);
+        assert_eq!(state.received_do(TelnetOption::Echo), Some(true));
+        assert!(state.local_enabled(TelnetOption::Echo));
+        assert!(!state.remote_enabled(TelnetOption::Echo));
+
+        state.record_received(DONT, TelnetOption::Echo);
+        assert!(!state.local_enabled(TelnetOption::Echo));
+    }
+
+    #[test]
+    fn test_display_from_str_roundtrip() {
+        for byte in 0..=u8::MAX {
+            let option = TelnetOption::from(byte);
+            assert_eq!(option.to_string().parse::<TelnetOption>().unwrap(), option);
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashSet;
+
+use bytes::BytesMut;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{
+    error::TelnetError,
+    event::TelnetEvent,
+    option::{OptionState, TelnetOption},
+    subnegotiation::SubnegotiationType,
+    TelnetCodec,
+};
+
+type Result<T> = std::result::Result<T, TelnetError>;
+
+/// A Telnet session, which owns a `TelnetCodec` and answers the peer's
+/// negotiations on behalf of the application.
+///
+/// Decoded events are passed to `process`, which returns the replies to send
+/// back: agreement to the options the session supports, refusal of all
+/// others, and anything the codec has queued for `take_outgoing`. Option
+/// tracking is always enabled on the wrapped codec.
+///
+/// The session is itself a codec, so it can be used with `Framed` in place
+/// of `TelnetCodec`, and reached with `Framed::codec_mut`.
+#[derive(Debug)]
+pub struct TelnetSession {
+    codec: TelnetCodec,
+    /// Options we agree to enable on our side when the peer sends DO.
+    local: HashSet<TelnetOption>,
+    /// Options we agree to let the peer enable when it sends WILL.
+    remote: HashSet<TelnetOption>,
+    /// The window size last reported by the peer through NAWS.
+    window_size: Option<(u16, u16)>,
+    /// The control and data channels that decoded events are copied to, once
+    /// `channels` has been called.
+    channels: Option<(UnboundedSender<TelnetEvent>, UnboundedSender<TelnetEvent>)>,
+}
+
+impl TelnetSession {
+    #[must_use]
+    pub fn new(mut codec: TelnetCodec) -> Self {
+        codec.track_options = true;
+
+        TelnetSession {
+            codec,
+            local: HashSet::new(),
+            remote: HashSet::new(),
+            window_size: None,
+            channels: None,
+        }
+    }
+
+    /// Agrees to enable the option on our side when the peer asks for it
+    /// with `Do`.
+    pub fn support_local(&mut self, option: TelnetOption) {
+        self.local.insert(option);
+    }
+
+    /// Agrees to let the peer enable the option when it offers it with
+    /// `Will`.
+    pub fn support_remote(&mut self, option: TelnetOption) {
+        self.remote.insert(option);
+    }
+
+    /// Returns the negotiation state of each option in this session.
+    pub fn options(&self) -> &OptionState {
+        &self.codec.options
+    }
+
+    /// Returns the window size last reported by the peer, as a width and a
+    /// height in characters, or `None` if it has not sent one.
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.window_size
+    }
+
+    /// Returns the wrapped codec.
+    pub fn codec(&self) -> &TelnetCodec {
+        &self.codec
+    }
+
+    /// Returns the wrapped codec mutably, for changing its settings.
+    pub fn codec_mut(&mut self) -> &mut TelnetCodec {
+        &mut self.codec
+    }
+
+    /// Consumes the session, returning the wrapped codec.
+    pub fn into_codec(self) -> TelnetCodec {
+        self.codec
+    }
+
+    /// Wraps `stream` in a `Framed` using this session, with a read buffer
+    /// sized by `TelnetCodec::recommended_read_buffer`.
+    pub fn framed<S>(self, stream: S) -> Framed<S, TelnetSession> {
+        let capacity = self.codec.recommended_read_buffer();
+
+        Framed::with_capacity(stream, self, capacity)
+    }
+
+    /// Updates the session from a decoded event and returns the events that
+    /// should be sent back to the peer, in order.
+    ///
+    /// A `Do` or `Will` for a supported option is agreed to, and one for any
+    /// other option is refused, each time it is received. A `Dont` or `Wont`
+    /// that turns off an enabled option is acknowledged. Nothing is sent for a
+    /// `Do` or `Will` that confirms an option already enabled, or for a
+    /// `Dont` or `Wont` about an option that is already off, so two sessions
+    /// cannot loop.
+    pub fn process(&mut self, event: &TelnetEvent) -> Vec<TelnetEvent> {
+        if let TelnetEvent::Subnegotiate(SubnegotiationType::WindowSize(width, height)) = event {
+            self.window_size = Some((*width, *height));
+        }
+
+        let mut replies = self.codec.take_outgoing();
+
+        let reply = match *event {
+            TelnetEvent::Do(option) => match self.codec.options.sent_will(option) {
+                Some(true) => None,
+                _ if self.local.contains(&option) => Some(TelnetEvent::Will(option)),
+                _ => Some(TelnetEvent::Wont(option)),
+            },
+            TelnetEvent::Dont(option) => (self.codec.options.sent_will(option) == Some(true))
+                .then_some(TelnetEvent::Wont(option)),
+            TelnetEvent::Will(option) => match self.codec.options.sent_do(option) {
+                Some(true) => None,
+                _ if self.remote.contains(&option) => Some(TelnetEvent::Do(option)),
+                _ => Some(TelnetEvent::Dont(option)),
+            },
+            TelnetEvent::Wont(option) => (self.codec.options.sent_do(option) == Some(true))
+                .then_some(TelnetEvent::Dont(option)),
+            _ => None,
+        };
+
+        // A denied option has already been refused by the codec.
+        if let Some(reply) = reply {
+            if !replies.iter().any(|queued| negotiated_option(queued) == negotiated_option(&reply))
+            {
+                replies.push(reply);
+            }
+        }
+
+        replies
+    }
+
+    /// Copies decoded events to two channels, and returns the control and
+    /// data receivers, in that order, so that other tasks can handle them.
+    ///
+    /// Data events (see `TelnetEvent::is_data`) are sent to the data channel,
+    /// and every other event to the control channel. Each event is still
+    /// returned from `decode` as well, so the task reading from the peer
+    /// keeps passing control events to `process` and sending its replies.
+    /// The channels are unbounded, so sending never blocks the decoder.
+    /// Calling this again replaces the channels, and events for a dropped
+    /// receiver are discarded.
+    pub fn channels(&mut self) -> (UnboundedReceiver<TelnetEvent>, UnboundedReceiver<TelnetEvent>) {
+        let (control_sender, control) = mpsc::unbounded_channel();
+        let (data_sender, data) = mpsc::unbounded_channel();
+        self.channels = Some((control_sender, data_sender));
+
+        (control, data)
+    }
+
+    /// Sends a copy of a decoded event to its channel, if there are any.
+    fn route(&self, event: Option<TelnetEvent>) -> Option<TelnetEvent> {
+        if let (Some(event), Some((control, data))) = (&event, &self.channels) {
+            let channel = if event.is_data() { data } else { control };
+            // The receiver may have been dropped, which discards the event.
+            let _ = channel.send(event.clone());
+        }
+
+        event
+    }
+
+    /// Encodes an event to send to the peer, recording any negotiation in
+    /// the session's option state.
+    pub fn send(&mut self, event: TelnetEvent, buffer: &mut BytesMut) -> Result<()> {
+        self.codec.encode(event, buffer)
+    }
+}
+
+/// Returns the option of a negotiation event, or `None` for any other event.
+fn negotiated_option(event: &TelnetEvent) -> Option<TelnetOption> {
+    match event {
+        TelnetEvent::Do(option)
+        | TelnetEvent::Dont(option)
+        | TelnetEvent::Will(option)
+        | TelnetEvent::Wont(option) => Some(*option),
+        _ => None,
+    }
+}
+
+impl Decoder for TelnetSession {
+    type Item = TelnetEvent;
+    type Error = TelnetError;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let event = self.codec.decode(buffer)?;
+
+        Ok(self.route(event))
+    }
+
+    fn decode_eof(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let event = self.codec.decode_eof(buffer)?;
+
+        Ok(self.route(event))
+    }
+}
+
+impl Encoder<TelnetEvent> for TelnetSession {
+    type Error = TelnetError;
+
+    fn encode(&mut self, event: TelnetEvent, buffer: &mut BytesMut) -> Result<()> {
+        self.send(event, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes `input` with the session and returns the replies to every
+    /// event, encoded.
+    fn exchange(session: &mut TelnetSession, input: &[u8]) -> BytesMut {
+        let mut input = BytesMut::from(input);
+        let mut output = BytesMut::new();
+
+        while let Some(event) = session.decode(&mut input).unwrap() {
+            for reply in session.process(&event) {
+                session.send(reply, &mut output).unwrap();
+            }
+        }
+
+        output
+    }
+
+    #[test]
+    fn test_process_negotiations() {
+        let mut session = TelnetSession::new(TelnetCodec::new(1024));
+        session.support_local(TelnetOption::GMCP);
+        session.support_remote(TelnetOption::NAWS);
+
+        assert_eq!(
+            session.process(&TelnetEvent::Do(TelnetOption::GMCP)),
+            vec![TelnetEvent::Will(TelnetOption::GMCP)]
+        );
+        assert_eq!(
+            session.process(&TelnetEvent::Will(TelnetOption::NAWS)),
+            vec![TelnetEvent::Do(TelnetOption::NAWS)]
+        );
+        assert_eq!(
+            session.process(&TelnetEvent::Do(TelnetOption::Echo)),
+            vec![TelnetEvent::Wont(TelnetOption::Echo)]
+        );
+        assert_eq!(
+            session.process(&TelnetEvent::Will(TelnetOption::Echo)),
+            vec![TelnetEvent::Dont(TelnetOption::Echo)]
+        );
+        assert!(session.process(&TelnetEvent::Dont(TelnetOption::Echo)).is_empty());
+        assert!(session.process(&TelnetEvent::Message("hi".to_string())).is_empty());
+    }
+
+    #[test]
+    fn test_process_refuses_repeats() {
+        let mut session = TelnetSession::new(TelnetCodec::new(1024));
+
+        // IAC DO ECHO, twice, is refused with IAC WONT ECHO each time
+        assert_eq!(&exchange(&mut session, &[255, 253, 1])[..], &[255, 252, 1]);
+        assert_eq!(&exchange(&mut session, &[255, 253, 1])[..], &[255, 252, 1]);
+
+        // The same for IAC WILL ECHO, refused with IAC DONT ECHO
+        assert_eq!(&exchange(&mut session, &[255, 251, 1])[..], &[255, 254, 1]);
+        assert_eq!(&exchange(&mut session, &[255, 251, 1])[..], &[255, 254, 1]);
+    }
+
+    #[test]
+    fn test_process_tracks_state() {
+        let mut session = TelnetSession::new(TelnetCodec::new(1024));
+        session.support_remote(TelnetOption::NAWS);
+
+        // IAC WILL NAWS, then a window size of 80x24
+        let output = exchange(&mut session, &[255, 251, 31, 255, 250, 31, 0, 80, 0, 24, 255, 240]);
+
+        assert_eq!(&output[..], &[255, 253, 31]);
+        assert!(session.options().remote_enabled(TelnetOption::NAWS));
+        assert_eq!(session.window_size(), Some((80, 24)));
+
+        // A repeated offer is not answered again, and turning it off is.
+        assert!(exchange(&mut session, &[255, 251, 31]).is_empty());
+        assert_eq!(&exchange(&mut session, &[255, 252, 31])[..], &[255, 254, 31]);
+        assert!(!session.options().remote_enabled(TelnetOption::NAWS));
+    }
+
+    #[test]
+    fn test_process_answers_own_request() {
+        let mut session = TelnetSession::new(TelnetCodec::new(1024));
+        let mut output = BytesMut::new();
+        session.send(TelnetEvent::Will(TelnetOption::GMCP), &mut output).unwrap();
+
+        // The peer agreeing to our offer (IAC DO GMCP) is not answered.
+        assert!(exchange(&mut session, &[255, 253, 201]).is_empty());
+        assert!(session.options().local_enabled(TelnetOption::GMCP));
+    }
+
+    #[test]
+    fn test_process_takes_outgoing() {
+        let mut session = TelnetSession::new(TelnetCodec::mud_server());
+        session.codec_mut().deny(TelnetOption::Echo);
+
+        let replies = exchange(&mut session, &[255, 253, 1]);
+
+        // The offers queued by `mud_server`, then a single refusal.
+        assert_eq!(&replies[..], &[255, 251, 201, 255, 251, 70, 255, 253, 31, 255, 252, 1]);
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut session = TelnetSession::new(TelnetCodec::new(1024));
+        session.support_remote(TelnetOption::NAWS);
+        let (mut control, mut data) = session.channels();
+
+        // A message, IAC WILL NAWS, IAC DO ECHO, then another message
+        let input = b"hi\r\n\xff\xfb\x1f\xff\xfd\x01bye\r\n";
+
+        // Events are still returned, so replies are sent as usual
+        assert_eq!(&exchange(&mut session, input)[..], &[255, 253, 31, 255, 252, 1]);
+
+        let mut received = Vec::new();
+        while let Ok(event) = data.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received,
+            vec![TelnetEvent::Message("hi".to_string()), TelnetEvent::Message("bye".to_string())]
+        );
+
+        received.clear();
+        while let Ok(event) = control.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received,
+            vec![TelnetEvent::Will(TelnetOption::NAWS), TelnetEvent::Do(TelnetOption::Echo)]
+        );
+
+        // A dropped receiver does not stop decoding
+        drop(data);
+        assert_eq!(
+            session.decode(&mut BytesMut::from(&b"again\r\n"[..])).unwrap(),
+            Some(TelnetEvent::Message("again".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_sessions_do_not_loop() {
+        let mut server = TelnetSession::new(TelnetCodec::new(1024));
+        let mut client = TelnetSession::new(TelnetCodec::new(1024));
+        server.support_local(TelnetOption::SuppressGoAhead);
+        client.support_remote(TelnetOption::SuppressGoAhead);
+
+        let mut to_client = BytesMut::new();
+        server.send(TelnetEvent::Will(TelnetOption::SuppressGoAhead), &mut to_client).unwrap();
+        server.send(TelnetEvent::Do(TelnetOption::Echo), &mut to_client).unwrap();
+
+        for _ in 0..4 {
+            let to_server = exchange(&mut client, &to_client);
+            to_client = exchange(&mut server, &to_server);
+        }
+
+        assert!(to_client.is_empty());
+        assert!(server.options().local_enabled(TelnetOption::SuppressGoAhead));
+        assert!(client.options().remote_enabled(TelnetOption::SuppressGoAhead));
+
+        // Text still gets through as lines once SGA is agreed
+        let mut to_client = BytesMut::new();
+        server.send(TelnetEvent::Message("hi".to_string()), &mut to_client).unwrap();
+        assert_eq!(
+            client.codec_mut().decode_all(&mut to_client).unwrap(),
+            vec![TelnetEvent::Message("hi".to_string())]
+        );
+        assert!(to_client.is_empty());
+    }
+}
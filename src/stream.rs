@@ -0,0 +1,140 @@
+use std::{
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::{ready, Stream};
+
+use crate::event::TelnetEvent;
+
+/// Wraps a stream of decoded events, such as a `Framed` using `TelnetCodec`,
+/// so that `Character` events are buffered until a newline and emitted as a
+/// single `Message`. All other events are passed through untouched.
+///
+/// This restores line semantics for an application that has switched the
+/// codec into character mode, without reconfiguring the codec mid-stream.
+pub fn coalesce_lines<S>(stream: S) -> CoalesceLines<S> {
+    CoalesceLines { stream, line: Vec::new(), finished: false }
+}
+
+/// A stream that joins `Character` events into `Message` events. Created by
+/// `coalesce_lines`.
+#[derive(Debug)]
+pub struct CoalesceLines<S> {
+    stream: S,
+    line: Vec<u8>,
+    finished: bool,
+}
+
+impl<S> CoalesceLines<S> {
+    /// Consumes the adaptor, returning the underlying stream. Any partially
+    /// buffered line is discarded.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Takes the buffered line as a `Message`, dropping the trailing carriage
+    /// return if there is one.
+    fn take_line(&mut self) -> TelnetEvent {
+        let mut line = mem::take(&mut self.line);
+
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        TelnetEvent::Message(String::from_utf8_lossy(&line).to_string())
+    }
+}
+
+impl<S, E> Stream for CoalesceLines<S>
+where
+    S: Stream<Item = Result<TelnetEvent, E>> + Unpin,
+{
+    type Item = Result<TelnetEvent, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if this.finished {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(Ok(TelnetEvent::Character(b'\n'))) => {
+                    return Poll::Ready(Some(Ok(this.take_line())));
+                }
+                Some(Ok(TelnetEvent::Character(byte))) => this.line.push(byte),
+                #[cfg(feature = "unicode")]
+                Some(Ok(TelnetEvent::Unicode(c))) => {
+                    this.line.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                Some(event) => return Poll::Ready(Some(event)),
+                None => {
+                    this.finished = true;
+
+                    // Flush a final line that was never terminated.
+                    if this.line.is_empty() {
+                        return Poll::Ready(None);
+                    }
+
+                    return Poll::Ready(Some(Ok(this.take_line())));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, convert::Infallible, task::Waker};
+
+    use super::*;
+    use crate::option::TelnetOption;
+
+    struct Events(VecDeque<TelnetEvent>);
+
+    impl Stream for Events {
+        type Item = Result<TelnetEvent, Infallible>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    fn collect(events: Vec<TelnetEvent>) -> Vec<TelnetEvent> {
+        let mut stream = coalesce_lines(Events(events.into()));
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut output = Vec::new();
+
+        while let Poll::Ready(Some(event)) = Pin::new(&mut stream).poll_next(&mut cx) {
+            output.push(event.unwrap());
+        }
+
+        output
+    }
+
+    #[test]
+    fn test_coalesce_lines() {
+        let events = collect(vec![
+            TelnetEvent::Character(b'h'),
+            TelnetEvent::Will(TelnetOption::Echo),
+            TelnetEvent::Character(b'i'),
+            TelnetEvent::Character(b'\r'),
+            TelnetEvent::Character(b'\n'),
+            TelnetEvent::Message("line".to_string()),
+            TelnetEvent::Character(b'!'),
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                TelnetEvent::Will(TelnetOption::Echo),
+                TelnetEvent::Message("hi".to_string()),
+                TelnetEvent::Message("line".to_string()),
+                TelnetEvent::Message("!".to_string()),
+            ]
+        );
+    }
+}
@@ -1,14 +1,15 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 
 use crate::{
-    constants::{LINEMODE_FORWARD_MASK, LINEMODE_SLC, MODE},
+    constants::{CHARSET_TTABLE, LINEMODE_FORWARD_MASK, LINEMODE_SLC, MODE},
     env::EnvironmentOperation,
-    linemode::{Dispatch, ForwardMaskOption},
+    linemode::{Dispatch, ForwardMaskOption, Level, LineMode, Modifiers, SlcFunction},
+    msdp::{var_size, MsdpValue},
     option::TelnetOption,
 };
 
 /// Represents all Telnet subnegotiation events supported by Nectar.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum SubnegotiationType {
     /// A subnegotiation for the window size, where the first value is the width
     /// and the second value is the height. The values are in characters.
@@ -16,34 +17,174 @@ pub enum SubnegotiationType {
     /// Indicates an intent to begin CHARSET subnegotiation. This can only be
     /// sent after receiving a DO CHARSET after sending a WILL CHARSET (in any
     /// order).
-    CharsetRequest(Vec<Bytes>),
+    ///
+    /// The first value is the translation table version, if the sender
+    /// offered to send a TTABLE (the `[TTABLE]` prefix), and the second is
+    /// the list of character sets.
+    CharsetRequest(Option<u8>, Vec<Bytes>),
     /// Indicates that the receiver has accepted the charset request.
     CharsetAccepted(Bytes),
     /// Indicates that the receiver acknowledges the charset request, but will
     /// not use any of the requested characters.
     CharsetRejected,
+    /// Sends a translation table, after the receiver accepted a request that
+    /// offered one. The first value is the table version, and the second is
+    /// the table itself, which is passed through as is.
+    CharsetTTableIs(u8, Bytes),
     /// Indicates that the receiver acknowledges a TTABLE-IS message, but is
     /// unable to handle it. This will terminate subnegotiation.
     CharsetTTableRejected,
+    /// Indicates that the receiver has accepted the translation table.
+    CharsetTTableAck,
+    /// Indicates that the translation table was received with an error, and
+    /// should be sent again.
+    CharsetTTableNak,
     /// Represents different line-mode options possible in a telnet session.
     LineMode(LineModeOption),
     Environment(EnvironmentOperation),
+    /// A subnegotiation for the legacy environment option, which is encoded
+    /// with the swapped VAR and VALUE bytes used by older servers.
+    OldEnvironment(EnvironmentOperation),
+    /// A subnegotiation for the terminal type, such as `"xterm-256color"`.
+    TerminalType(TerminalTypeOption),
+    /// A subnegotiation for the terminal speed, as transmit and receive baud
+    /// rates.
+    TerminalSpeed(TerminalSpeedOption),
+    /// A subnegotiation for the X display location, in the form
+    /// `<host>:<display>[.<screen>]`.
+    XDisplayLocation(XDisplayLocationOption),
+    /// A subnegotiation for authentication, containing the raw payload for
+    /// the application to interpret.
+    Authentication(Bytes),
+    /// A subnegotiation for encryption, containing the raw payload for the
+    /// application to interpret.
+    Encrypt(Bytes),
+    /// A subnegotiation for MSDP, as a list of variables and their values.
+    Msdp(Vec<(String, MsdpValue)>),
     /// A subnegotiation for an unknown option.
     Unknown(TelnetOption, Bytes),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum LineModeOption {
-    Mode(u8),
+    /// Proposes a new line mode to the other side.
+    Mode(LineMode),
+    /// Acknowledges a line mode proposed by the other side. The `MODE_ACK`
+    /// bit is not included in the contained mode; it is added when encoding.
+    ModeAck(LineMode),
     SLC(Vec<(Dispatch, char)>),
     ForwardMask(ForwardMaskOption),
     Unknown(u8, Bytes),
 }
 
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TerminalTypeOption {
+    /// Reports the name of the terminal type.
+    Is(String),
+    /// Requests that the other side report its terminal type. Each request
+    /// moves a client that supports several types on to the next one.
+    Send,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TerminalSpeedOption {
+    /// Reports the transmit and receive speeds, in bits per second.
+    Is(u32, u32),
+    /// Requests that the other side report its terminal speed.
+    Send,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum XDisplayLocationOption {
+    /// Reports the location of the X display.
+    Is(String),
+    /// Requests that the other side report its X display location.
+    Send,
+}
+
+/// A character set name, as exchanged in CHARSET subnegotiations. Common
+/// names are recognized case-insensitively, along with their usual aliases.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum Charset {
+    Utf8,
+    UsAscii,
+    Latin1,
+    /// Any other character set, by name.
+    Other(String),
+}
+
+impl Charset {
+    /// Returns the name sent on the wire, which is the IANA preferred name
+    /// for the known character sets.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Charset::Utf8 => "UTF-8",
+            Charset::UsAscii => "US-ASCII",
+            Charset::Latin1 => "ISO-8859-1",
+            Charset::Other(name) => name,
+        }
+    }
+}
+
+impl From<&[u8]> for Charset {
+    fn from(name: &[u8]) -> Self {
+        let name = String::from_utf8_lossy(name);
+
+        match name.to_ascii_uppercase().as_str() {
+            "UTF-8" | "UTF8" => Charset::Utf8,
+            "US-ASCII" | "ASCII" => Charset::UsAscii,
+            "ISO-8859-1" | "ISO_8859-1" | "LATIN1" | "LATIN-1" => Charset::Latin1,
+            _ => Charset::Other(name.into_owned()),
+        }
+    }
+}
+
+impl From<&Bytes> for Charset {
+    fn from(name: &Bytes) -> Self {
+        Charset::from(name.as_ref())
+    }
+}
+
+impl From<Charset> for Bytes {
+    fn from(charset: Charset) -> Self {
+        match charset {
+            Charset::Other(name) => Bytes::from(name),
+            charset => Bytes::copy_from_slice(charset.as_str().as_bytes()),
+        }
+    }
+}
+
+impl LineModeOption {
+    /// Returns the SLC message that asks the other side for its default
+    /// special character table, sent to bootstrap SLC negotiation. This is a
+    /// single triple with a function of 0 and a level of `SLC_DEFAULT`.
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc1184> for more information.
+    pub fn slc_default_request() -> Self {
+        let modifiers =
+            Modifiers { level: Level::Default, ack: false, flush_in: false, flush_out: false };
+
+        LineModeOption::SLC(vec![(Dispatch { function: SlcFunction::Unknown(0), modifiers }, '\0')])
+    }
+
+    /// Returns true if this is a request for the default SLC table, as built
+    /// by `slc_default_request`.
+    pub fn is_slc_default_request(&self) -> bool {
+        match self {
+            LineModeOption::SLC(triples) => matches!(
+                triples.as_slice(),
+                [(Dispatch { function: SlcFunction::Unknown(0), modifiers }, _)]
+                    if modifiers.level == Level::Default
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl From<u8> for LineModeOption {
     fn from(value: u8) -> Self {
         match value {
-            MODE => LineModeOption::Mode(0),
+            MODE => LineModeOption::Mode(LineMode::empty()),
             LINEMODE_SLC => LineModeOption::SLC(Vec::new()),
             LINEMODE_FORWARD_MASK => LineModeOption::ForwardMask(ForwardMaskOption::Unknown(0)),
             _ => LineModeOption::Unknown(value, Bytes::new()),
@@ -52,62 +193,151 @@ impl From<u8> for LineModeOption {
 }
 
 impl SubnegotiationType {
+    /// Returns the character sets named by a `CharsetRequest` or
+    /// `CharsetAccepted`, or `None` for any other subnegotiation.
+    pub fn charsets(&self) -> Option<Vec<Charset>> {
+        match self {
+            SubnegotiationType::CharsetRequest(_, charsets) => {
+                Some(charsets.iter().map(Charset::from).collect())
+            }
+            SubnegotiationType::CharsetAccepted(charset) => Some(vec![Charset::from(charset)]),
+            _ => None,
+        }
+    }
+
+    /// Returns a NAWS subnegotiation reporting the window size, in
+    /// characters.
+    pub fn window_size(width: u16, height: u16) -> Self {
+        SubnegotiationType::WindowSize(width, height)
+    }
+
+    /// Returns the GMCP `Core.Supports.Set` message, which advertises the
+    /// packages a client supports, such as `["Char 1", "Room 1"]`. Each
+    /// package is written as a JSON string.
+    ///
+    /// See <https://www.gammon.com.au/gmcp> for more information.
+    pub fn gmcp_supports_set(packages: &[&str]) -> Self {
+        let mut message = String::from("Core.Supports.Set [");
+
+        for (i, package) in packages.iter().enumerate() {
+            if i > 0 {
+                message.push(',');
+            }
+
+            message.push('"');
+            for c in package.chars() {
+                match c {
+                    '"' => message.push_str("\\\""),
+                    '\\' => message.push_str("\\\\"),
+                    c if c.is_control() => message.push_str(&format!("\\u{:04x}", c as u32)),
+                    c => message.push(c),
+                }
+            }
+            message.push('"');
+        }
+
+        message.push(']');
+
+        SubnegotiationType::Unknown(TelnetOption::GMCP, Bytes::from(message))
+    }
+
+    /// Encodes the subnegotiation on its own, including the surrounding
+    /// `IAC SB` and `IAC SE` framing.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buffer = BytesMut::new();
+        crate::encode_sb(self, &mut buffer);
+
+        buffer.freeze()
+    }
+
     /// Returns the length (in bytes) of the subnegotiation data. This _does
     /// not_ include the IAC SB and IAC SE bytes, _nor_ the single byte that
     /// represents the option.
     pub fn len(&self) -> usize {
         match self {
             SubnegotiationType::WindowSize(_, _) => 4,
-            SubnegotiationType::CharsetRequest(vec) => {
+            SubnegotiationType::CharsetRequest(ttable, vec) => {
                 // 1 separator per charset, as the list starts with one.
                 let mut len = vec.len();
 
+                // The `[TTABLE]` token and its version byte
+                if ttable.is_some() {
+                    len = len.saturating_add(CHARSET_TTABLE.len()).saturating_add(1);
+                }
+
                 for bytes in vec {
-                    len += bytes.len();
+                    len = len.saturating_add(bytes.len());
                 }
                 // add one more for the subnegotation sub-option (i.e.
                 // CHARSET_REQUEST)
-                len + 1
+                len.saturating_add(1)
             }
             SubnegotiationType::CharsetAccepted(charset) => {
                 // add one more for the subnegotation sub-option (i.e.
                 // CHARSET_ACCEPTED)
-                charset.len() + 1
+                charset.len().saturating_add(1)
             }
             SubnegotiationType::CharsetRejected => 1,
-            SubnegotiationType::CharsetTTableRejected => 1,
+            SubnegotiationType::CharsetTTableIs(_, table) => {
+                // The TTABLE-IS sub-option and the version byte
+                table.len().saturating_add(2)
+            }
+            SubnegotiationType::CharsetTTableRejected
+            | SubnegotiationType::CharsetTTableAck
+            | SubnegotiationType::CharsetTTableNak => 1,
             SubnegotiationType::LineMode(mode) => {
                 match mode {
                     LineModeOption::SLC(triples) => {
                         // Mode byte plus length of triples
-                        triples.len() * 3 + 1
+                        triples.len().saturating_mul(3).saturating_add(1)
+                    }
+                    LineModeOption::Mode(_) | LineModeOption::ModeAck(_) => 2,
+                    LineModeOption::ForwardMask(ForwardMaskOption::Do(mask)) => {
+                        // The binary form of the mask is 32 bytes long.
+                        let mask_len: usize = if mask.len() > 16 { 32 } else { 16 };
+                        mask_len.saturating_add(2)
                     }
-                    LineModeOption::Mode(_) => 2,
-                    LineModeOption::ForwardMask(ForwardMaskOption::Do(_)) => 2 + 16,
                     LineModeOption::ForwardMask(_) => 2,
-                    LineModeOption::Unknown(_, data) => 1 + data.len(),
+                    LineModeOption::Unknown(_, data) => data.len().saturating_add(1),
                 }
             }
-            SubnegotiationType::Environment(op) => match op {
-                EnvironmentOperation::Is(vars) | EnvironmentOperation::Info(vars) => {
-                    vars.iter()
+            SubnegotiationType::Environment(op) | SubnegotiationType::OldEnvironment(op) => {
+                match op {
+                    EnvironmentOperation::Is(vars) | EnvironmentOperation::Info(vars) => vars
+                        .iter()
                         .map(|(k, v)| {
-                            k.encoded_size()
-                                + match v {
-                                    None => 0,
-                                    Some(value) if value.is_empty() => 1,
-                                    Some(value) => value.len() + 1,
-                                }
+                            k.encoded_size().saturating_add(match v {
+                                None => 0,
+                                Some(value) => value.len().saturating_add(1),
+                            })
                         })
-                        .sum::<usize>()
-                        + 1
-                }
-                EnvironmentOperation::Send(vars) => {
-                    vars.iter().map(|v| v.encoded_size()).sum::<usize>() + 1
-                }
+                        .fold(1, usize::saturating_add),
+                    EnvironmentOperation::Send(vars) => {
+                        vars.iter().map(|v| v.encoded_size()).fold(1, usize::saturating_add)
+                    }
 
-                EnvironmentOperation::Unknown(_, data) => 1 + data.len(),
-            },
+                    EnvironmentOperation::Unknown(_, data) => data.len().saturating_add(1),
+                }
+            }
+            SubnegotiationType::TerminalType(TerminalTypeOption::Is(name)) => {
+                name.len().saturating_add(1)
+            }
+            SubnegotiationType::TerminalType(TerminalTypeOption::Send) => 1,
+            SubnegotiationType::TerminalSpeed(TerminalSpeedOption::Is(transmit, receive)) => {
+                // IS byte plus the comma-separated ASCII speeds
+                format!("{transmit},{receive}").len().saturating_add(1)
+            }
+            SubnegotiationType::TerminalSpeed(TerminalSpeedOption::Send) => 1,
+            SubnegotiationType::XDisplayLocation(XDisplayLocationOption::Is(location)) => {
+                location.len().saturating_add(1)
+            }
+            SubnegotiationType::XDisplayLocation(XDisplayLocationOption::Send) => 1,
+            SubnegotiationType::Authentication(bytes) | SubnegotiationType::Encrypt(bytes) => {
+                bytes.len()
+            }
+            SubnegotiationType::Msdp(vars) => {
+                vars.iter().map(var_size).fold(0, usize::saturating_add)
+            }
             SubnegotiationType::Unknown(_, bytes) => bytes.len(),
         }
     }
@@ -0,0 +1,89 @@
+use bytes::BytesMut;
+use tokio::io::DuplexStream;
+
+use crate::{event::TelnetEvent, TelnetCodec, TelnetFrame};
+
+/// Decodes `input` with a default `TelnetCodec` and asserts that it produces
+/// exactly the `expected` events, in order.
+///
+/// # Panics
+///
+/// Panics if decoding fails or the events differ from `expected`.
+pub fn assert_decodes(input: &[u8], expected: &[TelnetEvent]) {
+    assert_decodes_with(TelnetCodec::new(1024), input, expected);
+}
+
+/// Like `assert_decodes`, but decodes with the given codec, for input that
+/// relies on a particular configuration.
+///
+/// # Panics
+///
+/// Panics if decoding fails or the events differ from `expected`.
+pub fn assert_decodes_with(mut codec: TelnetCodec, input: &[u8], expected: &[TelnetEvent]) {
+    let mut buffer = BytesMut::from(input);
+    let events = codec.decode_all(&mut buffer).expect("input should decode");
+
+    assert_eq!(events, expected, "decoded events differ for input {input:?}");
+}
+
+/// Creates two framed Telnet endpoints that are connected to each other in
+/// memory, each with a `TelnetCodec::new(max_buffer_length)`. Anything sent
+/// on one side can be read from the other, which makes this useful for
+/// testing protocol handling without opening real sockets.
+pub fn framed_pair(
+    max_buffer_length: usize,
+) -> (TelnetFrame<DuplexStream>, TelnetFrame<DuplexStream>) {
+    let (server, client) = tokio::io::duplex(max_buffer_length);
+
+    (
+        TelnetCodec::new(max_buffer_length).framed(server),
+        TelnetCodec::new(max_buffer_length).framed(client),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::{
+        constants::{DO, ECHO, IAC},
+        option::TelnetOption,
+    };
+
+    #[test]
+    fn test_assert_decodes() {
+        assert_decodes(
+            b"\xFF\xFD\x01hi\r\n",
+            &[TelnetEvent::Do(TelnetOption::Echo), TelnetEvent::Message("hi".to_string())],
+        );
+
+        let mut codec = TelnetCodec::new(1024);
+        codec.message_mode = false;
+        assert_decodes_with(
+            codec,
+            &[b'a', IAC, DO, ECHO],
+            &[TelnetEvent::Character(b'a'), TelnetEvent::Do(TelnetOption::Echo)],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "decoded events differ")]
+    fn test_assert_decodes_mismatch() {
+        assert_decodes(b"hi\r\n", &[]);
+    }
+
+    #[tokio::test]
+    async fn test_framed_pair() {
+        let (mut server, mut client) = framed_pair(1024);
+
+        server.send(TelnetEvent::Do(TelnetOption::NAWS)).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), TelnetEvent::Do(TelnetOption::NAWS));
+
+        client.send(TelnetEvent::Message("hello".to_string())).await.unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            TelnetEvent::Message("hello".to_string())
+        );
+    }
+}